@@ -3,9 +3,8 @@ use tracing_subscriber::{EnvFilter, Registry};
 use tracing_subscriber::fmt::format::FmtSpan;
 use console_subscriber as tokio_console_subscriber;
 use tracing_subscriber::layer::SubscriberExt;
-use meshcore_companion_rs::{Companion, Commands, MessageTypes, AppStart};
-use meshcore_companion_rs::commands::{AdvertisementMode, DeviceQuery, GetContacts, SendChannelTxtMsg};
-use meshcore_companion_rs::consts;
+use meshcore_companion_rs::{CompanionBuilder, Commands, MessageTypes};
+use meshcore_companion_rs::commands::{AdvertisementMode, SendChannelTxtMsg};
 use meshcore_companion_rs::contact_mgmt::PublicKey;
 
 #[tokio::main]
@@ -32,39 +31,12 @@ async fn main() {
         .with(format_layer);
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set tracing subscriber");
     //endregion
-    let mut companion = Companion::new("/dev/ttyUSB0");
-    companion.start().await.unwrap();
-
-    //region companion data sync (contacts, app info)
-    let appstart: AppStart = AppStart {
-        code: consts::CMD_APP_START,
-        app_ver: 1,
-        app_name: "test".to_string(),
-        ..AppStart::default()
-    };
-    let _ = companion.command(Commands::CmdAppStart(appstart)).await;
-
-    let data: DeviceQuery = DeviceQuery {
-        code: consts::CMD_DEVICE_QEURY,
-        app_target_ver: 3,
-    };
-    let _ = companion.command(Commands::CmdDeviceQuery(data)).await;
-
-    let data: GetContacts = GetContacts {
-        code: consts::CMD_GET_CONTACTS,
-        since: None,
-    };
-    let _ = companion.command(Commands::CmdGetContacts(data)).await;
-    let _ = companion.command(Commands::CmdGetBattAndStorage).await;
-    let _ = companion.command(Commands::CmdSetDeviceTime).await;
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-    let _ = companion.command(Commands::CmdGetDeviceTime).await;
-    //endregion
-
-
-
-    // Give the companion a moment to initialize and download contacts list
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    let companion = CompanionBuilder::new("/dev/ttyUSB0")
+        .app_name("test")
+        .target_version(3)
+        .connect()
+        .await
+        .expect("Failed to connect to companion");
 
 
     let contact_key: PublicKey = PublicKey::from_hex("0680ae32618ef25b6a43b30c646d8458f6da82c33556a8ced1600aa111588b6f").unwrap();
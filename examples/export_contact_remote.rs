@@ -1,12 +1,9 @@
 #[macro_use]
 extern crate tracing;
 use console_subscriber as tokio_console_subscriber;
-use meshcore_companion_rs::commands::{
-    AdvertisementMode, DeviceQuery, GetContacts, SendChannelTxtMsg,
-};
-use meshcore_companion_rs::consts;
+use meshcore_companion_rs::commands::{AdvertisementMode, SendChannelTxtMsg};
 use meshcore_companion_rs::contact_mgmt::PublicKey;
-use meshcore_companion_rs::{AppStart, Commands, Companion, MessageTypes};
+use meshcore_companion_rs::{Commands, CompanionBuilder, MessageTypes};
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{EnvFilter, Registry};
@@ -35,37 +32,12 @@ async fn main() {
         .with(format_layer);
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set tracing subscriber");
     //endregion
-    let mut companion = Companion::new("/dev/ttyUSB0");
-    companion.start().await.unwrap();
-
-    //region companion data sync (contacts, app info)
-    let appstart: AppStart = AppStart {
-        code: consts::CMD_APP_START,
-        app_ver: 1,
-        app_name: "test".to_string(),
-        ..AppStart::default()
-    };
-    let _ = companion.command(Commands::CmdAppStart(appstart)).await;
-
-    let data: DeviceQuery = DeviceQuery {
-        code: consts::CMD_DEVICE_QEURY,
-        app_target_ver: 3,
-    };
-    let _ = companion.command(Commands::CmdDeviceQuery(data)).await;
-
-    let data: GetContacts = GetContacts {
-        code: consts::CMD_GET_CONTACTS,
-        since: None,
-    };
-    let _ = companion.command(Commands::CmdGetContacts(data)).await;
-    let _ = companion.command(Commands::CmdGetBattAndStorage).await;
-    let _ = companion.command(Commands::CmdSetDeviceTime).await;
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-    let _ = companion.command(Commands::CmdGetDeviceTime).await;
-    //endregion
-
-    // Give the companion a moment to initialize and download contacts list
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    let companion = CompanionBuilder::new("/dev/ttyUSB0")
+        .app_name("test")
+        .target_version(3)
+        .connect()
+        .await
+        .expect("Failed to connect to companion");
 
     let key: PublicKey =
         PublicKey::from_hex("0663f1725334df8c20b2269e426c546ca9bea2a975c287eb1bcead3cdac56fb6").unwrap();
@@ -3,8 +3,9 @@ use tracing_subscriber::{EnvFilter, Layer, Registry};
 use tracing_subscriber::fmt::format::FmtSpan;
 use console_subscriber as tokio_console_subscriber;
 use tracing_subscriber::layer::SubscriberExt;
-use meshcore_companion_rs::{Companion, Commands, MessageTypes, AppStart};
-use meshcore_companion_rs::commands::{DeviceQuery, GetContacts, SendChannelTxtMsg, SendTxtMsg};
+use meshcore_companion_rs::{CompanionBuilder, Commands};
+use meshcore_companion_rs::responses::MessageSource;
+use meshcore_companion_rs::commands::{SendChannelTxtMsg, SendTxtMsg};
 use meshcore_companion_rs::consts;
 
 #[tokio::main]
@@ -31,38 +32,12 @@ async fn main() {
         .with(format_layer);
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set tracing subscriber");
     //endregion
-    let mut companion = Companion::new("/dev/ttyUSB0");
-    companion.start().await.unwrap();
-
-    //region companion data sync (contacts, app info)
-    let appstart: AppStart = AppStart {
-        code: consts::CMD_APP_START,
-        app_ver: 1,
-        app_name: "test".to_string(),
-        ..AppStart::default()
-    };
-    let _ = companion.command(Commands::CmdAppStart(appstart)).await;
-
-    let data: DeviceQuery = DeviceQuery {
-        code: consts::CMD_DEVICE_QEURY,
-        app_target_ver: 3,
-    };
-    let _ = companion.command(Commands::CmdDeviceQuery(data)).await;
-
-    let data: GetContacts = GetContacts {
-        code: consts::CMD_GET_CONTACTS,
-        since: None,
-    };
-    let _ = companion.command(Commands::CmdGetContacts(data)).await;
-    let _ = companion.command(Commands::CmdGetBattAndStorage).await;
-    let _ = companion.command(Commands::CmdSetDeviceTime).await;
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-    let _ = companion.command(Commands::CmdGetDeviceTime).await;
-
-    //endregion
-
-    // Give the companion a moment to initialize and download contacts list
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    let companion = CompanionBuilder::new("/dev/ttyUSB0")
+        .app_name("test")
+        .target_version(3)
+        .connect()
+        .await
+        .expect("Failed to connect to companion");
 
     // G2GH    = 0663f1725334df8c20b2269e426c546ca9bea2a975c287eb1bcead3cdac56fb6
     // Pete    = 0680ae32618ef25b6a43b30c646d8458f6da82c33556a8ced1600aa111588b6f
@@ -95,22 +70,15 @@ async fn main() {
 
     // Receive messages
     loop {
-        while let Some(msg) = companion.pop_message().await {
-            match msg {
-                MessageTypes::ContactMsg(msg) => {
-                    info!("[{}] {}", msg.pubkey_prefix, msg.text);
+        while let Some(msg) = companion.pop_received().await {
+            let sender = match companion.resolve_sender(&msg).await {
+                Some(contact) => contact.adv_name,
+                None => match msg.source() {
+                    MessageSource::Contact(prefix) => prefix.to_string(),
+                    MessageSource::Channel(id) => format!("channel {id}"),
                 },
-                MessageTypes::ContactMsgV3(msg) => {
-                    info!("[{}] {}", msg.pubkey_prefix, msg.text);
-                },
-                MessageTypes::ChannelMsg(msg) => {
-                    info!("[{}] {}", msg.channel_id, msg.text);
-                }
-                MessageTypes::ChannelMsgV3(msg) => {
-                    info!("[{}] {}", msg.channel_id, msg.text);
-                }
-
-            }
+            };
+            info!("[{}] {} (snr: {:?}, hops: {:?})", sender, msg.text(), msg.snr_db(), msg.hops());
         }
         tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
     }
@@ -3,10 +3,9 @@ use tracing_subscriber::{EnvFilter, Registry};
 use tracing_subscriber::fmt::format::FmtSpan;
 use console_subscriber as tokio_console_subscriber;
 use tracing_subscriber::layer::SubscriberExt;
-use meshcore_companion_rs::{Companion, Commands, MessageTypes, AppStart};
-use meshcore_companion_rs::commands::{AdvertisementMode, DeviceQuery, GetContacts, RadioParameters, SendChannelTxtMsg};
-use meshcore_companion_rs::consts;
-use meshcore_companion_rs::consts::USA_RADIO_PRESET;
+use meshcore_companion_rs::{CompanionBuilder, MessageTypes};
+use meshcore_companion_rs::commands::{AdvertisementMode, RadioParameters, SendChannelTxtMsg};
+use meshcore_companion_rs::radio::RadioPreset;
 use meshcore_companion_rs::contact_mgmt::PublicKey;
 
 #[tokio::main]
@@ -33,43 +32,18 @@ async fn main() {
         .with(format_layer);
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set tracing subscriber");
     //endregion
-    let mut companion = Companion::new("/dev/ttyUSB0");
-    companion.start().await.unwrap();
+    let companion = CompanionBuilder::new("/dev/ttyUSB0")
+        .app_name("test")
+        .target_version(3)
+        .connect()
+        .await
+        .expect("Failed to connect to companion");
 
-    //region companion data sync (contacts, app info)
-    let appstart: AppStart = AppStart {
-        code: consts::CMD_APP_START,
-        app_ver: 1,
-        app_name: "test".to_string(),
-        ..AppStart::default()
-    };
-    let _ = companion.command(Commands::CmdAppStart(appstart)).await;
 
-    let data: DeviceQuery = DeviceQuery {
-        code: consts::CMD_DEVICE_QEURY,
-        app_target_ver: 3,
-    };
-    let _ = companion.command(Commands::CmdDeviceQuery(data)).await;
-
-    let data: GetContacts = GetContacts {
-        code: consts::CMD_GET_CONTACTS,
-        since: None,
-    };
-    let _ = companion.command(Commands::CmdGetContacts(data)).await;
-    let _ = companion.command(Commands::CmdGetBattAndStorage).await;
-    let _ = companion.command(Commands::CmdSetDeviceTime).await;
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-    let _ = companion.command(Commands::CmdGetDeviceTime).await;
-    //endregion
-
-
-
-    // Give the companion a moment to initialize and download contacts list
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-
-
-    let rp = USA_RADIO_PRESET;
-    let _ = companion.command(Commands::CmdSetRadioParams(rp)).await;
+    match companion.apply_preset(RadioPreset::Usa).await {
+        Ok(()) => info!("Radio now on {}", RadioPreset::Usa.parameters()),
+        Err(e) => error!("Failed to apply radio preset: {e}"),
+    }
     // let power = 255;
     // let _ = companion.command(Commands::CmdSetRadioTxPower(power)).await;
 
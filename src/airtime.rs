@@ -0,0 +1,128 @@
+use crate::commands::RadioParameters;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+// MeshCore radios use a 16 symbol preamble, explicit header and CRC
+const PREAMBLE_SYMBOLS: f64 = 16.0;
+
+/// Time on air of a LoRa packet with `payload_len` bytes (Semtech AN1200.13).
+pub fn airtime(params: &RadioParameters, payload_len: usize) -> Duration {
+    let sf = params.radio_sf as f64;
+    let symbol_ms = 2f64.powf(sf) / (params.radio_bw as f64 / 1000.0);
+    // Low data rate optimisation is mandated once symbols exceed 16ms
+    let low_data_rate = if symbol_ms > 16.0 { 1.0 } else { 0.0 };
+    let coding_rate = params.radio_cr.saturating_sub(4).max(1) as f64;
+    let numerator = 8.0 * payload_len as f64 - 4.0 * sf + 28.0 + 16.0;
+    let denominator = 4.0 * (sf - 2.0 * low_data_rate);
+    let payload_symbols = 8.0 + ((numerator / denominator).ceil() * (coding_rate + 4.0)).max(0.0);
+    let preamble_ms = (PREAMBLE_SYMBOLS + 4.25) * symbol_ms;
+    Duration::from_secs_f64((preamble_ms + payload_symbols * symbol_ms) / 1000.0)
+}
+
+/// Over-the-air size of a direct text message sent without a path.
+pub fn txt_msg_packet_len(text_len: usize) -> usize {
+    // header, path length, dest hash, src hash, MAC, then timestamp + flags + text in AES blocks
+    2 + 1 + 1 + 2 + (4 + 1 + text_len).div_ceil(16) * 16
+}
+
+/// Over-the-air size of a channel message, which carries "name: text".
+pub fn grp_txt_packet_len(sender_name_len: usize, text_len: usize) -> usize {
+    // header, path length, channel hash, MAC, then timestamp + flags + "name: text" in AES blocks
+    2 + 1 + 2 + (4 + 1 + sender_name_len + 2 + text_len).div_ceil(16) * 16
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitMode {
+    /// Wait for budget to free up, for at most `max_delay`
+    Delay,
+    /// Fail the send with `AppError::DutyCycle`
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DutyCycleConfig {
+    /// Hold back outgoing messages that would exceed the budget
+    pub limit: bool,
+    /// Fraction of `window` we may spend transmitting, 0.01 is 1%
+    pub budget: f32,
+    pub window: Duration,
+    pub mode: RateLimitMode,
+    pub max_delay: Duration,
+}
+
+impl Default for DutyCycleConfig {
+    fn default() -> Self {
+        Self {
+            limit: false,
+            budget: 0.01,
+            window: Duration::from_secs(3600),
+            mode: RateLimitMode::Delay,
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl DutyCycleConfig {
+    pub fn allowance(&self) -> Duration {
+        self.window.mul_f32(self.budget.clamp(0.0, 1.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct DutyCycleStatus {
+    pub used: Duration,
+    pub allowance: Duration,
+    /// Share of the window spent transmitting, 0.01 is 1%
+    pub duty_cycle: f32,
+}
+
+/// Sliding window of our own transmissions.
+#[derive(Debug, Default)]
+pub struct DutyCycleTracker {
+    sends: VecDeque<(Instant, Duration)>,
+}
+
+impl DutyCycleTracker {
+    pub fn record(&mut self, at: Instant, airtime: Duration) {
+        self.sends.push_back((at, airtime));
+    }
+
+    /// Airtime spent within `window` before `now`.
+    pub fn used(&mut self, now: Instant, window: Duration) -> Duration {
+        while let Some((at, _)) = self.sends.front()
+            && now.saturating_duration_since(*at) >= window
+        {
+            self.sends.pop_front();
+        }
+        self.sends.iter().map(|(_, airtime)| *airtime).sum()
+    }
+
+    pub fn status(&mut self, now: Instant, config: &DutyCycleConfig) -> DutyCycleStatus {
+        let used = self.used(now, config.window);
+        DutyCycleStatus {
+            used,
+            allowance: config.allowance(),
+            duty_cycle: used.as_secs_f32() / config.window.as_secs_f32().max(f32::EPSILON),
+        }
+    }
+
+    /// How long until `airtime` more fits in the budget, zero if it fits now and None if it never will.
+    pub fn wait_for(&mut self, now: Instant, airtime: Duration, config: &DutyCycleConfig) -> Option<Duration> {
+        let allowance = config.allowance();
+        if airtime > allowance {
+            return None;
+        }
+        let mut used = self.used(now, config.window);
+        if used + airtime <= allowance {
+            return Some(Duration::ZERO);
+        }
+        for (at, spent) in &self.sends {
+            used -= *spent;
+            if used + airtime <= allowance {
+                return Some((*at + config.window).saturating_duration_since(now));
+            }
+        }
+        Some(config.window)
+    }
+}
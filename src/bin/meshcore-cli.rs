@@ -0,0 +1,507 @@
+use clap::{Parser, Subcommand};
+use meshcore_companion_rs::commands::{AdvertisementMode, LatLonAlt, RadioParameters};
+use meshcore_companion_rs::consts;
+use meshcore_companion_rs::contact_mgmt::{advert_from_url, Contact};
+use meshcore_companion_rs::events::CompanionEvent;
+use meshcore_companion_rs::radio::RadioPreset;
+use meshcore_companion_rs::responses::{MessageSource, TuningParameters};
+use meshcore_companion_rs::{AppError, Commands, Companion, CompanionBuilder};
+use serde_json::{json, Value};
+use std::process::ExitCode;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser)]
+#[command(name = "meshcore-cli", version, about = "Talk to a MeshCore companion radio")]
+struct Cli {
+    /// Serial port of the companion radio
+    #[arg(long, short, default_value = "/dev/ttyUSB0")]
+    port: String,
+    /// Connect over TCP (host:port) instead of a serial port
+    #[arg(long, conflicts_with = "port")]
+    tcp: Option<String>,
+    #[arg(long, default_value_t = consts::DEFAULT_BAUD_RATE)]
+    baud: u32,
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    /// Seconds to wait for the radio to answer
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the radio's identity, firmware, radio settings and battery
+    Info,
+    #[command(subcommand)]
+    Contacts(ContactsCommand),
+    #[command(subcommand)]
+    Send(SendCommand),
+    /// Print incoming messages until interrupted
+    Listen {
+        /// Also print every packet the radio hears
+        #[arg(long)]
+        rx_log: bool,
+    },
+    /// Broadcast our advert
+    Advert {
+        /// Let repeaters forward it instead of zero hop only
+        #[arg(long)]
+        flood: bool,
+    },
+    #[command(subcommand)]
+    Radio(RadioCommand),
+    #[command(subcommand)]
+    Tuning(TuningCommand),
+    /// Set the advertised node name
+    Name { name: String },
+    /// Set the advertised position
+    Location {
+        #[arg(allow_negative_numbers = true)]
+        lat: f64,
+        #[arg(allow_negative_numbers = true)]
+        lon: f64,
+        #[arg(long, default_value_t = 0.0)]
+        alt: f64,
+    },
+    Reboot,
+    #[command(subcommand)]
+    Time(TimeCommand),
+}
+
+/// Contacts are given by name or by hex public key prefix
+#[derive(Subcommand)]
+enum ContactsCommand {
+    List,
+    Remove { contact: String },
+    /// Print a meshcore:// URL for a contact, or for ourselves when none is given
+    Export { contact: Option<String> },
+    /// Add a contact from a meshcore:// URL
+    Import { url: String },
+    /// Forget the direct path so the next message floods
+    ResetPath { contact: String },
+}
+
+#[derive(Subcommand)]
+enum SendCommand {
+    Dm { contact: String, text: String },
+    Channel { channel: u8, text: String },
+}
+
+#[derive(Subcommand)]
+enum RadioCommand {
+    Get,
+    Set {
+        freq_mhz: f64,
+        bw_khz: f64,
+        sf: u8,
+        cr: u8,
+    },
+    /// Apply a named preset, or list them when no name is given
+    Preset { name: Option<String> },
+}
+
+#[derive(Subcommand)]
+enum TuningCommand {
+    Get,
+    Set { rx_delay: u32, airtime_factor: u32 },
+}
+
+#[derive(Subcommand)]
+enum TimeCommand {
+    /// Set the radio clock from this host
+    Sync,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
+    tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr).init();
+
+    let cli = Cli::parse();
+    let builder = match &cli.tcp {
+        Some(addr) => CompanionBuilder::tcp(addr),
+        None => CompanionBuilder::new(&cli.port),
+    };
+    let companion = match builder
+        .app_name("meshcore-cli")
+        .baud_rate(cli.baud)
+        .handshake_timeout(Duration::from_secs(cli.timeout))
+        .connect()
+        .await
+    {
+        Ok(companion) => companion,
+        Err(e) => {
+            eprintln!("Failed to connect: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let out = Output { json: cli.json };
+    let result = run(&companion, cli.command, &out, Duration::from_secs(cli.timeout)).await;
+    companion.shutdown().await;
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            out.error(&e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn print(&self, value: Value, text: impl FnOnce() -> String) {
+        if self.json {
+            println!("{value}");
+        } else {
+            println!("{}", text());
+        }
+    }
+    fn done(&self, what: &str) {
+        self.print(json!({ "ok": true, "result": what }), || what.to_string());
+    }
+    fn error(&self, e: &AppError) {
+        if self.json {
+            println!("{}", json!({ "ok": false, "error": e.to_string() }));
+        } else {
+            eprintln!("Error: {e}");
+        }
+    }
+}
+
+async fn run(companion: &Companion, command: Command, out: &Output, timeout: Duration) -> Result<(), AppError> {
+    match command {
+        Command::Info => info(companion, out).await,
+        Command::Contacts(action) => contacts(companion, action, out, timeout).await,
+        Command::Send(SendCommand::Dm { contact, text }) => {
+            let contact = companion.find_contact(&contact).await?;
+            companion.send_text(&contact, text).await?;
+            let report = poll(timeout, || companion.pop_delivery_report()).await;
+            match report {
+                Some(report) => out.print(
+                    json!({
+                        "ok": true,
+                        "to": contact.adv_name,
+                        "outcome": format!("{:?}", report.outcome),
+                        "attempts": report.attempts,
+                        "round_trip_ms": report.round_trip_ms,
+                    }),
+                    || format!("{:?} to {} after {} attempt(s)", report.outcome, contact.adv_name, report.attempts),
+                ),
+                None => out.done("sent, no delivery confirmation yet"),
+            }
+            Ok(())
+        }
+        Command::Send(SendCommand::Channel { channel, text }) => {
+            companion.send_channel_text(channel, text).await?;
+            out.done("sent");
+            Ok(())
+        }
+        Command::Listen { rx_log } => listen(companion, rx_log, out).await,
+        Command::Advert { flood } => {
+            let mode = if flood { AdvertisementMode::Flood } else { AdvertisementMode::ZeroHop };
+            companion.command_and_wait(Commands::CmdSendSelfAdvert(mode), timeout).await?;
+            out.done("advert sent");
+            Ok(())
+        }
+        Command::Radio(action) => radio(companion, action, out).await,
+        Command::Tuning(TuningCommand::Get) => {
+            companion.command(Commands::CmdGetTuningParams).await?;
+            let params = poll(timeout, || companion.get_tuning_parameters())
+                .await
+                .ok_or_else(|| AppError::Timeout("no tuning parameters received".to_string()))?;
+            out.print(
+                json!({ "rx_delay": params.rxdelay_base, "airtime_factor": params.airtime_factor }),
+                || format!("rx delay {}, airtime factor {}", params.rxdelay_base, params.airtime_factor),
+            );
+            Ok(())
+        }
+        Command::Tuning(TuningCommand::Set { rx_delay, airtime_factor }) => {
+            let params = TuningParameters::new(rx_delay, airtime_factor);
+            companion.command_and_wait(Commands::CmdSetTuningParams(params), timeout).await?;
+            out.done("tuning parameters set");
+            Ok(())
+        }
+        Command::Name { name } => {
+            companion.command_and_wait(Commands::CmdSetAdvertName(name), timeout).await?;
+            out.done("name set");
+            Ok(())
+        }
+        Command::Location { lat, lon, alt } => {
+            let position = LatLonAlt::from_decimal(lat, lon, alt);
+            companion.command_and_wait(Commands::CmdSetAdvertLatLon(position), timeout).await?;
+            out.done("location set");
+            Ok(())
+        }
+        Command::Reboot => {
+            companion.command(Commands::CmdReboot).await?;
+            // The radio resets without answering, give the frame a moment to leave
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            out.done("rebooting");
+            Ok(())
+        }
+        Command::Time(TimeCommand::Sync) => {
+            companion.command_and_wait(Commands::CmdSetDeviceTime, timeout).await?;
+            let before = companion.device_clock().await.map(|c| c.measured_at);
+            companion.command(Commands::CmdGetDeviceTime).await?;
+            let clock = poll(timeout, || async {
+                companion.device_clock().await.filter(|c| Some(c.measured_at) != before)
+            })
+            .await
+            .ok_or_else(|| AppError::Timeout("no device time received".to_string()))?;
+            out.print(
+                json!({ "ok": true, "device_time": clock.device_time, "drift_secs": clock.drift_secs() }),
+                || format!("device time {} ({}s drift)", clock.device_time, clock.drift_secs()),
+            );
+            Ok(())
+        }
+    }
+}
+
+async fn info(companion: &Companion, out: &Output) -> Result<(), AppError> {
+    let self_info = companion
+        .get_self_info()
+        .await
+        .ok_or_else(|| AppError::Misc("radio did not send self info".to_string()))?;
+    let capabilities = companion.capabilities().await;
+    let params = self_info.radio_parameters();
+    let preset = RadioPreset::matching(&params).map(|p| p.name());
+    let position = self_info.position();
+    let battery = companion.health().await;
+    out.print(
+        json!({
+            "name": self_info.name(),
+            "public_key": self_info.public_key().to_string(),
+            "model": capabilities.as_ref().map(|c| c.model.clone()),
+            "firmware": capabilities.as_ref().map(|c| c.semantic_version.clone()),
+            "firmware_version": capabilities.as_ref().map(|c| c.firmware_version),
+            "radio": {
+                "freq_mhz": params.radio_freq as f64 / 1000.0,
+                "bw_khz": params.radio_bw as f64 / 1000.0,
+                "sf": params.radio_sf,
+                "cr": params.radio_cr,
+                "preset": preset,
+                "tx_power_dbm": self_info.tx_power_dbm(),
+            },
+            "position": position.map(|p| json!({ "lat": p.lat, "lon": p.lon })),
+            "battery_mv": battery.map(|b| b.millivolts),
+            "battery_percent": battery.map(|b| b.battery_percent),
+            "storage_used_kb": battery.map(|b| b.storage_used_kb),
+            "storage_total_kb": battery.map(|b| b.storage_total_kb),
+        }),
+        || {
+            let mut lines = vec![
+                format!("Name:      {}", self_info.name()),
+                format!("Key:       {}", self_info.public_key()),
+            ];
+            if let Some(c) = &capabilities {
+                lines.push(format!("Firmware:  {} {} (v{})", c.model, c.semantic_version, c.firmware_version));
+            }
+            lines.push(format!(
+                "Radio:     {params}, {} dBm{}",
+                self_info.tx_power_dbm(),
+                preset.map(|p| format!(" ({p})")).unwrap_or_default()
+            ));
+            if let Some(p) = position {
+                lines.push(format!("Position:  {:.6}, {:.6}", p.lat, p.lon));
+            }
+            if let Some(b) = battery {
+                lines.push(format!(
+                    "Battery:   {} mV ({}%), storage {}/{} kB",
+                    b.millivolts, b.battery_percent, b.storage_used_kb, b.storage_total_kb
+                ));
+            }
+            lines.join("\n")
+        },
+    );
+    Ok(())
+}
+
+async fn contacts(companion: &Companion, action: ContactsCommand, out: &Output, timeout: Duration) -> Result<(), AppError> {
+    match action {
+        ContactsCommand::List => {
+            let contacts = companion.get_contacts().await;
+            if out.json {
+                let list: Vec<Value> = contacts.iter().map(contact_json).collect();
+                println!("{}", Value::Array(list));
+            } else {
+                for contact in &contacts {
+                    let path = match contact.out_path_len {
+                        len if len < 0 => "flood".to_string(),
+                        0 => "direct".to_string(),
+                        len => format!("{len} hops"),
+                    };
+                    println!("{:<12} {:<32} {}", hex(&contact.public_key.prefix()), contact.adv_name, path);
+                }
+            }
+        }
+        ContactsCommand::Remove { contact } => {
+            let contact = companion.find_contact(&contact).await?;
+            companion.command_and_wait(Commands::CmdRemoveContact(contact.public_key), timeout).await?;
+            out.done("contact removed");
+        }
+        ContactsCommand::ResetPath { contact } => {
+            let contact = companion.find_contact(&contact).await?;
+            companion.command_and_wait(Commands::CmdResetPath(contact.public_key), timeout).await?;
+            out.done("path reset");
+        }
+        ContactsCommand::Export { contact } => {
+            let key = match contact {
+                Some(name) => Some(companion.find_contact(&name).await?.public_key),
+                None => None,
+            };
+            let owner = match key {
+                Some(key) => key,
+                None => companion
+                    .get_public_key()
+                    .await
+                    .ok_or_else(|| AppError::Misc("radio did not send self info".to_string()))?,
+            };
+            companion.command(Commands::CmdExportContact(key)).await?;
+            let url = poll(timeout, || companion.retrieve_export(owner))
+                .await
+                .ok_or_else(|| AppError::Timeout("no export received".to_string()))?;
+            out.print(json!({ "public_key": owner.to_string(), "url": url }), || url.clone());
+        }
+        ContactsCommand::Import { url } => {
+            let advert = advert_from_url(&url)?;
+            companion.command_and_wait(Commands::CmdImportContact(advert), timeout).await?;
+            out.done("contact imported");
+        }
+    }
+    Ok(())
+}
+
+async fn radio(companion: &Companion, action: RadioCommand, out: &Output) -> Result<(), AppError> {
+    match action {
+        RadioCommand::Get => {
+            let self_info = companion
+                .get_self_info()
+                .await
+                .ok_or_else(|| AppError::Misc("radio did not send self info".to_string()))?;
+            let params = self_info.radio_parameters();
+            print_radio(&params, out);
+        }
+        RadioCommand::Set { freq_mhz, bw_khz, sf, cr } => {
+            let params = RadioParameters::from_mhz(freq_mhz, bw_khz, sf, cr)?;
+            companion.apply_radio_parameters(params.clone()).await?;
+            print_radio(&params, out);
+        }
+        RadioCommand::Preset { name: None } => {
+            let presets: Vec<Value> = RadioPreset::ALL
+                .iter()
+                .map(|p| json!({ "name": p.name(), "parameters": p.parameters().to_string() }))
+                .collect();
+            out.print(Value::Array(presets), || {
+                RadioPreset::ALL
+                    .iter()
+                    .map(|p| format!("{:<16} {}", p.name(), p.parameters()))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+        }
+        RadioCommand::Preset { name: Some(name) } => {
+            let preset = RadioPreset::from_name(&name).ok_or_else(|| AppError::Misc(format!("unknown preset {name}")))?;
+            companion.apply_preset(preset).await?;
+            print_radio(&preset.parameters(), out);
+        }
+    }
+    Ok(())
+}
+
+fn print_radio(params: &RadioParameters, out: &Output) {
+    let preset = RadioPreset::matching(params).map(|p| p.name());
+    out.print(
+        json!({
+            "freq_mhz": params.radio_freq as f64 / 1000.0,
+            "bw_khz": params.radio_bw as f64 / 1000.0,
+            "sf": params.radio_sf,
+            "cr": params.radio_cr,
+            "preset": preset,
+        }),
+        || match preset {
+            Some(preset) => format!("{params} ({preset})"),
+            None => params.to_string(),
+        },
+    );
+}
+
+async fn listen(companion: &Companion, rx_log: bool, out: &Output) -> Result<(), AppError> {
+    let mut events = companion.subscribe();
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        while let Some(msg) = companion.pop_received().await {
+            let sender = match (companion.resolve_sender(&msg).await, msg.source()) {
+                (Some(contact), _) => contact.adv_name,
+                (None, MessageSource::Contact(prefix)) => prefix.to_string(),
+                (None, MessageSource::Channel(id)) => format!("channel {id}"),
+            };
+            out.print(
+                json!({
+                    "type": "message",
+                    "from": sender,
+                    "channel": msg.channel_id(),
+                    "text": msg.text(),
+                    "snr": msg.snr_db(),
+                    "path_len": msg.path_len(),
+                    "sender_timestamp": msg.sender_timestamp(),
+                }),
+                || format!("[{sender}] {}", msg.text()),
+            );
+        }
+        tokio::select! {
+            _ = &mut ctrl_c => return Ok(()),
+            event = events.recv() => {
+                if let Ok(CompanionEvent::RxLog(rx)) = event && rx_log {
+                    let kind = rx.packet.as_ref().map(|p| p.payload_type.name()).unwrap_or("malformed");
+                    out.print(
+                        json!({ "type": "rx_log", "payload_type": kind, "snr": rx.snr_db, "rssi": rx.rssi, "raw": hex(&rx.raw) }),
+                        || format!("<rx {kind} snr {:.2} rssi {}> {}", rx.snr_db, rx.rssi, hex(&rx.raw)),
+                    );
+                }
+            }
+            _ = tokio::time::sleep(Duration::from_millis(250)) => (),
+        }
+    }
+}
+
+/// Polls `check` every 100ms until it returns something or `timeout` passes.
+async fn poll<T, F, Fut>(timeout: Duration, mut check: F) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        if let Some(value) = check().await {
+            return Some(value);
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return None;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+fn contact_json(contact: &Contact) -> Value {
+    json!({
+        "name": contact.adv_name,
+        "public_key": contact.public_key.to_string(),
+        "type": contact.adv_type,
+        "out_path_len": contact.out_path_len,
+        "last_advert": contact.last_advert,
+        "position": contact.position().map(|p| json!({ "lat": p.lat, "lon": p.lon })),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
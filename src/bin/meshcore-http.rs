@@ -0,0 +1,89 @@
+use clap::Parser;
+use meshcore_companion_rs::gateway::{self, GatewayConfig};
+use meshcore_companion_rs::{consts, CompanionBuilder};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing_subscriber::EnvFilter;
+
+/// Serves the companion radio over HTTP and WebSocket.
+#[derive(Parser)]
+#[command(name = "meshcore-http", version)]
+struct Cli {
+    /// Serial port of the companion radio
+    #[arg(long, short, default_value = "/dev/ttyUSB0")]
+    port: String,
+    /// Connect over TCP (host:port) instead of a serial port
+    #[arg(long, conflicts_with = "port")]
+    tcp: Option<String>,
+    #[arg(long, default_value_t = consts::DEFAULT_BAUD_RATE)]
+    baud: u32,
+    #[arg(long, short, default_value = "127.0.0.1:8080")]
+    bind: SocketAddr,
+    /// Bearer token clients must send
+    #[arg(long, required_unless_present = "token_file", conflicts_with = "token_file")]
+    token: Option<String>,
+    /// Read the bearer token from a file, keeping it out of the process list
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+    /// Channel index listed by /api/channels, repeatable
+    #[arg(long = "channel", default_value = "0")]
+    channels: Vec<u8>,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    let cli = Cli::parse();
+    let token = match (cli.token, &cli.token_file) {
+        (Some(token), _) => token,
+        (None, Some(path)) => match std::fs::read_to_string(path) {
+            Ok(token) => token.trim().to_string(),
+            Err(e) => {
+                tracing::error!("Failed to read {}: {e}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        (None, None) => unreachable!("clap requires a token"),
+    };
+    let builder = match &cli.tcp {
+        Some(addr) => CompanionBuilder::tcp(addr),
+        None => CompanionBuilder::new(&cli.port),
+    };
+    let companion = match builder.app_name("meshcore-http").baud_rate(cli.baud).connect().await {
+        Ok(companion) => Arc::new(companion),
+        Err(e) => {
+            tracing::error!("Failed to connect: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let config = GatewayConfig { bind: cli.bind, token, channels: cli.channels };
+    let (stop, shutdown) = watch::channel(false);
+    let server = tokio::spawn(gateway::serve(companion.clone(), config, shutdown));
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+    tracing::info!("Shutting down");
+    stop.send_replace(true);
+    let result = server.await;
+    companion.shutdown().await;
+    match result {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(e)) => {
+            tracing::error!("{e}");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            tracing::error!("HTTP gateway task failed: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use clap::Parser;
+use meshcore_companion_rs::mqtt::{self, MqttConfig, MqttTopics};
+use meshcore_companion_rs::{consts, CompanionBuilder};
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing_subscriber::EnvFilter;
+
+/// Publishes mesh traffic to an MQTT broker and sends messages on request.
+#[derive(Parser)]
+#[command(name = "meshcore-mqtt", version)]
+struct Cli {
+    /// Serial port of the companion radio
+    #[arg(long, short, default_value = "/dev/ttyUSB0")]
+    port: String,
+    /// Connect over TCP (host:port) instead of a serial port
+    #[arg(long, conflicts_with = "port")]
+    tcp: Option<String>,
+    #[arg(long, default_value_t = consts::DEFAULT_BAUD_RATE)]
+    baud: u32,
+    /// MQTT broker host
+    #[arg(long, short, default_value = "localhost")]
+    broker: String,
+    #[arg(long, default_value_t = 1883)]
+    broker_port: u16,
+    #[arg(long, default_value = "meshcore-bridge")]
+    client_id: String,
+    #[arg(long, requires = "password")]
+    username: Option<String>,
+    #[arg(long, requires = "username")]
+    password: Option<String>,
+    /// Every topic starts with this
+    #[arg(long, default_value = "meshcore")]
+    topic_prefix: String,
+    /// Serve the cmd/command topic, which sends any companion command to the radio
+    #[arg(long)]
+    allow_raw_commands: bool,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    let cli = Cli::parse();
+    let builder = match &cli.tcp {
+        Some(addr) => CompanionBuilder::tcp(addr),
+        None => CompanionBuilder::new(&cli.port),
+    };
+    let companion = match builder.app_name("meshcore-mqtt").baud_rate(cli.baud).connect().await {
+        Ok(companion) => Arc::new(companion),
+        Err(e) => {
+            tracing::error!("Failed to connect: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let config = MqttConfig {
+        host: cli.broker,
+        port: cli.broker_port,
+        client_id: cli.client_id,
+        credentials: cli.username.zip(cli.password),
+        topics: MqttTopics::with_prefix(&cli.topic_prefix),
+        allow_raw_commands: cli.allow_raw_commands,
+        ..MqttConfig::default()
+    };
+    let (stop, shutdown) = watch::channel(false);
+    let bridge = tokio::spawn(mqtt::run_bridge(companion.clone(), config, shutdown));
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+    tracing::info!("Shutting down");
+    stop.send_replace(true);
+    let result = bridge.await;
+    companion.shutdown().await;
+    match result {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(e)) => {
+            tracing::error!("{e}");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            tracing::error!("MQTT bridge task failed: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use clap::Parser;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use meshcore_companion_rs::consts;
+use meshcore_companion_rs::delivery::DeliveryOutcome;
+use meshcore_companion_rs::events::CompanionEvent;
+use meshcore_companion_rs::health::HealthReading;
+use meshcore_companion_rs::responses::{MessageSource, ReceivedMessage};
+use meshcore_companion_rs::{Companion, CompanionBuilder, ConnectionState};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Parser)]
+#[command(name = "meshcore-tui", version, about = "Terminal chat client for a MeshCore companion radio")]
+struct Cli {
+    /// Serial port of the companion radio
+    #[arg(long, short, default_value = "/dev/ttyUSB0")]
+    port: String,
+    /// Connect over TCP (host:port) instead of a serial port
+    #[arg(long, conflicts_with = "port")]
+    tcp: Option<String>,
+    #[arg(long, default_value_t = consts::DEFAULT_BAUD_RATE)]
+    baud: u32,
+    /// Channel indexes to list in the sidebar, channels we receive on are added as they show up
+    #[arg(long = "channel", default_values_t = [0u8])]
+    channels: Vec<u8>,
+    /// Write logs to this file, the terminal is taken by the interface
+    #[arg(long)]
+    log: Option<std::path::PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    if let Some(path) = &cli.log {
+        let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+        tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_ansi(false)
+            .with_writer(Mutex::new(std::fs::File::create(path)?))
+            .init();
+    }
+
+    let builder = match &cli.tcp {
+        Some(addr) => CompanionBuilder::tcp(addr),
+        None => CompanionBuilder::new(&cli.port),
+    };
+    let companion = match builder.app_name("meshcore-tui").baud_rate(cli.baud).connect().await {
+        Ok(companion) => companion,
+        Err(e) => {
+            eprintln!("Failed to connect: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut app = App::new(&cli.channels);
+    app.connection = companion.connection_state();
+    app.battery = companion.health().await;
+    app.refresh(&companion).await;
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal, &companion).await;
+    ratatui::restore();
+    companion.shutdown().await;
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Conversation {
+    Contact([u8; 6]),
+    Channel(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tick {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+struct ChatLine {
+    at: SystemTime,
+    from: String,
+    text: String,
+    /// Set on our own messages, along with the timestamp the delivery report will carry
+    sent: Option<(u32, Tick)>,
+}
+
+struct Chat {
+    title: String,
+    lines: Vec<ChatLine>,
+    unread: usize,
+}
+
+struct App {
+    order: Vec<Conversation>,
+    chats: HashMap<Conversation, Chat>,
+    selected: usize,
+    input: String,
+    /// Lines scrolled up from the bottom of the conversation
+    scroll: usize,
+    connection: ConnectionState,
+    battery: Option<HealthReading>,
+    radio: Option<String>,
+    self_name: String,
+    notice: Option<String>,
+    quit: bool,
+}
+
+impl App {
+    fn new(channels: &[u8]) -> Self {
+        let mut app = Self {
+            order: vec![],
+            chats: HashMap::new(),
+            selected: 0,
+            input: String::new(),
+            scroll: 0,
+            connection: ConnectionState::Connecting,
+            battery: None,
+            radio: None,
+            self_name: String::new(),
+            notice: None,
+            quit: false,
+        };
+        for channel in channels {
+            app.chat(Conversation::Channel(*channel), None);
+        }
+        app
+    }
+
+    /// Looks up a conversation, adding it to the sidebar the first time it is seen.
+    fn chat(&mut self, conversation: Conversation, title: Option<&str>) -> &mut Chat {
+        if !self.chats.contains_key(&conversation) {
+            let selected = self.current();
+            self.chats.insert(
+                conversation,
+                Chat {
+                    title: title.filter(|t| !t.is_empty()).map_or_else(|| default_title(conversation), str::to_string),
+                    lines: vec![],
+                    unread: 0,
+                },
+            );
+            self.order.push(conversation);
+            // Channels first, then contacts by name
+            let chats = &self.chats;
+            self.order.sort_by_cached_key(|c| match c {
+                Conversation::Channel(idx) => (0, *idx, String::new()),
+                Conversation::Contact(_) => (1, 0, chats[c].title.to_lowercase()),
+            });
+            if let Some(selected) = selected {
+                self.selected = self.order.iter().position(|c| *c == selected).unwrap_or(0);
+            }
+        }
+        let chat = self.chats.get_mut(&conversation).unwrap();
+        if let Some(title) = title
+            && !title.is_empty()
+        {
+            chat.title = title.to_string();
+        }
+        chat
+    }
+
+    fn current(&self) -> Option<Conversation> {
+        self.order.get(self.selected).copied()
+    }
+
+    fn select(&mut self, index: usize) {
+        self.selected = index.min(self.order.len().saturating_sub(1));
+        self.scroll = 0;
+        if let Some(conversation) = self.current()
+            && let Some(chat) = self.chats.get_mut(&conversation)
+        {
+            chat.unread = 0;
+        }
+    }
+
+    /// Picks up new contacts and radio settings.
+    async fn refresh(&mut self, companion: &Companion) {
+        for contact in companion.get_contacts().await {
+            self.chat(Conversation::Contact(contact.public_key.prefix_bytes()), Some(&contact.adv_name));
+        }
+        if let Some(info) = companion.get_self_info().await {
+            self.radio = Some(info.radio_parameters().to_string());
+            self.self_name = info.name().to_string();
+        }
+    }
+
+    async fn run(&mut self, terminal: &mut DefaultTerminal, companion: &Companion) -> std::io::Result<()> {
+        let mut keys = EventStream::new();
+        let mut events = companion.subscribe();
+        let mut connection = companion.watch_connection();
+        let mut refresh = tokio::time::interval(Duration::from_secs(5));
+        // Messages that arrived before we subscribed
+        self.drain_inbox(companion).await;
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            tokio::select! {
+                key = keys.next() => match key {
+                    Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => self.on_key(key, companion).await,
+                    Some(Ok(_)) => (),
+                    Some(Err(e)) => return Err(e),
+                    None => break,
+                },
+                event = events.recv() => match event {
+                    Ok(CompanionEvent::Message(_)) => self.drain_inbox(companion).await,
+                    Ok(event) => self.on_event(event),
+                    Err(RecvError::Lagged(missed)) => {
+                        self.notice = Some(format!("Missed {missed} events"));
+                        self.drain_inbox(companion).await;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                changed = connection.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    self.connection = *connection.borrow_and_update();
+                },
+                _ = refresh.tick() => self.refresh(companion).await,
+            }
+        }
+        Ok(())
+    }
+
+    /// The inbox keeps its duplicate suppression and ordering, so messages are taken from there.
+    async fn drain_inbox(&mut self, companion: &Companion) {
+        while let Some(msg) = companion.pop_received().await {
+            let contact = companion.resolve_sender(&msg).await;
+            self.on_message(msg, contact.map(|c| c.adv_name));
+        }
+    }
+
+    fn on_message(&mut self, msg: ReceivedMessage, sender: Option<String>) {
+        let (conversation, from, text) = match msg.source() {
+            MessageSource::Contact(prefix) => {
+                let from = sender.clone().unwrap_or_else(|| prefix.to_string());
+                (Conversation::Contact(prefix.bytes()), from, msg.text().to_string())
+            }
+            // Channel messages carry the sender's name in front of the text
+            MessageSource::Channel(idx) => match msg.text().split_once(": ") {
+                Some((from, text)) => (Conversation::Channel(idx), from.to_string(), text.to_string()),
+                None => (Conversation::Channel(idx), String::new(), msg.text().to_string()),
+            },
+        };
+        let active = self.current() == Some(conversation);
+        let chat = self.chat(conversation, sender.as_deref());
+        chat.lines.push(ChatLine {
+            at: msg.received_at(),
+            from,
+            text,
+            sent: None,
+        });
+        if !active {
+            chat.unread += 1;
+        }
+    }
+
+    fn on_event(&mut self, event: CompanionEvent) {
+        match event {
+            CompanionEvent::Health(reading) => self.battery = Some(reading),
+            CompanionEvent::BatteryLow(reading) => {
+                self.notice = Some(format!("Battery low: {}%", reading.battery_percent));
+                self.battery = Some(reading);
+            }
+            CompanionEvent::Delivery(report) => {
+                let conversation = Conversation::Contact(report.pubkey_prefix.bytes());
+                self.set_tick(conversation, report.sender_timestamp, report.outcome);
+            }
+            CompanionEvent::ChannelDelivery(report) => {
+                let conversation = Conversation::Channel(report.channel_idx);
+                self.set_tick(conversation, report.sender_timestamp, report.outcome);
+            }
+            _ => (),
+        }
+    }
+
+    fn set_tick(&mut self, conversation: Conversation, sender_timestamp: u32, outcome: DeliveryOutcome) {
+        let Some(chat) = self.chats.get_mut(&conversation) else {
+            return;
+        };
+        let line = chat
+            .lines
+            .iter_mut()
+            .rev()
+            .find(|l| l.sent.is_some_and(|(ts, tick)| ts == sender_timestamp && tick == Tick::Pending));
+        if let Some(line) = line {
+            let tick = match outcome {
+                DeliveryOutcome::Delivered => Tick::Delivered,
+                DeliveryOutcome::Failed => Tick::Failed,
+            };
+            line.sent = Some((sender_timestamp, tick));
+        }
+    }
+
+    async fn on_key(&mut self, key: KeyEvent, companion: &Companion) {
+        self.notice = None;
+        match key.code {
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Up | KeyCode::BackTab => self.select(self.selected.saturating_sub(1)),
+            KeyCode::Down | KeyCode::Tab => self.select(self.selected + 1),
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Enter => self.send(companion).await,
+            KeyCode::Char(c) => self.input.push(c),
+            _ => (),
+        }
+    }
+
+    async fn send(&mut self, companion: &Companion) {
+        let text = self.input.trim().to_string();
+        let Some(conversation) = self.current() else {
+            return;
+        };
+        if text.is_empty() {
+            return;
+        }
+        let sent = match conversation {
+            Conversation::Contact(pubkey_prefix) => companion.send_text_to_prefix(pubkey_prefix, text.clone()).await,
+            Conversation::Channel(channel_idx) => companion.send_channel_text(channel_idx, text.clone()).await,
+        };
+        let (sender_timestamp, tick) = match sent {
+            Ok(sender_timestamp) => (sender_timestamp, Tick::Pending),
+            Err(e) => {
+                self.notice = Some(format!("Send failed: {e}"));
+                (0, Tick::Failed)
+            }
+        };
+        self.input.clear();
+        self.scroll = 0;
+        let from = self.self_name.clone();
+        self.chat(conversation, None).lines.push(ChatLine {
+            at: SystemTime::now(),
+            from,
+            text,
+            sent: Some((sender_timestamp, tick)),
+        });
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, input, status] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3), Constraint::Length(1)]).areas(frame.area());
+        let [sidebar, conversation] =
+            Layout::horizontal([Constraint::Length(28), Constraint::Min(20)]).areas(main);
+        self.draw_sidebar(frame, sidebar);
+        self.draw_conversation(frame, conversation);
+
+        let prompt = Paragraph::new(self.input.as_str()).block(Block::default().borders(Borders::ALL).title("Message"));
+        frame.render_widget(prompt, input);
+        frame.set_cursor_position((input.x + 1 + self.input.chars().count() as u16, input.y + 1));
+
+        frame.render_widget(Paragraph::new(self.status_line()), status);
+    }
+
+    fn draw_sidebar(&self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .order
+            .iter()
+            .map(|c| {
+                let chat = &self.chats[c];
+                let mut spans = vec![Span::raw(chat.title.clone())];
+                if chat.unread > 0 {
+                    spans.push(Span::styled(format!(" ({})", chat.unread), Style::default().fg(Color::Yellow)));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("Conversations"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut state = ListState::default().with_selected(Some(self.selected));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_conversation(&self, frame: &mut Frame, area: Rect) {
+        let chat = self.current().and_then(|c| self.chats.get(&c));
+        let title = chat.map(|c| c.title.clone()).unwrap_or_default();
+        let lines: Vec<Line> = chat
+            .map(|chat| chat.lines.iter().map(render_line).collect())
+            .unwrap_or_default();
+        let block = Block::default().borders(Borders::ALL).title(title);
+        let inner = block.inner(area);
+        let paragraph = Paragraph::new(lines).block(block).wrap(Wrap { trim: false });
+        // Stick to the bottom unless the user has scrolled up
+        let total = paragraph.line_count(area.width).saturating_sub(2);
+        let bottom = total.saturating_sub(inner.height as usize);
+        let offset = bottom.saturating_sub(self.scroll);
+        frame.render_widget(paragraph.scroll((offset as u16, 0)), area);
+    }
+
+    fn status_line(&self) -> Line<'static> {
+        let (connection, color) = match self.connection {
+            ConnectionState::Connecting => ("connecting".to_string(), Color::Yellow),
+            ConnectionState::Connected => ("connected".to_string(), Color::Green),
+            ConnectionState::Reconnecting { attempt } => (format!("reconnecting ({attempt})"), Color::Yellow),
+            ConnectionState::Closed => ("closed".to_string(), Color::Red),
+        };
+        let mut spans = vec![Span::styled(format!(" {connection} "), Style::default().fg(color))];
+        if let Some(battery) = &self.battery {
+            spans.push(Span::raw(format!(
+                "| {:.2} V {}% ",
+                battery.millivolts as f64 / 1000.0,
+                battery.battery_percent
+            )));
+        }
+        if let Some(radio) = &self.radio {
+            spans.push(Span::raw(format!("| {radio} ")));
+        }
+        if let Some(notice) = &self.notice {
+            spans.push(Span::styled(format!("| {notice}"), Style::default().fg(Color::Red)));
+        }
+        Line::from(spans)
+    }
+}
+
+fn default_title(conversation: Conversation) -> String {
+    match conversation {
+        Conversation::Channel(0) => "#public".to_string(),
+        Conversation::Channel(idx) => format!("#channel {idx}"),
+        Conversation::Contact(prefix) => prefix.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+fn render_line(line: &ChatLine) -> Line<'static> {
+    let secs = line.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 86_400;
+    let time = format!("{:02}:{:02} ", secs / 3600, secs % 3600 / 60);
+    let from_style = match line.sent {
+        Some(_) => Style::default().fg(Color::Cyan),
+        None => Style::default().fg(Color::Magenta),
+    };
+    let mut spans = vec![
+        Span::styled(time, Style::default().fg(Color::DarkGray)),
+        Span::styled(format!("<{}> ", line.from), from_style.add_modifier(Modifier::BOLD)),
+        Span::raw(line.text.clone()),
+    ];
+    match line.sent {
+        Some((_, Tick::Pending)) => spans.push(Span::styled(" ·", Style::default().fg(Color::DarkGray))),
+        Some((_, Tick::Delivered)) => spans.push(Span::styled(" ✓", Style::default().fg(Color::Green))),
+        Some((_, Tick::Failed)) => spans.push(Span::styled(" ✗", Style::default().fg(Color::Red))),
+        None => (),
+    }
+    Line::from(spans)
+}
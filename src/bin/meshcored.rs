@@ -0,0 +1,67 @@
+use clap::Parser;
+use meshcore_companion_rs::{consts, rpc, CompanionBuilder};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing_subscriber::EnvFilter;
+
+/// Owns the companion radio and shares it with other processes over JSON-RPC.
+#[derive(Parser)]
+#[command(name = "meshcored", version)]
+struct Cli {
+    /// Serial port of the companion radio
+    #[arg(long, short, default_value = "/dev/ttyUSB0")]
+    port: String,
+    /// Connect over TCP (host:port) instead of a serial port
+    #[arg(long, conflicts_with = "port")]
+    tcp: Option<String>,
+    #[arg(long, default_value_t = consts::DEFAULT_BAUD_RATE)]
+    baud: u32,
+    /// Unix socket to serve JSON-RPC on
+    #[arg(long, short, default_value = "/tmp/meshcored.sock")]
+    socket: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    let cli = Cli::parse();
+    let builder = match &cli.tcp {
+        Some(addr) => CompanionBuilder::tcp(addr),
+        None => CompanionBuilder::new(&cli.port),
+    };
+    let companion = match builder.app_name("meshcored").baud_rate(cli.baud).connect().await {
+        Ok(companion) => Arc::new(companion),
+        Err(e) => {
+            tracing::error!("Failed to connect: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let (stop, shutdown) = watch::channel(false);
+    let server = tokio::spawn(rpc::serve_unix(companion.clone(), cli.socket, shutdown));
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+    tracing::info!("Shutting down");
+    stop.send_replace(true);
+    let result = server.await;
+    companion.shutdown().await;
+    match result {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(e)) => {
+            tracing::error!("{e}");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            tracing::error!("RPC server task failed: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
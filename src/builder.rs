@@ -0,0 +1,233 @@
+use crate::commands::{send_command, AppStart, DeviceQuery, GetContacts};
+use crate::delivery::{ChannelPolicy, DeliveryPolicy};
+use crate::inbox::InboxConfig;
+use crate::multipart::MultipartConfig;
+use crate::{consts, AppError, Commands, Companion, CompanionState, SerialConfig};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+/// What to exchange with the radio before a `Companion` is considered ready.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakeConfig {
+    pub app_name: String,
+    pub app_version: u8,
+    pub target_version: u8,
+    pub sync_time: bool,
+    pub sync_contacts: bool,
+    pub timeout: Duration,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        let app_start = AppStart::default();
+        Self {
+            app_name: app_start.app_name,
+            app_version: app_start.app_ver,
+            target_version: 3,
+            sync_time: true,
+            sync_contacts: true,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompanionBuilder {
+    port: String,
+    serial: SerialConfig,
+    handshake: HandshakeConfig,
+    inbox: Option<InboxConfig>,
+    multipart: Option<MultipartConfig>,
+    delivery_policy: Option<DeliveryPolicy>,
+    channel_policy: Option<ChannelPolicy>,
+    record_session: Option<PathBuf>,
+    tcp: bool,
+}
+
+impl CompanionBuilder {
+    pub fn new(port: &str) -> Self {
+        Self {
+            port: port.to_string(),
+            serial: SerialConfig::default(),
+            handshake: HandshakeConfig::default(),
+            inbox: None,
+            multipart: None,
+            delivery_policy: None,
+            channel_policy: None,
+            record_session: None,
+            tcp: false,
+        }
+    }
+    /// Connect over TCP to `addr` (host:port) instead of a serial port.
+    pub fn tcp(addr: &str) -> Self {
+        Self {
+            tcp: true,
+            ..Self::new(addr)
+        }
+    }
+    pub fn serial_config(mut self, config: SerialConfig) -> Self {
+        self.serial = config;
+        self
+    }
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.serial.baud_rate = baud_rate;
+        self
+    }
+    pub fn app_name(mut self, name: &str) -> Self {
+        self.handshake.app_name = name.to_string();
+        self
+    }
+    pub fn app_version(mut self, version: u8) -> Self {
+        self.handshake.app_version = version;
+        self
+    }
+    pub fn target_version(mut self, version: u8) -> Self {
+        self.handshake.target_version = version;
+        self
+    }
+    pub fn sync_time(mut self, sync: bool) -> Self {
+        self.handshake.sync_time = sync;
+        self
+    }
+    pub fn sync_contacts(mut self, sync: bool) -> Self {
+        self.handshake.sync_contacts = sync;
+        self
+    }
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake.timeout = timeout;
+        self
+    }
+    pub fn inbox_config(mut self, config: InboxConfig) -> Self {
+        self.inbox = Some(config);
+        self
+    }
+    pub fn multipart_config(mut self, config: MultipartConfig) -> Self {
+        self.multipart = Some(config);
+        self
+    }
+    pub fn delivery_policy(mut self, policy: DeliveryPolicy) -> Self {
+        self.delivery_policy = Some(policy);
+        self
+    }
+    pub fn channel_policy(mut self, policy: ChannelPolicy) -> Self {
+        self.channel_policy = Some(policy);
+        self
+    }
+    pub fn record_session(mut self, path: impl Into<PathBuf>) -> Self {
+        self.record_session = Some(path.into());
+        self
+    }
+
+    /// Opens the port, starts the background tasks and waits for the startup handshake to complete.
+    pub async fn connect(self) -> Result<Companion, AppError> {
+        let mut companion = Companion::with_serial_config(&self.port, self.serial);
+        companion.tcp = self.tcp;
+        if let Some(config) = self.inbox {
+            companion.set_inbox_config(config).await;
+        }
+        if let Some(config) = self.multipart {
+            companion.set_multipart_config(config).await;
+        }
+        if let Some(policy) = self.delivery_policy {
+            companion.set_delivery_policy(policy).await;
+        }
+        if let Some(policy) = self.channel_policy {
+            companion.set_channel_policy(policy).await;
+        }
+        if let Some(path) = self.record_session {
+            companion.record_session(path)?;
+        }
+        companion.start().await?;
+        companion.handshake(&self.handshake).await?;
+        Ok(companion)
+    }
+}
+
+impl Companion {
+    pub fn builder(port: &str) -> CompanionBuilder {
+        CompanionBuilder::new(port)
+    }
+
+    /// Announces the app to the radio and waits until self info, device info and (optionally)
+    /// the contact list have arrived. The handshake is repeated whenever the port reconnects.
+    pub async fn handshake(&self, config: &HandshakeConfig) -> Result<(), AppError> {
+        self.state.write().await.handshake = Some(config.clone());
+        run_handshake(&self.state, &self.port, config).await
+    }
+}
+
+pub(crate) async fn run_handshake(
+    state: &Arc<RwLock<CompanionState>>,
+    port: &str,
+    config: &HandshakeConfig,
+) -> Result<(), AppError> {
+    {
+        let mut state = state.write().await;
+        state.self_info = None;
+        state.device_info = None;
+        state.contacts_synced = false;
+    }
+    let app_start = AppStart {
+        code: consts::CMD_APP_START,
+        app_ver: config.app_version,
+        app_name: config.app_name.clone(),
+        ..AppStart::default()
+    };
+    send_command(state, Commands::CmdAppStart(app_start)).await?;
+    send_command(
+        state,
+        Commands::CmdDeviceQuery(DeviceQuery {
+            code: consts::CMD_DEVICE_QEURY,
+            app_target_ver: config.target_version,
+        }),
+    )
+    .await?;
+    if config.sync_contacts {
+        state.write().await.contacts.clear();
+        send_command(
+            state,
+            Commands::CmdGetContacts(GetContacts {
+                code: consts::CMD_GET_CONTACTS,
+                since: None,
+            }),
+        )
+        .await?;
+    }
+    send_command(state, Commands::CmdGetBattAndStorage).await?;
+    if config.sync_time {
+        send_command(state, Commands::CmdSetDeviceTime).await?;
+    }
+
+    let deadline = Instant::now() + config.timeout;
+    loop {
+        let missing = {
+            let state = state.read().await;
+            let mut missing = vec![];
+            if state.self_info.is_none() {
+                missing.push("self info");
+            }
+            if state.device_info.is_none() {
+                missing.push("device info");
+            }
+            if config.sync_contacts && !state.contacts_synced {
+                missing.push("end of contacts");
+            }
+            missing
+        };
+        if missing.is_empty() {
+            info!("Handshake with {} complete.", port);
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(AppError::Timeout(format!(
+                "device on {} did not answer the handshake, still waiting for {}",
+                port,
+                missing.join(", ")
+            )));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
@@ -0,0 +1,74 @@
+use crate::commands::Commands;
+use crate::consts;
+use crate::responses::DeviceInfo;
+use serde::Serialize;
+
+/// What the connected firmware can do, derived from its `DeviceInfo` response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    pub firmware_version: u8,
+    pub firmware_build_date: String,
+    pub model: String,
+    pub semantic_version: String,
+    pub max_contacts: u16,
+    pub max_channels: u8,
+    /// Messages arrive as the V3 frames that carry SNR
+    pub v3_messages: bool,
+}
+
+impl Capabilities {
+    /// `app_target_ver` is what the app sent in `CmdDeviceQuery`, the firmware only switches to
+    /// V3 message frames when it is at least 3.
+    pub fn new(info: &DeviceInfo, app_target_ver: u8) -> Self {
+        Capabilities {
+            firmware_version: info.firmware_version,
+            firmware_build_date: info.firmware_build_date.clone(),
+            model: info.manufacturer_model.clone(),
+            semantic_version: info.semantic_version.clone(),
+            max_contacts: info.max_contacts_div_2 as u16 * 2,
+            max_channels: info.max_channels,
+            v3_messages: info.firmware_version >= 3 && app_target_ver >= 3,
+        }
+    }
+
+    pub fn supports(&self, cmd: &Commands) -> bool {
+        self.firmware_version >= min_firmware_version(cmd.code())
+    }
+
+    /// Codes of the optional commands this firmware understands.
+    pub fn optional_commands(&self) -> Vec<u8> {
+        OPTIONAL_COMMANDS
+            .iter()
+            .filter(|(_, version)| self.firmware_version >= *version)
+            .map(|(code, _)| *code)
+            .collect()
+    }
+}
+
+/// Lowest `FIRMWARE_VER_CODE` whose companion firmware handles the command, following the
+/// command handlers in MeshCore's `examples/companion_radio/MyMesh.cpp`. Anything not listed
+/// has been there since the first version.
+const OPTIONAL_COMMANDS: [(u8, u8); 15] = [
+    (consts::CMD_SEND_RAW_DATA, 2),
+    (consts::CMD_SEND_LOGIN, 2),
+    (consts::CMD_SEND_STATUS_REQ, 2),
+    (consts::CMD_LOGOUT, 2),
+    (consts::CMD_SEND_TRACE_PATH, 3),
+    (consts::CMD_SET_OTHER_PARAMS, 3),
+    (consts::CMD_SEND_TELEMETRY_REQ, 3),
+    (consts::CMD_GET_CUSTOM_VARS, 4),
+    (consts::CMD_SET_CUSTOM_VARS, 4),
+    (consts::CMD_GET_ADVERT_PATH, 4),
+    (consts::CMD_GET_TUNING_PARAMS, 4),
+    (consts::CMD_SEND_BINARY_REQ, 6),
+    (consts::CMD_FACTORY_RESET, 6),
+    (consts::CMD_SEND_CONTROL_DATA, 7),
+    (consts::CMD_GET_STATS, 8),
+];
+
+fn min_firmware_version(code: u8) -> u8 {
+    OPTIONAL_COMMANDS
+        .iter()
+        .find(|(optional, _)| *optional == code)
+        .map_or(0, |(_, version)| *version)
+}
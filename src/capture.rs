@@ -0,0 +1,215 @@
+use crate::packet::RxLogEvent;
+use crate::AppError;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// LINKTYPE_USER0, there is no registered link type for LoRa/MeshCore yet.
+pub const LINKTYPE_MESHCORE: u16 = 147;
+
+const BLOCK_SHB: u32 = 0x0a0d_0d0a;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+
+/// Minimal pcapng writer with a single MeshCore interface and microsecond timestamps.
+pub struct PcapngWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Writes the section header and interface description.
+    pub fn new(inner: W) -> std::io::Result<Self> {
+        let mut writer = Self { inner, written: 0 };
+        let mut shb = vec![];
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is unknown while we are still appending
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        push_option(&mut shb, OPT_SHB_USERAPPL, env!("CARGO_PKG_NAME").as_bytes());
+        push_option(&mut shb, OPT_END, &[]);
+        writer.write_block(BLOCK_SHB, &shb)?;
+
+        let mut idb = vec![];
+        idb.extend_from_slice(&LINKTYPE_MESHCORE.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        push_option(&mut idb, OPT_IF_NAME, b"meshcore");
+        push_option(&mut idb, OPT_END, &[]);
+        writer.write_block(BLOCK_IDB, &idb)?;
+        writer.inner.flush()?;
+        Ok(writer)
+    }
+
+    /// Appends a packet, with its SNR and RSSI in the packet comment.
+    pub fn write_packet(&mut self, at: SystemTime, data: &[u8], snr_db: f32, rssi: i8) -> std::io::Result<()> {
+        let micros = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        let mut epb = vec![];
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(micros as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(data);
+        pad(&mut epb);
+        push_option(&mut epb, OPT_COMMENT, format!("snr={snr_db:.2}dB rssi={rssi}dBm").as_bytes());
+        push_option(&mut epb, OPT_END, &[]);
+        self.write_block(BLOCK_EPB, &epb)?;
+        self.inner.flush()
+    }
+
+    /// Bytes written so far, headers included.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+        let total = (12 + body.len()) as u32;
+        self.inner.write_all(&block_type.to_le_bytes())?;
+        self.inner.write_all(&total.to_le_bytes())?;
+        self.inner.write_all(body)?;
+        self.inner.write_all(&total.to_le_bytes())?;
+        self.written += total as u64;
+        Ok(())
+    }
+}
+
+fn pad(data: &mut Vec<u8>) {
+    data.resize(data.len().div_ceil(4) * 4, 0);
+}
+
+fn push_option(data: &mut Vec<u8>, code: u16, value: &[u8]) {
+    data.extend_from_slice(&code.to_le_bytes());
+    data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    data.extend_from_slice(value);
+    pad(data);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RotationPolicy {
+    /// Start a new file once the current one reaches this size
+    pub max_bytes: Option<u64>,
+    /// Start a new file once the current one has been open this long
+    pub max_age: Option<Duration>,
+    /// Delete the oldest capture files beyond this count
+    pub max_files: Option<usize>,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        Self {
+            max_bytes: Some(64 * 1024 * 1024),
+            max_age: None,
+            max_files: Some(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureConfig {
+    pub directory: PathBuf,
+    /// Files are named `<prefix>-<unix time>-<sequence>.pcapng`
+    pub prefix: String,
+    pub rotation: RotationPolicy,
+}
+
+impl CaptureConfig {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            prefix: "meshcore".to_string(),
+            rotation: RotationPolicy::default(),
+        }
+    }
+}
+
+/// Writes received packets to a rotating set of pcapng files.
+pub struct PacketCapture {
+    config: CaptureConfig,
+    writer: PcapngWriter<BufWriter<File>>,
+    opened_at: Instant,
+    sequence: u32,
+    files: Vec<PathBuf>,
+}
+
+impl std::fmt::Debug for PacketCapture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketCapture")
+            .field("config", &self.config)
+            .field("files", &self.files)
+            .finish()
+    }
+}
+
+impl PacketCapture {
+    pub fn open(config: CaptureConfig) -> Result<Self, AppError> {
+        std::fs::create_dir_all(&config.directory).map_err(|e| AppError::Capture(e.to_string()))?;
+        let path = file_name(&config, 0);
+        let writer = create(&path)?;
+        Ok(Self {
+            config,
+            writer,
+            opened_at: Instant::now(),
+            sequence: 0,
+            files: vec![path],
+        })
+    }
+
+    pub fn record(&mut self, event: &RxLogEvent) -> Result<(), AppError> {
+        if self.rotation_due() {
+            self.rotate()?;
+        }
+        self.writer
+            .write_packet(event.received_at, &event.raw, event.snr_db, event.rssi)
+            .map_err(|e| AppError::Capture(e.to_string()))
+    }
+
+    /// Files written by this capture that have not been rotated away, oldest first.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    fn rotation_due(&self) -> bool {
+        let policy = &self.config.rotation;
+        policy.max_bytes.is_some_and(|max| self.writer.written() >= max)
+            || policy.max_age.is_some_and(|max| self.opened_at.elapsed() >= max)
+    }
+
+    fn rotate(&mut self) -> Result<(), AppError> {
+        self.sequence += 1;
+        let path = file_name(&self.config, self.sequence);
+        self.writer = create(&path)?;
+        self.opened_at = Instant::now();
+        self.files.push(path);
+        if let Some(max_files) = self.config.rotation.max_files {
+            while self.files.len() > max_files.max(1) {
+                let oldest = self.files.remove(0);
+                std::fs::remove_file(&oldest).map_err(|e| AppError::Capture(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn file_name(config: &CaptureConfig, sequence: u32) -> PathBuf {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    config
+        .directory
+        .join(format!("{}-{started}-{sequence:04}.pcapng", config.prefix))
+}
+
+fn create(path: &Path) -> Result<PcapngWriter<BufWriter<File>>, AppError> {
+    let file = File::create(path).map_err(|e| AppError::Capture(e.to_string()))?;
+    PcapngWriter::new(BufWriter::new(file)).map_err(|e| AppError::Capture(e.to_string()))
+}
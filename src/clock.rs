@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockSyncConfig {
+    /// Periodically read the device clock and set it when it drifts too far
+    pub auto_sync: bool,
+    /// Drift in either direction that triggers a resync
+    pub max_drift: Duration,
+    /// How often the device clock is read
+    pub check_interval: Duration,
+}
+
+impl Default for ClockSyncConfig {
+    fn default() -> Self {
+        Self {
+            auto_sync: true,
+            max_drift: Duration::from_secs(10),
+            check_interval: Duration::from_secs(600),
+        }
+    }
+}
+
+/// A reading of the device clock, taken when `RESP_CODE_CURR_TIME` arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DeviceClock {
+    pub device_time: u32,
+    pub host_time: u32,
+    #[serde(skip)]
+    pub measured_at: Instant,
+}
+
+impl DeviceClock {
+    pub fn new(device_time: u32) -> Self {
+        Self {
+            device_time,
+            host_time: host_time(),
+            measured_at: Instant::now(),
+        }
+    }
+    /// Seconds the device is ahead of the host, negative when it is behind.
+    pub fn drift_secs(&self) -> i64 {
+        self.device_time as i64 - self.host_time as i64
+    }
+    pub fn exceeds(&self, max_drift: Duration) -> bool {
+        self.drift_secs().unsigned_abs() > max_drift.as_secs()
+    }
+}
+
+/// Host time in the 4 byte epoch seconds the firmware uses.
+pub fn host_time() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+}
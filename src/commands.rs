@@ -1,14 +1,24 @@
+use std::collections::HashSet;
+use std::fmt;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
+use crate::airtime::{airtime, grp_txt_packet_len, txt_msg_packet_len, RateLimitMode};
+use crate::capabilities::Capabilities;
+use crate::clock::host_time;
+use crate::radio::{self, RadioParamsError};
 use crate::{consts, string_to_bytes, AppError, CompanionState};
 use crate::consts::*;
 use crate::contact_mgmt::{Contact, PublicKey};
+use crate::history::{ConversationKey, DeliveryStatus, Direction};
+use crate::multipart::split_text;
+use crate::packet::GroupEncrypted;
+use crate::delivery::RouteType;
 use crate::responses::TuningParameters;
 use crate::serial_actor::SerialFrame;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Commands {
     CmdDeviceQuery(DeviceQuery),
     CmdAppStart(AppStart),
@@ -19,11 +29,13 @@ pub enum Commands {
     CmdSetAdvertName(String),
     CmdSetAdvertLatLon(LatLonAlt),
     CmdSyncNextMessage,
-    CmdAddUpdateContact(Contact),
+    /// Boxed as `AppError` carries a `Commands`, which would otherwise make every `Result` large
+    CmdAddUpdateContact(Box<Contact>),
     CmdRemoveContact(PublicKey),
     CmdShareContact(PublicKey),
     CmdExportContact(Option<PublicKey>),
-    CmdImportContact,
+    /// Adds a contact from the advert packet in a `meshcore://` export
+    CmdImportContact(Vec<u8>),
     CmdReboot,
     CmdGetBattAndStorage,
     CmdSetTuningParams(TuningParameters),
@@ -36,11 +48,12 @@ pub enum Commands {
     CmdSendRawData,
     CmdSendLogin(LoginData),
     CmdSendStatusReq,
-    CmdSendTracePath,
+    CmdSendTracePath(TracePath),
     CmdSendTelemetryReq,
     CmdGetCustomVars,
     CmdSetCustomVar,
-    CmdGetAdvertPath,
+    /// Ask for the path the last advert from this contact took to reach us
+    CmdGetAdvertPath(PublicKey),
     CmdGetTuningParams,
     CmdSendBinaryReq,
     CmdFactoryReset,
@@ -49,7 +62,52 @@ pub enum Commands {
     CmdLogout(PublicKey),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl Commands {
+    /// The command code the frame for this command starts with.
+    pub fn code(&self) -> u8 {
+        match self {
+            Commands::CmdDeviceQuery(_) => consts::CMD_DEVICE_QEURY,
+            Commands::CmdAppStart(_) => consts::CMD_APP_START,
+            Commands::CmdGetContacts(_) => consts::CMD_GET_CONTACTS,
+            Commands::CmdGetDeviceTime => consts::CMD_GET_DEVICE_TIME,
+            Commands::CmdSetDeviceTime => consts::CMD_SET_DEVICE_TIME,
+            Commands::CmdSendSelfAdvert(_) => consts::CMD_SEND_SELF_ADVERT,
+            Commands::CmdSetAdvertName(_) => consts::CMD_SET_ADVERT_NAME,
+            Commands::CmdSetAdvertLatLon(_) => consts::CMD_SET_ADVERT_LATLON,
+            Commands::CmdSyncNextMessage => consts::CMD_SYNC_NEXT_MESSAGE,
+            Commands::CmdAddUpdateContact(_) => consts::CMD_ADD_UPDATE_CONTACT,
+            Commands::CmdRemoveContact(_) => consts::CMD_REMOVE_CONTACT,
+            Commands::CmdShareContact(_) => consts::CMD_SHARE_CONTACT,
+            Commands::CmdExportContact(_) => consts::CMD_EXPORT_CONTACT,
+            Commands::CmdImportContact(_) => consts::CMD_IMPORT_CONTACT,
+            Commands::CmdReboot => consts::CMD_REBOOT,
+            Commands::CmdGetBattAndStorage => consts::CMD_GET_BATT_AND_STORAGE,
+            Commands::CmdSetTuningParams(_) => consts::CMD_SET_TUNING_PARAMS,
+            Commands::CmdSetOtherParams => consts::CMD_SET_OTHER_PARAMS,
+            Commands::CmdSendTxtMsg(_) => consts::CMD_SEND_TXT_MSG,
+            Commands::CmdSendChannelTxtMsg(_) => consts::CMD_SEND_CHANNEL_TXT_MSG,
+            Commands::CmdSetRadioParams(_) => consts::CMD_SET_RADIO_PARAMS,
+            Commands::CmdSetRadioTxPower(_) => consts::CMD_SET_RADIO_TX_POWER,
+            Commands::CmdResetPath(_) => consts::CMD_RESET_PATH,
+            Commands::CmdSendRawData => consts::CMD_SEND_RAW_DATA,
+            Commands::CmdSendLogin(_) => consts::CMD_SEND_LOGIN,
+            Commands::CmdSendStatusReq => consts::CMD_SEND_STATUS_REQ,
+            Commands::CmdSendTracePath(_) => consts::CMD_SEND_TRACE_PATH,
+            Commands::CmdSendTelemetryReq => consts::CMD_SEND_TELEMETRY_REQ,
+            Commands::CmdGetCustomVars => consts::CMD_GET_CUSTOM_VARS,
+            Commands::CmdSetCustomVar => consts::CMD_SET_CUSTOM_VARS,
+            Commands::CmdGetAdvertPath(_) => consts::CMD_GET_ADVERT_PATH,
+            Commands::CmdGetTuningParams => consts::CMD_GET_TUNING_PARAMS,
+            Commands::CmdSendBinaryReq => consts::CMD_SEND_BINARY_REQ,
+            Commands::CmdFactoryReset => consts::CMD_FACTORY_RESET,
+            Commands::CmdSendControlData => consts::CMD_SEND_CONTROL_DATA,
+            Commands::CmdGetStats => consts::CMD_GET_STATS,
+            Commands::CmdLogout(_) => consts::CMD_LOGOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LoginData {
     pub code: u8,
     pub public_key: PublicKey,
@@ -65,34 +123,149 @@ impl LoginData {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+/// A trace sent along `path`, each hop adds the SNR it heard the trace with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TracePath {
+    /// Echoed back in `PUSH_CODE_TRACE_DATA` to match the result
+    pub tag: u32,
+    pub auth_code: u32,
+    pub flags: u8,
+    pub path: Vec<u8>,
+}
+impl TracePath {
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut data = vec![consts::CMD_SEND_TRACE_PATH];
+        data.extend_from_slice(&self.tag.to_le_bytes());
+        data.extend_from_slice(&self.auth_code.to_le_bytes());
+        data.push(self.flags);
+        data.extend_from_slice(&self.path);
+        data
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct AppStart {
     pub code: u8,
     pub app_ver: u8,
     pub reserved: [u8; 6],
     pub app_name: String,
 }
+impl Default for AppStart {
+    fn default() -> Self {
+        AppStart {
+            code: consts::CMD_APP_START,
+            app_ver: 3,
+            reserved: [0u8; 6],
+            app_name: env!("CARGO_PKG_NAME").to_string(),
+        }
+    }
+}
+impl AppStart {
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut frame = vec![self.code, self.app_ver];
+        frame.extend_from_slice(&self.reserved);
+        frame.extend_from_slice(self.app_name.as_bytes());
+        frame
+    }
+    pub fn from_frame(frame: &[u8]) -> Self {
+        let mut reserved = [0u8; 6];
+        if let Some(bytes) = frame.get(2..8) {
+            reserved.copy_from_slice(bytes);
+        }
+        let name = frame.get(8..).unwrap_or_default();
+        let name = name.split(|b| *b == 0).next().unwrap_or_default();
+        AppStart {
+            code: frame.first().copied().unwrap_or_default(),
+            app_ver: frame.get(1).copied().unwrap_or_default(),
+            reserved,
+            app_name: String::from_utf8_lossy(name).to_string(),
+        }
+    }
+}
 #[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
 pub struct DeviceQuery {
     pub code: u8,
     pub app_target_ver: u8,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct GetContacts {
     pub code: u8,
     pub since: Option<u32>,
 }
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Reboot {
     pub(crate) code: u8,
     pub(crate) text: String,
 }
+impl Default for Reboot {
+    fn default() -> Self {
+        // The firmware only reboots when the command is followed by the literal "reboot"
+        Reboot {
+            code: consts::CMD_REBOOT,
+            text: "reboot".to_string(),
+        }
+    }
+}
+impl Reboot {
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut frame = vec![self.code];
+        frame.extend_from_slice(self.text.as_bytes());
+        frame
+    }
+    pub fn from_frame(frame: &[u8]) -> Self {
+        Reboot {
+            code: frame.first().copied().unwrap_or_default(),
+            text: String::from_utf8_lossy(frame.get(1..).unwrap_or_default()).to_string(),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct MessageEnvelope {
     pub(crate) msg: SendingMessageTypes,
     pub(crate) last_attempt_timestamp: u128,
+    pub(crate) route: Option<RouteType>,
+    pub(crate) direct_failures: u8,
+    pub(crate) flood_fallback: bool,
+}
+impl MessageEnvelope {
+    pub(crate) fn new(msg: SendingMessageTypes) -> Self {
+        Self {
+            msg,
+            last_attempt_timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+            route: None,
+            direct_failures: 0,
+            flood_fallback: false,
+        }
+    }
+}
+
+/// A channel message awaiting rebroadcast by repeaters. Channel sends are never acked, so the
+/// only evidence of delivery is hearing our own packet repeated.
+#[derive(Debug, Clone)]
+pub struct ChannelEnvelope {
+    pub(crate) msg: SendChannelTxtMsg,
+    pub(crate) last_attempt: Instant,
+    pub(crate) attempts: u8,
+    pub(crate) expected_payload_len: usize,
+    /// Our packet as first heard from a neighbouring repeater, later repeats must be identical
+    pub(crate) fingerprint: Option<GroupEncrypted>,
+    pub(crate) repeaters: HashSet<u8>,
+}
+impl ChannelEnvelope {
+    pub(crate) fn new(msg: SendChannelTxtMsg, sender_name: &str) -> Self {
+        // GRP_TXT payload: channel hash, 2 byte MAC, then AES blocks of timestamp, flags and "name: text"
+        let plaintext_len = 4 + 1 + sender_name.len() + 2 + msg.text.len();
+        Self {
+            msg,
+            last_attempt: Instant::now(),
+            attempts: 1,
+            expected_payload_len: 3 + plaintext_len.div_ceil(16) * 16,
+            fingerprint: None,
+            repeaters: HashSet::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -101,7 +274,7 @@ pub enum SendingMessageTypes {
     ChannelMsg(SendChannelTxtMsg)
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SendTxtMsg {
     pub code: u8,
     pub txt_type: u8,
@@ -120,7 +293,7 @@ impl SendTxtMsg {
         frame
     }
 }
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct SendChannelTxtMsg {
     pub code: u8,
     pub txt_type: u8,
@@ -137,12 +310,12 @@ impl SendChannelTxtMsg {
         frame   
     }
 }
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum AdvertisementMode {
     ZeroHop = 0,
     Flood = 1
 }
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LatLonAlt {
     pub latitude: i32,
     pub longitude: i32,
@@ -171,7 +344,7 @@ impl LatLonAlt {
         (self.latitude as f64 / 1E6, self.longitude as f64 / 1E6, self.altitude as f64 / 1E6)
     }
 }
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RadioParameters {
     pub code: u8,
     pub radio_freq:u32,
@@ -180,14 +353,42 @@ pub struct RadioParameters {
     pub radio_cr: u8
 }
 impl RadioParameters {
-    pub fn new(radio_freq: u32, radio_bw: u32, radio_sf: u8, radio_cr: u8) -> Self {
-        Self {
+    /// Frequency in kHz, bandwidth in Hz, coding rate as the denominator of 4/x.
+    pub fn new(radio_freq: u32, radio_bw: u32, radio_sf: u8, radio_cr: u8) -> Result<Self, RadioParamsError> {
+        let params = Self {
             code: CMD_SET_RADIO_PARAMS,
             radio_freq,
             radio_bw,
             radio_sf,
             radio_cr
+        };
+        params.validate()?;
+        Ok(params)
+    }
+    pub fn from_mhz(freq_mhz: f64, bw_khz: f64, radio_sf: u8, radio_cr: u8) -> Result<Self, RadioParamsError> {
+        Self::new((freq_mhz * 1000.0).round() as u32, (bw_khz * 1000.0).round() as u32, radio_sf, radio_cr)
+    }
+    pub fn validate(&self) -> Result<(), RadioParamsError> {
+        if !(radio::MIN_FREQ_KHZ..=radio::MAX_FREQ_KHZ).contains(&self.radio_freq) {
+            return Err(RadioParamsError::Frequency(self.radio_freq));
+        }
+        if !(radio::MIN_BW_HZ..=radio::MAX_BW_HZ).contains(&self.radio_bw) {
+            return Err(RadioParamsError::Bandwidth(self.radio_bw));
         }
+        if !(radio::MIN_SF..=radio::MAX_SF).contains(&self.radio_sf) {
+            return Err(RadioParamsError::SpreadingFactor(self.radio_sf));
+        }
+        if !(radio::MIN_CR..=radio::MAX_CR).contains(&self.radio_cr) {
+            return Err(RadioParamsError::CodingRate(self.radio_cr));
+        }
+        Ok(())
+    }
+    /// Compares the radio settings, ignoring the command code.
+    pub fn same_settings(&self, other: &RadioParameters) -> bool {
+        self.radio_freq == other.radio_freq
+            && self.radio_bw == other.radio_bw
+            && self.radio_sf == other.radio_sf
+            && self.radio_cr == other.radio_cr
     }
     pub fn to_frame(&self) -> Vec<u8> {
         let mut frame = vec![];
@@ -199,12 +400,34 @@ impl RadioParameters {
         frame
     }
 }
+impl fmt::Display for RadioParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.3} MHz, BW {} kHz, SF{}, CR 4/{}",
+            self.radio_freq as f64 / 1000.0,
+            self.radio_bw as f64 / 1000.0,
+            self.radio_sf,
+            self.radio_cr
+        )
+    }
+}
 
 pub async fn send_command(
     state: &Arc<RwLock<CompanionState>>,
     cmd: Commands,
 ) -> Result<(), AppError> {
     let tx = state.write().await.to_radio_tx.clone();
+    let unsupported = {
+        let state = state.read().await;
+        state
+            .device_info
+            .as_ref()
+            .is_some_and(|info| !Capabilities::new(info, state.app_target_ver).supports(&cmd))
+    };
+    if unsupported {
+        return Err(AppError::UnsupportedCommand(cmd));
+    }
     match cmd {
         Commands::CmdShareContact(ref contact) => {
             let mut data = vec![consts::CMD_SHARE_CONTACT];
@@ -279,8 +502,7 @@ pub async fn send_command(
             Ok(())
         }
         Commands::CmdSetRadioParams(ref radioparams) => {
-            //    if (freq >= 300000 && freq <= 2500000 && sf >= 5 && sf <= 12 && cr >= 5 && cr <= 8 && bw >= 7000 &&
-            //         bw <= 500000) {
+            radioparams.validate()?;
             let data: Vec<u8> = radioparams.to_frame();
             let frame = SerialFrame::from_data(data);
             info!("Setting radio params SerialFrame: {:#?}", frame);
@@ -345,7 +567,8 @@ pub async fn send_command(
         }
         Commands::CmdSetDeviceTime => {
             let mut data: Vec<u8> = vec![CMD_SET_DEVICE_TIME];
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            // The firmware expects 4 byte epoch seconds
+            let timestamp: u32 = host_time();
             let timestamp_bytes = timestamp.to_le_bytes();
             data.extend_from_slice(&timestamp_bytes);
             let frame: SerialFrame = SerialFrame::from_data(data);
@@ -383,7 +606,7 @@ pub async fn send_command(
 
         }
         Commands::CmdReboot => {
-            let data: Vec<u8> = vec![0x13, 0x72, 0x65, 0x62, 0x6f, 0x6f, 0x74];
+            let data: Vec<u8> = Reboot::default().to_frame();
             let frame: SerialFrame = SerialFrame::from_data(data);
             tx.send(frame)
                 .await
@@ -392,17 +615,8 @@ pub async fn send_command(
         }
         Commands::CmdAppStart(app) => {
             // Send command
-            let data: Vec<u8> = vec![
-                consts::CMD_APP_START,
-                0x03,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x01,
-            ];
+            let data: Vec<u8> = app.to_frame();
+            state.write().await.app_start = Some(app);
 
             let frame: SerialFrame = SerialFrame::from_data(data);
             tx.send(frame)
@@ -412,6 +626,7 @@ pub async fn send_command(
         }
         Commands::CmdDeviceQuery(app) => {
             // Send command
+            state.write().await.app_target_ver = app.app_target_ver;
             let data = vec![consts::CMD_DEVICE_QEURY, app.app_target_ver];
             let frame: SerialFrame = SerialFrame::from_data(data);
             tx.send(frame)
@@ -438,22 +653,33 @@ pub async fn send_command(
             Ok(())
         }
         Commands::CmdSendTxtMsg(msg) => {
-            let pending_msgs = state.write().await.pending_msgs.clone();
-            if pending_msgs.len() > 0 {
+            // A new message must not go out between the parts of a split one
+            if state.read().await.outbound_parts.iter().any(|c| matches!(c, Commands::CmdSendTxtMsg(_))) {
                 return Err(AppError::Congestion(
-                    "Messages still awaiting expected ack code".to_string(),
+                    "Parts of an earlier message are still queued".to_string(),
                 ));
             }
-            state.write().await.pending_msgs.push(msg.clone());
-            let data = msg.to_frame();
+            send_txt_msg(state, msg).await
+        }
+        Commands::CmdSendChannelTxtMsg(msg) => {
+            if state.read().await.outbound_parts.iter().any(|c| matches!(c, Commands::CmdSendChannelTxtMsg(_))) {
+                return Err(AppError::Congestion(
+                    "Parts of an earlier channel message are still queued".to_string(),
+                ));
+            }
+            send_channel_txt_msg(state, msg).await
+        }
+        Commands::CmdSendLogin(login) => {
+            let data = login.to_frame();
             let frame: SerialFrame = SerialFrame::from_data(data);
             tx.send(frame)
                 .await
                 .unwrap_or_else(|e| error!("Failed to send serial frame: {}", e));
             Ok(())
         }
-        Commands::CmdSendChannelTxtMsg(ref msg) => {
-            let data = msg.to_frame();
+        Commands::CmdImportContact(ref advert) => {
+            let mut data = vec![consts::CMD_IMPORT_CONTACT];
+            data.extend_from_slice(advert);
             let frame: SerialFrame = SerialFrame::from_data(data);
             tx.send(frame)
                 .await
@@ -461,14 +687,212 @@ pub async fn send_command(
             state.write().await.command_queue.push_back(cmd);
             Ok(())
         }
-        Commands::CmdSendLogin(login) => {
-            let data = login.to_frame();
+        Commands::CmdSendTracePath(ref trace) => {
+            let frame: SerialFrame = SerialFrame::from_data(trace.to_frame());
+            tx.send(frame)
+                .await
+                .unwrap_or_else(|e| error!("Failed to send serial frame: {}", e));
+            Ok(())
+        }
+        Commands::CmdGetAdvertPath(ref public_key) => {
+            let mut data = vec![consts::CMD_GET_ADVERT_PATH, 0];
+            data.extend_from_slice(&public_key.bytes);
             let frame: SerialFrame = SerialFrame::from_data(data);
             tx.send(frame)
                 .await
                 .unwrap_or_else(|e| error!("Failed to send serial frame: {}", e));
+            state.write().await.command_queue.push_back(cmd);
+            Ok(())
+        }
+        other => Err(AppError::UnsupportedCommand(other)),
+    }
+}
+
+/// Sends a direct message, splitting it when it is too long. Queued parts are dispatched through
+/// here directly, `send_command` turns new messages away while parts are still queued.
+pub(crate) async fn send_txt_msg(state: &Arc<RwLock<CompanionState>>, msg: SendTxtMsg) -> Result<(), AppError> {
+    let pending_msgs = state.write().await.pending_msgs.clone();
+    if pending_msgs.len() > 0 {
+        return Err(AppError::Congestion(
+            "Messages still awaiting expected ack code".to_string(),
+        ));
+    }
+    let mut parts = {
+        let lock = state.read().await;
+        if lock.multipart.split && msg.attempt == 0 && msg.text.len() > MAX_TEXT_LEN {
+            split_text(&msg.text, MAX_TEXT_LEN, lock.multipart.marker)?
+                .into_iter()
+                .map(|text| SendTxtMsg { text, ..msg.clone() })
+                .collect()
+        } else {
+            vec![msg]
+        }
+    };
+    let msg = parts.remove(0);
+    let airtime = outbound_airtime(&*state.read().await, txt_msg_packet_len(msg.text.len()));
+    throttle(state, airtime).await?;
+    let (first_attempt, conversation, text, sender_timestamp) =
+        (msg.attempt == 0, ConversationKey::contact(&msg.pubkey_prefix), msg.text.clone(), msg.sender_timestamp);
+    transmit_txt_msg(state, MessageEnvelope::new(SendingMessageTypes::TxtMsg(msg))).await?;
+    // The rest only follows once the first part is out
+    let mut lock = state.write().await;
+    lock.outbound_parts.extend(parts.into_iter().map(Commands::CmdSendTxtMsg));
+    if first_attempt
+        && let Some(history) = lock.history.as_mut()
+        && let Err(e) = history.record(conversation, Direction::Outbound, &text, sender_timestamp, None, None, DeliveryStatus::Pending)
+    {
+        error!("Failed to record outbound message: {e}");
+    }
+    Ok(())
+}
+
+/// Sends a channel message, splitting it when it is too long. Queued parts are dispatched
+/// through here directly, `send_command` turns new messages away while parts are still queued.
+pub(crate) async fn send_channel_txt_msg(state: &Arc<RwLock<CompanionState>>, msg: SendChannelTxtMsg) -> Result<(), AppError> {
+    let (mut parts, name_len) = {
+        let lock = state.read().await;
+        let name_len = lock.self_info.as_ref().map(|i| i.name.len()).unwrap_or(0);
+        let max_len = MAX_TEXT_LEN.saturating_sub(name_len + 2);
+        let parts = if lock.multipart.split && msg.text.len() > max_len {
+            split_text(&msg.text, max_len, lock.multipart.marker)?
+                .into_iter()
+                .map(|text| SendChannelTxtMsg { text, ..msg.clone() })
+                .collect()
+        } else {
+            vec![msg]
+        };
+        (parts, name_len)
+    };
+    let msg = parts.remove(0);
+    let airtime = outbound_airtime(&*state.read().await, grp_txt_packet_len(name_len, msg.text.len()));
+    throttle(state, airtime).await?;
+    transmit_channel_msg(state, msg.clone()).await?;
+    // The rest only follows once the first part is out
+    let mut lock = state.write().await;
+    lock.outbound_parts.extend(parts.into_iter().map(Commands::CmdSendChannelTxtMsg));
+    let track = lock.channel_policy.track;
+    if let Some(history) = lock.history.as_mut() {
+        let conversation = ConversationKey::Channel(msg.channel_idx);
+        // Untracked sends are never confirmed, so they stay recorded as sent
+        let status = if track { DeliveryStatus::Pending } else { DeliveryStatus::Sent };
+        if let Err(e) = history.record(conversation, Direction::Outbound, &msg.text, msg.sender_timestamp, None, None, status) {
+            error!("Failed to record outbound message: {e}");
+        }
+    }
+    if track {
+        let sender_name = lock.self_info.as_ref().map(|i| i.name.clone()).unwrap_or_default();
+        lock.pending_channel_msgs.push(ChannelEnvelope::new(msg, &sender_name));
+    }
+    Ok(())
+}
+
+/// Sends a direct message, keeping its envelope so retry state survives until the firmware
+/// assigns an ack code.
+pub(crate) async fn transmit_txt_msg(
+    state: &Arc<RwLock<CompanionState>>,
+    envelope: MessageEnvelope,
+) -> Result<(), AppError> {
+    let SendingMessageTypes::TxtMsg(ref msg) = envelope.msg else {
+        return Err(AppError::Misc("Only direct messages are tracked through ack codes".to_string()));
+    };
+    let data = msg.to_frame();
+    let tx = {
+        let mut lock = state.write().await;
+        if !lock.pending_msgs.is_empty() {
+            return Err(AppError::Congestion(
+                "Messages still awaiting expected ack code".to_string(),
+            ));
+        }
+        reserve_airtime(&mut lock, txt_msg_packet_len(msg.text.len()))?;
+        lock.pending_msgs.push(envelope.clone());
+        lock.to_radio_tx.clone()
+    };
+    let frame: SerialFrame = SerialFrame::from_data(data);
+    tx.send(frame)
+        .await
+        .unwrap_or_else(|e| error!("Failed to send serial frame: {}", e));
+    Ok(())
+}
+
+pub(crate) async fn transmit_channel_msg(
+    state: &Arc<RwLock<CompanionState>>,
+    msg: SendChannelTxtMsg,
+) -> Result<(), AppError> {
+    let data = msg.to_frame();
+    let tx = {
+        let mut lock = state.write().await;
+        let name_len = lock.self_info.as_ref().map(|i| i.name.len()).unwrap_or(0);
+        reserve_airtime(&mut lock, grp_txt_packet_len(name_len, msg.text.len()))?;
+        lock.to_radio_tx.clone()
+    };
+    let frame: SerialFrame = SerialFrame::from_data(data);
+    tx.send(frame)
+        .await
+        .unwrap_or_else(|e| error!("Failed to send serial frame: {}", e));
+    state.write().await.command_queue.push_back(Commands::CmdSendChannelTxtMsg(msg));
+    Ok(())
+}
+
+/// Airtime of a packet of `packet_len` bytes with the radio's current settings, if known.
+pub(crate) fn outbound_airtime(state: &CompanionState, packet_len: usize) -> Option<Duration> {
+    let params = state.self_info.as_ref()?.radio_parameters();
+    Some(airtime(&params, packet_len))
+}
+
+/// Records the airtime of a packet about to go out, refusing it if the budget has no room now.
+fn reserve_airtime(state: &mut CompanionState, packet_len: usize) -> Result<(), AppError> {
+    let Some(airtime) = outbound_airtime(state, packet_len) else {
+        return Ok(());
+    };
+    match budget_wait(state, airtime) {
+        Some(wait) if wait.is_zero() => {
+            state.duty_cycle_tracker.record(Instant::now(), airtime);
             Ok(())
         }
-        _ => todo!(),
+        Some(wait) => Err(AppError::DutyCycle(format!(
+            "{airtime:?} of airtime would exceed the budget for another {wait:?}"
+        ))),
+        None => Err(AppError::DutyCycle(format!(
+            "{airtime:?} of airtime exceeds the whole budget of {:?}",
+            state.duty_cycle.allowance()
+        ))),
+    }
+}
+
+/// How long a send of `airtime` has to wait for the duty cycle budget, None if it can never fit.
+pub(crate) fn budget_wait(state: &mut CompanionState, airtime: Duration) -> Option<Duration> {
+    if !state.duty_cycle.limit {
+        return Some(Duration::ZERO);
+    }
+    let config = state.duty_cycle.clone();
+    state.duty_cycle_tracker.wait_for(Instant::now(), airtime, &config)
+}
+
+async fn throttle(state: &Arc<RwLock<CompanionState>>, airtime: Option<Duration>) -> Result<(), AppError> {
+    let Some(airtime) = airtime else {
+        return Ok(());
+    };
+    loop {
+        let (wait, config) = {
+            let mut lock = state.write().await;
+            (budget_wait(&mut lock, airtime), lock.duty_cycle.clone())
+        };
+        let wait = match wait {
+            Some(wait) if wait.is_zero() => return Ok(()),
+            Some(wait) if config.mode == RateLimitMode::Delay && wait <= config.max_delay => wait,
+            Some(wait) => {
+                return Err(AppError::DutyCycle(format!(
+                    "{airtime:?} of airtime would exceed the budget for another {wait:?}"
+                )));
+            }
+            None => {
+                return Err(AppError::DutyCycle(format!(
+                    "{airtime:?} of airtime exceeds the whole budget of {:?}",
+                    config.allowance()
+                )));
+            }
+        };
+        debug!("Delaying send by {wait:?} to stay within the duty cycle budget");
+        tokio::time::sleep(wait).await;
     }
 }
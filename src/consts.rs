@@ -90,13 +90,21 @@ pub const ERR_CODE_FILE_IO_ERROR: u8 = 5;
 pub const ERR_CODE_ILLEGAL_ARG: u8 = 6;
 //endregion
 
+// firmware MAX_TEXT_LEN; channel texts also carry "<sender name>: "
+pub const MAX_TEXT_LEN: usize = 160;
+
+pub const DEFAULT_ACK_TIMEOUT_MS: u32 = 10000;
+
 pub const MPSC_BUFFER_DEPTH: usize = 100;
-pub const SERIAL_LOOP_SLEEP_MS: u64 = 10;
-pub const TIMEOUT_SERIAL_MS: u64 = 100;
+pub const EVENT_BUFFER_DEPTH: usize = 256;
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
 
 // hex: 0x3e
 pub const SERIAL_INBOUND: u8 = 62;
 // hex: 0x3c
 pub const SERIAL_OUTBOUND: u8 = 60;
 
+// How long apply_radio_parameters waits for the radio to report its new settings
+pub const RADIO_VERIFY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 pub const USA_RADIO_PRESET: RadioParameters = RadioParameters { code: 11, radio_freq: 910525, radio_bw: 62500, radio_sf: 7, radio_cr: 5 };
\ No newline at end of file
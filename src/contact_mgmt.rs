@@ -1,6 +1,8 @@
+use crate::geo::Position;
 use crate::{string_to_bytes, AppError};
 use std::fmt;
 use std::io::{Cursor, Read};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Clone, Copy, PartialEq)]
 pub struct PublicKey {
@@ -35,6 +37,18 @@ impl PublicKey {
     }
 }
 
+/// Serialized as a hex string.
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::serde_hex::serialize(&self.bytes, serializer)
+    }
+}
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self { bytes: crate::serde_hex::deserialize(deserializer)? })
+    }
+}
+
 impl fmt::Debug for PublicKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for byte in &self.bytes {
@@ -52,12 +66,13 @@ impl fmt::Display for PublicKey {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Contact {
     pub public_key: PublicKey,
     pub adv_type: u8,
     pub flags: u8,
     pub out_path_len: i8,
+    #[serde(with = "crate::serde_hex")]
     pub out_path: [u8; 64],
     pub adv_name: String,
     pub last_advert: u32,
@@ -69,6 +84,9 @@ pub struct Contact {
 
 
 impl Contact {
+    pub fn position(&self) -> Option<Position> {
+        Position::from_micro(self.adv_lat, self.adv_lon)
+    }
     pub(crate) fn to_frame(&self) -> Vec<u8> {
 
         let mut data = vec![];
@@ -130,3 +148,11 @@ impl Contact {
         }
     }
 }
+
+/// The advert packet inside a `meshcore://` contact URL, ready for `Commands::CmdImportContact`.
+pub fn advert_from_url(url: &str) -> Result<Vec<u8>, AppError> {
+    let encoded = url.trim().trim_start_matches("meshcore://");
+    crate::serde_hex::decode(encoded)
+        .filter(|advert| !advert.is_empty())
+        .ok_or_else(|| AppError::Misc(format!("not a meshcore:// contact URL: {url}")))
+}
@@ -0,0 +1,97 @@
+use crate::responses::PubkeyPrefix;
+use std::time::Duration;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteType {
+    Flood,
+    Direct,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeliveryPolicy {
+    /// Total number of transmissions (including the first) before a message is given up on
+    pub max_attempts: u8,
+    /// Reset the contact's path and resend as flood once direct routing keeps failing
+    pub flood_fallback: bool,
+    /// How many unacknowledged direct sends trigger the flood fallback
+    pub direct_failures_before_flood: u8,
+    /// Scales the ack timeout suggested by the firmware
+    pub timeout_multiplier: f32,
+    /// Each further attempt waits this much longer than the previous one
+    pub backoff_multiplier: f32,
+}
+
+impl Default for DeliveryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            flood_fallback: true,
+            direct_failures_before_flood: 2,
+            timeout_multiplier: 1.0,
+            backoff_multiplier: 1.0,
+        }
+    }
+}
+
+impl DeliveryPolicy {
+    /// Milliseconds to wait for an ack of the given attempt (0 based).
+    pub fn ack_timeout_ms(&self, suggested_timeout_ms: u32, attempt: u8) -> u128 {
+        let timeout = suggested_timeout_ms as f64
+            * self.timeout_multiplier as f64
+            * (self.backoff_multiplier as f64).powi(attempt as i32);
+        timeout.max(0.0) as u128
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DeliveryOutcome {
+    Delivered,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeliveryReport {
+    pub pubkey_prefix: PubkeyPrefix,
+    pub sender_timestamp: u32,
+    pub text: String,
+    pub outcome: DeliveryOutcome,
+    pub attempts: u8,
+    /// Whether the path was reset and the message resent as flood
+    pub flood_fallback: bool,
+    pub round_trip_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelPolicy {
+    /// Track channel sends and listen for repeaters rebroadcasting them in the RX log
+    pub track: bool,
+    /// How long to listen for repeats after each transmission
+    pub repeat_window: Duration,
+    /// Distinct repeaters that must be heard for the send to count as confirmed
+    pub min_repeaters: usize,
+    /// How many times an unconfirmed message is transmitted again
+    pub max_resends: u8,
+}
+
+impl Default for ChannelPolicy {
+    fn default() -> Self {
+        Self {
+            track: true,
+            repeat_window: Duration::from_secs(15),
+            min_repeaters: 1,
+            max_resends: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChannelDeliveryReport {
+    pub channel_idx: u8,
+    pub sender_timestamp: u32,
+    pub text: String,
+    pub outcome: DeliveryOutcome,
+    pub attempts: u8,
+    /// Distinct repeaters (by 1 byte path hash) heard rebroadcasting the message
+    pub repeaters: usize,
+}
@@ -0,0 +1,22 @@
+use crate::delivery::{ChannelDeliveryReport, DeliveryReport};
+use crate::health::HealthReading;
+use crate::packet::RxLogEvent;
+use crate::responses::ReceivedMessage;
+use serde::Serialize;
+
+/// Notifications published to every `Companion::subscribe` receiver.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum CompanionEvent {
+    /// A battery and storage reading arrived
+    Health(HealthReading),
+    BatteryLow(HealthReading),
+    BatteryRecovered(HealthReading),
+    StorageHigh(HealthReading),
+    StorageRecovered(HealthReading),
+    /// The radio heard a packet, decoded from `PUSH_CODE_LOG_RX_DATA`
+    RxLog(RxLogEvent),
+    /// A message was added to the inbox, it can still be taken with `pop_received`
+    Message(ReceivedMessage),
+    Delivery(DeliveryReport),
+    ChannelDelivery(ChannelDeliveryReport),
+}
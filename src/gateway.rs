@@ -0,0 +1,316 @@
+//! HTTP REST and WebSocket gateway, every route needs `Authorization: Bearer <token>`.
+//!
+//! | Route | |
+//! |---|---|
+//! | `GET /api/device` | self info, capabilities, connection, health, clock and duty cycle |
+//! | `GET /api/contacts`, `GET`/`DELETE /api/contacts/{name or key prefix}` | contact list, lookup and removal |
+//! | `GET /api/channels` | configured channels with history summaries when history is enabled |
+//! | `GET`/`PUT /api/radio` | radio parameters, set with `{"preset": ".."}` or `{"freq_mhz", "bw_khz", "sf", "cr"}` |
+//! | `POST /api/messages/pop` | drains the inbox |
+//! | `POST /api/messages/dm`, `POST /api/messages/channel` | `{"to", "text"}` or `{"channel", "text"}` |
+//! | `POST /api/advert` | `{"flood": true}` |
+//! | `GET /api/events` | WebSocket streaming every `CompanionEvent` as JSON text |
+use crate::commands::{AdvertisementMode, RadioParameters};
+use crate::history::ConversationKey;
+use crate::radio::RadioPreset;
+use crate::{stopped, AppError, Commands, Companion};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+const COMMAND_WAIT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct GatewayConfig {
+    pub bind: SocketAddr,
+    /// Also accepted as an `access_token` query parameter, browsers cannot set WebSocket headers
+    pub token: String,
+    /// Channel indexes listed by `GET /api/channels`
+    pub channels: Vec<u8>,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            token: String::new(),
+            channels: vec![0],
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Gateway {
+    companion: Arc<Companion>,
+    config: Arc<GatewayConfig>,
+    shutdown: watch::Receiver<bool>,
+}
+
+/// An error response with a `{"error": ".."}` body.
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<AppError> for ApiError {
+    fn from(e: AppError) -> Self {
+        let status = match &e {
+            AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::DutyCycle(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Congestion(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::RadioParams(_) | AppError::IllegalArgument(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        Self(status, e.to_string())
+    }
+}
+
+type ApiResult = Result<Json<Value>, ApiError>;
+
+fn not_found(e: AppError) -> ApiError {
+    ApiError(StatusCode::NOT_FOUND, e.to_string())
+}
+
+#[derive(Deserialize)]
+struct DmRequest {
+    to: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct ChannelRequest {
+    channel: u8,
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct AdvertRequest {
+    #[serde(default)]
+    flood: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RadioRequest {
+    Preset { preset: String },
+    Parameters { freq_mhz: f64, bw_khz: f64, sf: u8, cr: u8 },
+}
+
+fn radio_json(params: &RadioParameters) -> Value {
+    json!({
+        "freq_mhz": params.radio_freq as f64 / 1000.0,
+        "bw_khz": params.radio_bw as f64 / 1000.0,
+        "sf": params.radio_sf,
+        "cr": params.radio_cr,
+        "preset": RadioPreset::matching(params).map(|p| p.name()),
+    })
+}
+
+async fn device(State(gw): State<Gateway>) -> ApiResult {
+    let companion = &gw.companion;
+    Ok(Json(json!({
+        "self_info": companion.get_self_info().await,
+        "public_key": companion.get_public_key().await,
+        "connection": companion.connection_state(),
+        "capabilities": companion.capabilities().await,
+        "health": companion.health().await,
+        "clock": companion.device_clock().await,
+        "duty_cycle": companion.duty_cycle_status().await,
+    })))
+}
+
+async fn contacts(State(gw): State<Gateway>) -> ApiResult {
+    Ok(Json(json!(gw.companion.get_contacts().await)))
+}
+
+async fn contact(State(gw): State<Gateway>, Path(query): Path<String>) -> ApiResult {
+    let contact = gw.companion.find_contact(&query).await.map_err(not_found)?;
+    Ok(Json(json!(contact)))
+}
+
+async fn remove_contact(State(gw): State<Gateway>, Path(query): Path<String>) -> ApiResult {
+    let contact = gw.companion.find_contact(&query).await.map_err(not_found)?;
+    gw.companion.command_and_wait(Commands::CmdRemoveContact(contact.public_key), COMMAND_WAIT).await?;
+    Ok(Json(json!({ "removed": contact.adv_name })))
+}
+
+async fn channels(State(gw): State<Gateway>) -> ApiResult {
+    // Without history there is nothing to summarize, only the indexes are listed
+    let summaries = gw.companion.history_conversations().await.unwrap_or_default();
+    let channels: Vec<Value> = gw
+        .config
+        .channels
+        .iter()
+        .map(|&index| {
+            let summary = summaries.iter().find(|s| s.conversation == ConversationKey::Channel(index));
+            json!({
+                "index": index,
+                "message_count": summary.map(|s| s.message_count),
+                "unread": summary.map(|s| s.unread),
+                "last_message": summary.map(|s| &s.last_message),
+            })
+        })
+        .collect();
+    Ok(Json(Value::Array(channels)))
+}
+
+async fn radio(State(gw): State<Gateway>) -> ApiResult {
+    let self_info = gw
+        .companion
+        .get_self_info()
+        .await
+        .ok_or_else(|| ApiError(StatusCode::SERVICE_UNAVAILABLE, "radio did not send self info".to_string()))?;
+    let mut body = radio_json(&self_info.radio_parameters());
+    body["tx_power_dbm"] = json!(self_info.tx_power_dbm());
+    body["tuning"] = json!(gw.companion.get_tuning_parameters().await);
+    Ok(Json(body))
+}
+
+async fn set_radio(State(gw): State<Gateway>, Json(request): Json<RadioRequest>) -> ApiResult {
+    let params = match request {
+        RadioRequest::Preset { preset } => RadioPreset::from_name(&preset)
+            .ok_or_else(|| ApiError(StatusCode::BAD_REQUEST, format!("unknown preset {preset}")))?
+            .parameters(),
+        RadioRequest::Parameters { freq_mhz, bw_khz, sf, cr } => {
+            RadioParameters::from_mhz(freq_mhz, bw_khz, sf, cr).map_err(AppError::from)?
+        }
+    };
+    gw.companion.apply_radio_parameters(params.clone()).await?;
+    Ok(Json(radio_json(&params)))
+}
+
+async fn pop_messages(State(gw): State<Gateway>) -> ApiResult {
+    let mut drained = vec![];
+    while let Some(message) = gw.companion.pop_received().await {
+        let sender = gw.companion.resolve_sender(&message).await;
+        drained.push(message.summary_json(sender.as_ref().map(|c| c.adv_name.as_str())));
+    }
+    Ok(Json(Value::Array(drained)))
+}
+
+async fn send_dm(State(gw): State<Gateway>, Json(DmRequest { to, text }): Json<DmRequest>) -> ApiResult {
+    let contact = gw.companion.find_contact(&to).await.map_err(not_found)?;
+    let sender_timestamp = gw.companion.send_text(&contact, text).await?;
+    Ok(Json(json!({ "to": contact.adv_name, "sender_timestamp": sender_timestamp })))
+}
+
+async fn send_channel(State(gw): State<Gateway>, Json(request): Json<ChannelRequest>) -> ApiResult {
+    let sender_timestamp = gw.companion.send_channel_text(request.channel, request.text).await?;
+    Ok(Json(json!({ "channel": request.channel, "sender_timestamp": sender_timestamp })))
+}
+
+async fn advert(State(gw): State<Gateway>, request: Option<Json<AdvertRequest>>) -> ApiResult {
+    let flood = request.is_some_and(|Json(request)| request.flood);
+    let mode = if flood { AdvertisementMode::Flood } else { AdvertisementMode::ZeroHop };
+    gw.companion.command_and_wait(Commands::CmdSendSelfAdvert(mode), COMMAND_WAIT).await?;
+    Ok(Json(json!({ "flood": flood })))
+}
+
+async fn events(ws: WebSocketUpgrade, State(gw): State<Gateway>) -> Response {
+    ws.on_upgrade(move |socket| stream_events(socket, gw))
+}
+
+async fn stream_events(mut socket: WebSocket, gw: Gateway) {
+    let mut events = gw.companion.subscribe();
+    let mut shutdown = gw.shutdown.clone();
+    loop {
+        let outgoing = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => json!(event),
+                Err(RecvError::Lagged(missed)) => json!({ "EventsLagged": { "missed": missed } }),
+                Err(RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum, anything else from the client is ignored
+                Some(Ok(_)) => continue,
+            },
+            _ = stopped(&mut shutdown) => break,
+        };
+        if socket.send(Message::Text(outgoing.to_string().into())).await.is_err() {
+            break;
+        }
+    }
+    debug!("WebSocket client disconnected");
+}
+
+fn token_matches(given: &str, expected: &str) -> bool {
+    // An unset token must not turn an empty bearer into a valid one
+    if expected.is_empty() {
+        return false;
+    }
+    // Compares every byte so the response time does not leak how much of the token matched
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn authorize(State(gw): State<Gateway>, request: Request, next: Next) -> Response {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query = request
+        .uri()
+        .query()
+        .and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("access_token=")));
+    let authorized = bearer.or(query).is_some_and(|token| token_matches(token, &gw.config.token));
+    if !authorized {
+        let error = ApiError(StatusCode::UNAUTHORIZED, "missing or wrong bearer token".to_string());
+        return ([(header::WWW_AUTHENTICATE, "Bearer")], error).into_response();
+    }
+    next.run(request).await
+}
+
+/// The gateway routes, for serving alongside other routes. `serve` is the standalone server.
+/// With an empty `token` every request is refused.
+pub fn router(companion: Arc<Companion>, config: GatewayConfig, shutdown: watch::Receiver<bool>) -> Router {
+    let gw = Gateway { companion, config: Arc::new(config), shutdown };
+    Router::new()
+        .route("/api/device", get(device))
+        .route("/api/contacts", get(contacts))
+        .route("/api/contacts/{query}", get(contact).delete(remove_contact))
+        .route("/api/channels", get(channels))
+        .route("/api/radio", get(radio).put(set_radio))
+        .route("/api/messages/pop", post(pop_messages))
+        .route("/api/messages/dm", post(send_dm))
+        .route("/api/messages/channel", post(send_channel))
+        .route("/api/advert", post(advert))
+        .route("/api/events", get(events))
+        .layer(middleware::from_fn_with_state(gw.clone(), authorize))
+        .with_state(gw)
+}
+
+/// Serves the gateway on `config.bind` until `shutdown` is set.
+pub async fn serve(
+    companion: Arc<Companion>,
+    config: GatewayConfig,
+    shutdown: watch::Receiver<bool>,
+) -> Result<(), AppError> {
+    if config.token.is_empty() {
+        return Err(AppError::Http("a bearer token is required".to_string()));
+    }
+    let listener = TcpListener::bind(config.bind).await.map_err(|e| AppError::Http(e.to_string()))?;
+    info!("HTTP gateway listening on {}", config.bind);
+    let mut stop = shutdown.clone();
+    axum::serve(listener, router(companion, config, shutdown))
+        .with_graceful_shutdown(async move { stopped(&mut stop).await })
+        .await
+        .map_err(|e| AppError::Http(e.to_string()))
+}
@@ -0,0 +1,205 @@
+use crate::contact_mgmt::{Contact, PublicKey};
+use crate::packet::AdvertType;
+use crate::responses::SelfInfo;
+use crate::topology::xml_escape;
+use serde::Serialize;
+use serde_json::json;
+use std::fmt::Write;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Position {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Position {
+    /// From the firmware's 1e-6 degree integers, None for the unset (0, 0) position.
+    pub fn from_micro(lat: i32, lon: i32) -> Option<Self> {
+        if lat == 0 && lon == 0 {
+            return None;
+        }
+        Some(Self {
+            lat: lat as f64 / 1e6,
+            lon: lon as f64 / 1e6,
+        })
+    }
+
+    /// Great circle distance in kilometres.
+    pub fn distance_km(&self, other: &Position) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.lon - self.lon).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+
+    /// Initial bearing towards `other` in degrees clockwise from north.
+    pub fn bearing_deg(&self, other: &Position) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let dlon = (other.lon - self.lon).to_radians();
+        let y = dlon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+        y.atan2(x).to_degrees().rem_euclid(360.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodePosition {
+    pub name: String,
+    pub public_key: PublicKey,
+    pub node_type: AdvertType,
+    /// Advert timestamp, None for our own node
+    pub last_advert: Option<u32>,
+    pub position: Position,
+    /// From our own node, when it has a position
+    pub distance_km: Option<f64>,
+    pub bearing_deg: Option<f64>,
+    pub is_self: bool,
+}
+
+/// Our own node followed by every contact that has advertised a position.
+pub fn node_positions(self_info: Option<&SelfInfo>, contacts: &[Contact]) -> Vec<NodePosition> {
+    let origin = self_info.and_then(SelfInfo::position);
+    let mut nodes = vec![];
+    if let Some(info) = self_info
+        && let Some(position) = origin
+    {
+        nodes.push(NodePosition {
+            name: info.name.clone(),
+            public_key: info.public_key,
+            node_type: info.r#type.into(),
+            last_advert: None,
+            position,
+            distance_km: None,
+            bearing_deg: None,
+            is_self: true,
+        });
+    }
+    for contact in contacts {
+        let Some(position) = contact.position() else {
+            continue;
+        };
+        nodes.push(NodePosition {
+            name: contact.adv_name.clone(),
+            public_key: contact.public_key,
+            node_type: contact.adv_type.into(),
+            last_advert: Some(contact.last_advert),
+            position,
+            distance_km: origin.map(|o| o.distance_km(&position)),
+            bearing_deg: origin.map(|o| o.bearing_deg(&position)),
+            is_self: false,
+        });
+    }
+    nodes
+}
+
+fn type_name(node_type: AdvertType) -> &'static str {
+    match node_type {
+        AdvertType::Chat => "chat",
+        AdvertType::Repeater => "repeater",
+        AdvertType::Room => "room",
+        AdvertType::Sensor => "sensor",
+        AdvertType::None | AdvertType::Unknown(_) => "unknown",
+    }
+}
+
+/// Epoch seconds as an ISO 8601 UTC timestamp.
+fn iso8601(secs: u32) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil from days, Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn description(node: &NodePosition) -> String {
+    let mut text = type_name(node.node_type).to_string();
+    if let (Some(distance), Some(bearing)) = (node.distance_km, node.bearing_deg) {
+        let _ = write!(text, ", {distance:.2} km at {bearing:.0}°");
+    }
+    if let Some(last_advert) = node.last_advert {
+        let _ = write!(text, ", last advert {}", iso8601(last_advert));
+    }
+    text
+}
+
+pub fn to_geojson(nodes: &[NodePosition]) -> String {
+    let features: Vec<_> = nodes
+        .iter()
+        .map(|node| {
+            json!({
+                "type": "Feature",
+                // GeoJSON puts longitude first
+                "geometry": { "type": "Point", "coordinates": [node.position.lon, node.position.lat] },
+                "properties": {
+                    "name": node.name,
+                    "public_key": node.public_key.to_string(),
+                    "type": type_name(node.node_type),
+                    "last_advert": node.last_advert,
+                    "distance_km": node.distance_km,
+                    "bearing_deg": node.bearing_deg,
+                    "self": node.is_self,
+                },
+            })
+        })
+        .collect();
+    json!({ "type": "FeatureCollection", "features": features }).to_string()
+}
+
+pub fn to_gpx(nodes: &[NodePosition]) -> String {
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<gpx version=\"1.1\" creator=\"meshcore_companion_rs\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+    ));
+    for node in nodes {
+        let _ = write!(out, "  <wpt lat=\"{:.6}\" lon=\"{:.6}\">", node.position.lat, node.position.lon);
+        if let Some(last_advert) = node.last_advert {
+            let _ = write!(out, "<time>{}</time>", iso8601(last_advert));
+        }
+        let _ = writeln!(
+            out,
+            "<name>{}</name><desc>{}</desc><type>{}</type></wpt>",
+            xml_escape(&node.name),
+            xml_escape(&description(node)),
+            type_name(node.node_type)
+        );
+    }
+    out.push_str("</gpx>\n");
+    out
+}
+
+pub fn to_kml(nodes: &[NodePosition]) -> String {
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n",
+        "  <Document>\n",
+        "    <name>MeshCore nodes</name>\n",
+    ));
+    for node in nodes {
+        let _ = writeln!(
+            out,
+            "    <Placemark><name>{}</name><description>{}</description><Point><coordinates>{:.6},{:.6}</coordinates></Point></Placemark>",
+            xml_escape(&node.name),
+            xml_escape(&description(node)),
+            node.position.lon,
+            node.position.lat
+        );
+    }
+    out.push_str("  </Document>\n</kml>\n");
+    out
+}
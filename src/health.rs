@@ -0,0 +1,159 @@
+use crate::events::CompanionEvent;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime};
+use serde::Serialize;
+
+// Resting voltage of a single LiPo cell against remaining charge
+const LIPO_CURVE: [(u16, u8); 11] = [
+    (3270, 0),
+    (3610, 5),
+    (3690, 10),
+    (3710, 15),
+    (3730, 20),
+    (3750, 25),
+    (3770, 30),
+    (3790, 35),
+    (3800, 40),
+    (3850, 60),
+    (4200, 100),
+];
+
+// Readings have to move this far past a threshold before the alarm clears again
+const HYSTERESIS_PERCENT: u8 = 5;
+
+/// Estimated charge of a single cell LiPo battery from its voltage.
+pub fn lipo_percent(millivolts: u16) -> u8 {
+    let (first_mv, first_pct) = LIPO_CURVE[0];
+    if millivolts <= first_mv {
+        return first_pct;
+    }
+    for pair in LIPO_CURVE.windows(2) {
+        let ((low_mv, low_pct), (high_mv, high_pct)) = (pair[0], pair[1]);
+        if millivolts <= high_mv {
+            let span = (high_mv - low_mv) as u32;
+            let offset = (millivolts - low_mv) as u32;
+            return low_pct + ((high_pct - low_pct) as u32 * offset / span) as u8;
+        }
+    }
+    100
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct HealthReading {
+    pub millivolts: u16,
+    pub battery_percent: u8,
+    pub storage_used_kb: u32,
+    pub storage_total_kb: u32,
+    pub received_at: SystemTime,
+}
+
+impl HealthReading {
+    pub fn new(millivolts: u16, storage_used_kb: u32, storage_total_kb: u32) -> Self {
+        Self {
+            millivolts,
+            battery_percent: lipo_percent(millivolts),
+            storage_used_kb,
+            storage_total_kb,
+            received_at: SystemTime::now(),
+        }
+    }
+    pub fn storage_percent(&self) -> u8 {
+        if self.storage_total_kb == 0 {
+            return 0;
+        }
+        (self.storage_used_kb as u64 * 100 / self.storage_total_kb as u64).min(100) as u8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthConfig {
+    /// Request battery and storage on this schedule, None to only record what arrives
+    pub poll_interval: Option<Duration>,
+    /// Readings kept in the ring buffer
+    pub history_len: usize,
+    pub battery_low_percent: u8,
+    pub storage_high_percent: u8,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: None,
+            history_len: 288,
+            battery_low_percent: 20,
+            storage_high_percent: 90,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HealthMonitor {
+    config: HealthConfig,
+    readings: VecDeque<HealthReading>,
+    battery_low: bool,
+    storage_high: bool,
+    last_poll: Option<Instant>,
+}
+
+impl HealthMonitor {
+    pub fn set_config(&mut self, config: HealthConfig) {
+        self.config = config;
+        self.last_poll = None;
+        self.trim();
+    }
+    pub fn config(&self) -> &HealthConfig {
+        &self.config
+    }
+    pub fn latest(&self) -> Option<&HealthReading> {
+        self.readings.back()
+    }
+    pub fn history(&self) -> impl Iterator<Item = &HealthReading> {
+        self.readings.iter()
+    }
+
+    /// Stores a reading, returning the events it raises.
+    pub fn record(&mut self, reading: HealthReading) -> Vec<CompanionEvent> {
+        self.readings.push_back(reading);
+        self.trim();
+        let mut events = vec![CompanionEvent::Health(reading)];
+
+        let low = self.config.battery_low_percent;
+        // Boards without a battery report 0 mV
+        let has_battery = reading.millivolts > 0;
+        if has_battery && !self.battery_low && reading.battery_percent <= low {
+            self.battery_low = true;
+            events.push(CompanionEvent::BatteryLow(reading));
+        } else if has_battery && self.battery_low && reading.battery_percent > low.saturating_add(HYSTERESIS_PERCENT) {
+            self.battery_low = false;
+            events.push(CompanionEvent::BatteryRecovered(reading));
+        }
+
+        let high = self.config.storage_high_percent;
+        if !self.storage_high && reading.storage_percent() > high {
+            self.storage_high = true;
+            events.push(CompanionEvent::StorageHigh(reading));
+        } else if self.storage_high && reading.storage_percent() < high.saturating_sub(HYSTERESIS_PERCENT) {
+            self.storage_high = false;
+            events.push(CompanionEvent::StorageRecovered(reading));
+        }
+        events
+    }
+
+    /// Whether a scheduled poll is due, marking it as sent if so.
+    pub(crate) fn poll_due(&mut self) -> bool {
+        let Some(interval) = self.config.poll_interval else {
+            return false;
+        };
+        if self.last_poll.is_some_and(|at| at.elapsed() < interval) {
+            return false;
+        }
+        self.last_poll = Some(Instant::now());
+        true
+    }
+
+    fn trim(&mut self) {
+        while self.readings.len() > self.config.history_len {
+            self.readings.pop_front();
+        }
+    }
+}
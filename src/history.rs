@@ -0,0 +1,247 @@
+use crate::contact_mgmt::PublicKey;
+use crate::AppError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ConversationKey {
+    /// Hex-encoded 6 byte public key prefix of the remote contact
+    Contact(String),
+    Channel(u8),
+}
+
+impl ConversationKey {
+    pub fn contact(pubkey_prefix: &[u8]) -> Self {
+        ConversationKey::Contact(pubkey_prefix.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryStatus {
+    Received,
+    Pending,
+    Sent,
+    Delivered,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub conversation: ConversationKey,
+    pub direction: Direction,
+    pub text: String,
+    pub sender_timestamp: u32,
+    /// Local time (unix millis) the entry was recorded
+    pub recorded_at: u64,
+    pub snr: Option<f32>,
+    pub path_len: Option<u8>,
+    pub status: DeliveryStatus,
+    pub read: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversationSummary {
+    pub conversation: ConversationKey,
+    pub last_message: HistoryEntry,
+    pub message_count: usize,
+    pub unread: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Message(HistoryEntry),
+    Status { id: u64, status: DeliveryStatus },
+    MarkRead { conversation: ConversationKey, up_to: u64 },
+}
+
+#[derive(Debug)]
+pub struct HistoryStore {
+    path: PathBuf,
+    file: File,
+    entries: Vec<HistoryEntry>,
+    next_id: u64,
+}
+
+impl HistoryStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, AppError> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries: Vec<HistoryEntry> = vec![];
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path).map_err(|e| AppError::History(e.to_string()))?);
+            for (lineno, line) in reader.lines().enumerate() {
+                let line = line.map_err(|e| AppError::History(e.to_string()))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(record) => apply(&mut entries, record),
+                    Err(e) => warn!("Skipping unreadable history line {} in {}: {e}", lineno + 1, path.display()),
+                }
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| AppError::History(e.to_string()))?;
+        let next_id = entries.iter().map(|e| e.id + 1).max().unwrap_or(0);
+        Ok(Self { path, file, entries, next_id })
+    }
+
+    /// Opens (or creates) `<dir>/<public key>.jsonl`, giving each device its own history file.
+    pub fn open_for_device(dir: impl AsRef<Path>, device: &PublicKey) -> Result<Self, AppError> {
+        std::fs::create_dir_all(dir.as_ref()).map_err(|e| AppError::History(e.to_string()))?;
+        Self::open(dir.as_ref().join(format!("{device}.jsonl")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &mut self,
+        conversation: ConversationKey,
+        direction: Direction,
+        text: &str,
+        sender_timestamp: u32,
+        snr: Option<f32>,
+        path_len: Option<u8>,
+        status: DeliveryStatus,
+    ) -> Result<u64, AppError> {
+        let entry = HistoryEntry {
+            id: self.next_id,
+            conversation,
+            direction,
+            text: text.to_string(),
+            sender_timestamp,
+            recorded_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+            snr,
+            path_len,
+            status,
+            read: direction == Direction::Outbound,
+        };
+        self.next_id += 1;
+        self.append(Record::Message(entry.clone()))?;
+        let id = entry.id;
+        self.entries.push(entry);
+        Ok(id)
+    }
+
+    pub fn update_status(&mut self, id: u64, status: DeliveryStatus) -> Result<(), AppError> {
+        if !self.entries.iter().any(|e| e.id == id) {
+            return Err(AppError::History(format!("No history entry with id {id}")));
+        }
+        self.append(Record::Status { id, status })?;
+        apply(&mut self.entries, Record::Status { id, status });
+        Ok(())
+    }
+
+    /// Finds the most recent outbound entry matching a sent message, for delivery status updates.
+    pub fn find_outbound(&self, conversation: &ConversationKey, sender_timestamp: u32, text: &str) -> Option<u64> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| {
+                e.direction == Direction::Outbound
+                    && &e.conversation == conversation
+                    && e.sender_timestamp == sender_timestamp
+                    && e.text == text
+            })
+            .map(|e| e.id)
+    }
+
+    /// Lists conversations, most recently active first.
+    pub fn conversations(&self) -> Vec<ConversationSummary> {
+        let mut summaries: BTreeMap<ConversationKey, ConversationSummary> = BTreeMap::new();
+        for entry in &self.entries {
+            let summary = summaries
+                .entry(entry.conversation.clone())
+                .or_insert_with(|| ConversationSummary {
+                    conversation: entry.conversation.clone(),
+                    last_message: entry.clone(),
+                    message_count: 0,
+                    unread: 0,
+                });
+            summary.message_count += 1;
+            if !entry.read {
+                summary.unread += 1;
+            }
+            summary.last_message = entry.clone();
+        }
+        let mut summaries: Vec<ConversationSummary> = summaries.into_values().collect();
+        summaries.sort_by_key(|s| std::cmp::Reverse(s.last_message.id));
+        summaries
+    }
+
+    /// Returns up to `limit` entries of a thread older than `before` (an entry id), oldest first.
+    /// Pass `None` to get the newest page.
+    pub fn thread(&self, conversation: &ConversationKey, before: Option<u64>, limit: usize) -> Vec<HistoryEntry> {
+        let mut page: Vec<HistoryEntry> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|e| &e.conversation == conversation && before.is_none_or(|b| e.id < b))
+            .take(limit)
+            .cloned()
+            .collect();
+        page.reverse();
+        page
+    }
+
+    pub fn unread_count(&self, conversation: &ConversationKey) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| &e.conversation == conversation && !e.read)
+            .count()
+    }
+
+    /// Marks every message in the conversation as read, returning how many changed.
+    pub fn mark_read(&mut self, conversation: &ConversationKey) -> Result<usize, AppError> {
+        let unread = self.unread_count(conversation);
+        if unread == 0 {
+            return Ok(0);
+        }
+        let up_to = self.next_id;
+        self.append(Record::MarkRead { conversation: conversation.clone(), up_to })?;
+        apply(&mut self.entries, Record::MarkRead { conversation: conversation.clone(), up_to });
+        Ok(unread)
+    }
+
+    fn append(&mut self, record: Record) -> Result<(), AppError> {
+        let mut line = serde_json::to_string(&record).map_err(|e| AppError::History(e.to_string()))?;
+        line.push('\n');
+        self.file
+            .write_all(line.as_bytes())
+            .map_err(|e| AppError::History(e.to_string()))
+    }
+}
+
+fn apply(entries: &mut Vec<HistoryEntry>, record: Record) {
+    match record {
+        Record::Message(entry) => entries.push(entry),
+        Record::Status { id, status } => {
+            if let Some(e) = entries.iter_mut().find(|e| e.id == id) {
+                e.status = status;
+            }
+        }
+        Record::MarkRead { conversation, up_to } => {
+            entries
+                .iter_mut()
+                .filter(|e| e.conversation == conversation && e.id < up_to)
+                .for_each(|e| e.read = true);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::responses::{MessageSource, ReceivedMessage};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Evict the oldest queued message to make room for the new one
+    DropOldest,
+    /// Keep the queue as-is and discard the incoming message
+    DropNewest,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InboxConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+    /// How long a message is remembered for duplicate suppression. Zero disables dedup.
+    pub dedup_window: Duration,
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            overflow: OverflowPolicy::DropOldest,
+            dedup_window: Duration::from_secs(120),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboxOutcome {
+    Queued,
+    Duplicate,
+    Overflowed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct DedupKey {
+    source: MessageSource,
+    sender_timestamp: u32,
+    text_hash: u64,
+}
+
+impl DedupKey {
+    fn from_message(msg: &ReceivedMessage) -> Self {
+        let mut hasher = DefaultHasher::new();
+        msg.text().hash(&mut hasher);
+        Self {
+            source: msg.source(),
+            sender_timestamp: msg.sender_timestamp(),
+            text_hash: hasher.finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Inbox {
+    config: InboxConfig,
+    queue: VecDeque<ReceivedMessage>,
+    seen: VecDeque<(DedupKey, Instant)>,
+    duplicates: u64,
+    dropped: u64,
+}
+
+impl Inbox {
+    pub fn new(config: InboxConfig) -> Self {
+        Self {
+            config,
+            queue: VecDeque::new(),
+            seen: VecDeque::new(),
+            duplicates: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn config(&self) -> &InboxConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: InboxConfig) {
+        self.config = config;
+        while self.queue.len() > self.config.capacity {
+            self.queue.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    pub fn push(&mut self, msg: ReceivedMessage) -> InboxOutcome {
+        if self.is_duplicate(&msg) {
+            return InboxOutcome::Duplicate;
+        }
+        self.enqueue(msg)
+    }
+
+    /// Checks a message against the dedup window, remembering it if it is new.
+    pub fn is_duplicate(&mut self, msg: &ReceivedMessage) -> bool {
+        if self.config.dedup_window.is_zero() {
+            return false;
+        }
+        let now = Instant::now();
+        while let Some((_, seen_at)) = self.seen.front() {
+            if now.duration_since(*seen_at) > self.config.dedup_window {
+                self.seen.pop_front();
+            } else {
+                break;
+            }
+        }
+        let key = DedupKey::from_message(msg);
+        if self.seen.iter().any(|(k, _)| *k == key) {
+            self.duplicates += 1;
+            return true;
+        }
+        self.seen.push_back((key, now));
+        false
+    }
+
+    /// Queues a message without duplicate checks, applying the overflow policy.
+    pub fn enqueue(&mut self, msg: ReceivedMessage) -> InboxOutcome {
+        if self.queue.len() >= self.config.capacity {
+            self.dropped += 1;
+            match self.config.overflow {
+                OverflowPolicy::DropNewest => return InboxOutcome::Overflowed,
+                OverflowPolicy::DropOldest => {
+                    if self.queue.pop_front().is_none() {
+                        return InboxOutcome::Overflowed;
+                    }
+                }
+            }
+        }
+        self.queue.push_back(msg);
+        InboxOutcome::Queued
+    }
+
+    pub fn pop(&mut self) -> Option<ReceivedMessage> {
+        self.queue.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Number of messages suppressed as duplicates since the inbox was created
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Number of messages lost to the overflow policy since the inbox was created
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+impl Default for Inbox {
+    fn default() -> Self {
+        Self::new(InboxConfig::default())
+    }
+}
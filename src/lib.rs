@@ -1,27 +1,67 @@
 #[macro_use]
 extern crate tracing;
+pub mod airtime;
+pub mod builder;
+pub mod capabilities;
+pub mod capture;
+pub mod clock;
 pub mod commands;
 pub mod consts;
 pub mod push_events;
 pub mod responses;
+pub mod topology;
 
 pub mod contact_mgmt;
+pub mod delivery;
+pub mod events;
+pub mod geo;
+#[cfg(feature = "http")]
+pub mod gateway;
+pub mod health;
+pub mod history;
+pub mod inbox;
+pub mod multipart;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod packet;
+pub mod radio;
+pub mod recorder;
+pub mod rpc;
+mod serde_hex;
 mod serial_actor;
 mod tests;
 
+use crate::airtime::{DutyCycleConfig, DutyCycleStatus, DutyCycleTracker};
+use crate::capabilities::Capabilities;
+use crate::capture::{CaptureConfig, PacketCapture};
+use crate::events::CompanionEvent;
+use crate::health::{HealthConfig, HealthMonitor, HealthReading};
+use crate::clock::{ClockSyncConfig, DeviceClock};
+use crate::radio::{RadioParamsError, RadioPreset};
+use crate::recorder::{ReplayTransport, SessionRecorder};
+use crate::topology::{Topology, TraceResult};
+use crate::geo::NodePosition;
 use crate::commands::SendingMessageTypes::TxtMsg;
 use crate::commands::{
-    send_command, GetContacts, MessageEnvelope, Reboot, SendTxtMsg, SendingMessageTypes,
+    send_command, RadioParameters, ChannelEnvelope, GetContacts, MessageEnvelope, Reboot, SendChannelTxtMsg,
+    SendTxtMsg, SendingMessageTypes,
 };
+use crate::builder::run_handshake;
+pub use crate::builder::{CompanionBuilder, HandshakeConfig};
 pub use crate::commands::{AppStart, Commands};
 use crate::consts::*;
 use crate::contact_mgmt::{Contact, PublicKey};
-use crate::responses::check_internal;
+use crate::history::{ConversationKey, ConversationSummary, HistoryEntry, HistoryStore};
+use crate::inbox::{Inbox, InboxConfig};
+use crate::multipart::{MultipartConfig, Reassembler};
+use crate::delivery::{ChannelDeliveryReport, ChannelPolicy, DeliveryPolicy, DeliveryReport};
+use crate::responses::{check_internal, fail_in_flight};
 use crate::responses::{
     AckCode, BattAndStorage, ChannelMsg, ChannelMsgV3, Confirmation, ContactMsg, ContactMsgV3,
-    DeviceInfo, LoginSuccess, Responses, SelfInfo, TuningParameters,
+    DeviceInfo, LoginSuccess, ReceivedMessage, Responses, SelfInfo, TuningParameters,
 };
-use crate::serial_actor::{serial_loop, SerialFrame};
+use crate::serial_actor::{serial_loop, tcp_loop, SerialFrame};
+pub use crate::serial_actor::{ConnectionState, DataBits, FlowControl, Parity, SerialConfig, StopBits};
 use crate::Commands::CmdSyncNextMessage;
 use lazy_static::lazy_static;
 use std::cmp::PartialEq;
@@ -31,7 +71,7 @@ use std::io::{Cursor, Read};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, watch, RwLock};
 use tokio::task::JoinHandle;
 use tokio::time::timeout;
 
@@ -55,14 +95,58 @@ pub enum AppError {
     FileIoError(Commands),
     #[error("Invalid argument: {0:#?}")]
     IllegalArgument(Commands),
+    #[error("History store error: {0}")]
+    History(String),
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[error("Duty cycle limit: {0}")]
+    DutyCycle(String),
+    #[error("Invalid radio parameters: {0}")]
+    RadioParams(#[from] RadioParamsError),
+    #[error("Packet capture error: {0}")]
+    Capture(String),
+    #[error("Session recording error: {0}")]
+    Recording(String),
+    #[error("RPC server error: {0}")]
+    Rpc(String),
+    #[error("MQTT bridge error: {0}")]
+    Mqtt(String),
+    #[error("HTTP gateway error: {0}")]
+    Http(String),
+}
+
+impl AppError {
+    /// The command a firmware error response was matched to.
+    pub fn command(&self) -> Option<&Commands> {
+        match self {
+            AppError::FailedCommand(cmd)
+            | AppError::UnsupportedCommand(cmd)
+            | AppError::NotFound(cmd)
+            | AppError::TableFull(cmd)
+            | AppError::BadState(cmd)
+            | AppError::FileIoError(cmd)
+            | AppError::IllegalArgument(cmd) => Some(cmd),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Companion {
     state: Arc<RwLock<CompanionState>>,
     port: String,
+    serial_config: SerialConfig,
     to_radio_rx: Option<mpsc::Receiver<SerialFrame>>,
     from_radio_tx: mpsc::Sender<SerialFrame>,
+    connection: Arc<watch::Sender<ConnectionState>>,
+    shutdown: watch::Sender<bool>,
+    tasks: std::sync::Mutex<Vec<JoinHandle<()>>>,
+    events: broadcast::Sender<CompanionEvent>,
+    capture: std::sync::Mutex<Option<JoinHandle<()>>>,
+    recorder: Option<SessionRecorder>,
+    replay: Option<ReplayTransport>,
+    /// `port` is a host:port address rather than a serial device
+    tcp: bool,
 }
 
 impl Companion {
@@ -75,6 +159,45 @@ impl Companion {
     pub async fn get_self_info(&self) -> Option<SelfInfo> {
         self.state.read().await.self_info.clone()
     }
+    /// None until the device has answered `CmdDeviceQuery`.
+    pub async fn capabilities(&self) -> Option<Capabilities> {
+        let state = self.state.read().await;
+        state.device_info.as_ref().map(|info| Capabilities::new(info, state.app_target_ver))
+    }
+}
+
+impl Companion {
+    pub async fn apply_preset(&self, preset: RadioPreset) -> Result<(), AppError> {
+        info!("Applying radio preset {}", preset.name());
+        self.apply_radio_parameters(preset.parameters()).await
+    }
+    /// Sets the radio parameters, then re-reads self info to confirm the radio took them.
+    pub async fn apply_radio_parameters(&self, params: RadioParameters) -> Result<(), AppError> {
+        params.validate()?;
+        self.command(Commands::CmdSetRadioParams(params.clone())).await?;
+        let app_start = {
+            let mut state = self.state.write().await;
+            state.self_info = None;
+            state.app_start.clone().unwrap_or_default()
+        };
+        self.command(Commands::CmdAppStart(app_start)).await?;
+        let deadline = tokio::time::Instant::now() + RADIO_VERIFY_TIMEOUT;
+        loop {
+            if let Some(self_info) = &self.state.read().await.self_info {
+                let actual = self_info.radio_parameters();
+                if actual.same_settings(&params) {
+                    return Ok(());
+                }
+                return Err(RadioParamsError::NotApplied { expected: params, actual }.into());
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(AppError::Timeout(
+                    "no self info received after setting radio parameters".to_string(),
+                ));
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -82,20 +205,42 @@ pub struct CompanionState {
     to_radio_tx: mpsc::Sender<SerialFrame>,
     from_radio_rx: mpsc::Receiver<SerialFrame>,
     contacts: Vec<Contact>,
-    pub pending_messages: Vec<MessageTypes>,
+    contacts_synced: bool,
+    app_start: Option<AppStart>,
+    app_target_ver: u8,
+    handshake: Option<HandshakeConfig>,
+    inbox: Inbox,
     newest_advert_time: u32,
     receive_queue: HashMap<u8, Responses>,
     pub self_info: Option<SelfInfo>,
     device_info: Option<DeviceInfo>,
     pending_acks: HashMap<AckCode, MessageEnvelope>,
-    pending_msgs: Vec<SendTxtMsg>,
-    battery_millivolts: Option<u16>,
-    storage_kb: Option<u32>,
-    storage_used_kb: Option<u32>,
+    pending_msgs: Vec<MessageEnvelope>,
+    health: HealthMonitor,
+    events: broadcast::Sender<CompanionEvent>,
     command_queue: VecDeque<Commands>,
     result_queue: VecDeque<Result<Commands, AppError>>,
     exports: HashMap<String, String>,
     tuning_parameters: Option<TuningParameters>,
+    history: Option<HistoryStore>,
+    multipart: MultipartConfig,
+    reassembler: Reassembler,
+    outbound_parts: VecDeque<Commands>,
+    delivery_policy: DeliveryPolicy,
+    delivery_reports: VecDeque<DeliveryReport>,
+    channel_policy: ChannelPolicy,
+    pending_channel_msgs: Vec<ChannelEnvelope>,
+    /// Channel hash byte by channel index, learned from confirmed repeats of our own sends
+    channel_hashes: HashMap<u8, u8>,
+    channel_reports: VecDeque<ChannelDeliveryReport>,
+    duty_cycle: DutyCycleConfig,
+    duty_cycle_tracker: DutyCycleTracker,
+    clock_sync: ClockSyncConfig,
+    device_clock: Option<DeviceClock>,
+    last_clock_check: Option<std::time::Instant>,
+    last_clock_resync: Option<std::time::Instant>,
+    topology: Topology,
+    trace_results: VecDeque<TraceResult>,
 }
 
 
@@ -116,6 +261,24 @@ impl Companion {
             .find(|c| c.public_key.bytes[0..6] == key)
             .cloned()
     }
+    /// Finds a contact by exact name, or else by a unique hex public key prefix.
+    pub async fn find_contact(&self, query: &str) -> Result<Contact, AppError> {
+        if let Some(contact) = self.find_contact_by_name(query).await {
+            return Ok(contact);
+        }
+        if let Some(prefix) = serde_hex::decode(query).filter(|p| !p.is_empty()) {
+            let state = self.state.read().await;
+            let mut matches = state.contacts.iter().filter(|c| c.public_key.bytes.starts_with(&prefix));
+            match (matches.next(), matches.count()) {
+                (Some(contact), 0) => return Ok(contact.clone()),
+                (Some(_), others) => {
+                    return Err(AppError::Misc(format!("{} contacts match key prefix {query}", others + 1)));
+                }
+                (None, _) => (),
+            }
+        }
+        Err(AppError::Misc(format!("no contact named or keyed {query}")))
+    }
     pub async fn find_contact_by_full_key(&self, key: Vec<u8>) -> Option<Contact> {
         let state = self.state.read().await;
         let contacts = state.contacts.clone();
@@ -124,10 +287,168 @@ impl Companion {
             .find(|c| c.public_key.bytes[0..32] == key)
             .cloned()
     }
+    /// Queues a direct message, returning the sender timestamp its delivery report will carry.
+    pub async fn send_text(&self, contact: &Contact, text: String) -> Result<u32, AppError> {
+        self.send_text_to_prefix(contact.public_key.prefix_bytes(), text).await
+    }
+    /// Like `send_text`, for callers that only know the key prefix, e.g. from a received message.
+    pub async fn send_text_to_prefix(&self, pubkey_prefix: [u8; 6], text: String) -> Result<u32, AppError> {
+        let sender_timestamp = clock::host_time();
+        let msg = SendTxtMsg {
+            code: CMD_SEND_TXT_MSG,
+            txt_type: 0,
+            attempt: 0,
+            sender_timestamp,
+            pubkey_prefix,
+            text,
+            timeout: None,
+        };
+        self.command(Commands::CmdSendTxtMsg(msg)).await?;
+        Ok(sender_timestamp)
+    }
+    /// Queues a channel message, returning its sender timestamp.
+    pub async fn send_channel_text(&self, channel_idx: u8, text: String) -> Result<u32, AppError> {
+        let sender_timestamp = clock::host_time();
+        let msg = SendChannelTxtMsg {
+            code: CMD_SEND_CHANNEL_TXT_MSG,
+            txt_type: 0,
+            channel_idx,
+            sender_timestamp,
+            text,
+        };
+        self.command(Commands::CmdSendChannelTxtMsg(msg)).await?;
+        Ok(sender_timestamp)
+    }
 
     pub async fn pop_message(&self) -> Option<MessageTypes> {
         let mut state = self.state.write().await;
-        state.pending_messages.pop()
+        state.inbox.pop().map(ReceivedMessage::into_message)
+    }
+    pub async fn pop_received(&self) -> Option<ReceivedMessage> {
+        let mut state = self.state.write().await;
+        state.inbox.pop()
+    }
+    pub async fn resolve_sender(&self, msg: &ReceivedMessage) -> Option<Contact> {
+        let state = self.state.read().await;
+        msg.resolve_contact(&state.contacts).cloned()
+    }
+    pub async fn pending_message_count(&self) -> usize {
+        self.state.read().await.inbox.len()
+    }
+    pub async fn set_inbox_config(&self, config: InboxConfig) {
+        self.state.write().await.inbox.set_config(config);
+    }
+    pub async fn set_multipart_config(&self, config: MultipartConfig) {
+        self.state.write().await.multipart = config;
+    }
+    pub async fn set_delivery_policy(&self, policy: DeliveryPolicy) {
+        self.state.write().await.delivery_policy = policy;
+    }
+    pub async fn pop_delivery_report(&self) -> Option<DeliveryReport> {
+        self.state.write().await.delivery_reports.pop_front()
+    }
+    pub async fn set_channel_policy(&self, policy: ChannelPolicy) {
+        self.state.write().await.channel_policy = policy;
+    }
+    pub async fn pop_channel_report(&self) -> Option<ChannelDeliveryReport> {
+        self.state.write().await.channel_reports.pop_front()
+    }
+    /// Events are dropped for receivers that fall more than `EVENT_BUFFER_DEPTH` behind.
+    pub fn subscribe(&self) -> broadcast::Receiver<CompanionEvent> {
+        self.events.subscribe()
+    }
+    /// Our own node and every contact with a position, with distance and bearing from us.
+    pub async fn node_positions(&self) -> Vec<NodePosition> {
+        let state = self.state.read().await;
+        geo::node_positions(state.self_info.as_ref(), &state.contacts)
+    }
+    /// Snapshot of the nodes and links learned from contacts, adverts, traces and the RX log.
+    pub async fn topology(&self) -> Topology {
+        self.state.read().await.topology.clone()
+    }
+    pub async fn pop_trace_result(&self) -> Option<TraceResult> {
+        self.state.write().await.trace_results.pop_front()
+    }
+    /// Writes every packet in the RX log to pcapng files, replacing any capture already running.
+    pub fn start_capture(&self, config: CaptureConfig) -> Result<(), AppError> {
+        let mut capture = PacketCapture::open(config)?;
+        let mut events = self.events.subscribe();
+        let mut shutdown = self.shutdown.subscribe();
+        let task = tokio::task::Builder::new()
+            .name("packet-capture")
+            .spawn(async move {
+                loop {
+                    let event = tokio::select! {
+                        event = events.recv() => event,
+                        _ = shutdown.wait_for(|stop| *stop) => break,
+                    };
+                    match event {
+                        Ok(CompanionEvent::RxLog(rx)) => {
+                            if let Err(e) = capture.record(&rx) {
+                                error!("Stopping packet capture: {e}");
+                                break;
+                            }
+                        }
+                        Ok(_) => (),
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            warn!("Packet capture fell behind, {missed} events were not written.");
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            })
+            .map_err(|e| AppError::Misc(format!("Failed to spawn packet capture: {e}")))?;
+        if let Some(previous) = self.capture.lock().unwrap().replace(task) {
+            previous.abort();
+        }
+        Ok(())
+    }
+    pub fn stop_capture(&self) {
+        if let Some(task) = self.capture.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+    pub async fn set_health_config(&self, config: HealthConfig) {
+        self.state.write().await.health.set_config(config);
+    }
+    pub async fn battery_millivolts(&self) -> Option<u16> {
+        self.state.read().await.health.latest().map(|r| r.millivolts)
+    }
+    pub async fn battery_percent(&self) -> Option<u8> {
+        self.state.read().await.health.latest().map(|r| r.battery_percent)
+    }
+    /// Used and total storage in kB.
+    pub async fn storage_kb(&self) -> Option<(u32, u32)> {
+        self.state.read().await.health.latest().map(|r| (r.storage_used_kb, r.storage_total_kb))
+    }
+    pub async fn health(&self) -> Option<HealthReading> {
+        self.state.read().await.health.latest().copied()
+    }
+    /// Past readings, oldest first.
+    pub async fn health_history(&self) -> Vec<HealthReading> {
+        self.state.read().await.health.history().copied().collect()
+    }
+    pub async fn set_clock_sync_config(&self, config: ClockSyncConfig) {
+        let mut state = self.state.write().await;
+        state.clock_sync = config;
+        state.last_clock_check = None;
+    }
+    /// The most recent reading of the device clock.
+    pub async fn device_clock(&self) -> Option<DeviceClock> {
+        self.state.read().await.device_clock
+    }
+    /// Seconds the device clock was ahead of the host at the last reading.
+    pub async fn clock_drift(&self) -> Option<i64> {
+        self.state.read().await.device_clock.map(|c| c.drift_secs())
+    }
+    pub async fn set_duty_cycle_config(&self, config: DutyCycleConfig) {
+        self.state.write().await.duty_cycle = config;
+    }
+    /// Airtime we spent transmitting within the configured window.
+    pub async fn duty_cycle_status(&self) -> DutyCycleStatus {
+        let mut state = self.state.write().await;
+        let config = state.duty_cycle.clone();
+        state.duty_cycle_tracker.status(std::time::Instant::now(), &config)
     }
     pub async fn peek_result(&self, cmd: Commands) -> Option<Result<Commands, AppError>> {
         let mut state = self.state.write().await;
@@ -144,6 +465,27 @@ impl Companion {
             None
         }
     }
+    /// Sends a command the firmware answers with OK or an error, and waits for that answer.
+    pub async fn command_and_wait(&self, cmd: Commands, timeout: std::time::Duration) -> Result<Commands, AppError> {
+        self.command(cmd.clone()).await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            {
+                let mut state = self.state.write().await;
+                let position = state.result_queue.iter().position(|r| match r {
+                    Ok(done) => *done == cmd,
+                    Err(e) => e.command() == Some(&cmd),
+                });
+                if let Some(result) = position.and_then(|i| state.result_queue.remove(i)) {
+                    return result;
+                }
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(AppError::Timeout(format!("no response to {cmd:?}")));
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
     pub async fn pop_result(&self) -> Option<Result<Commands, AppError>> {
         let mut state = self.state.write().await;
         state.result_queue.pop_front()
@@ -162,7 +504,41 @@ impl Companion {
     }
 }
 
-#[derive(Debug, Clone)]
+impl Companion {
+    pub async fn enable_history(&self, store: HistoryStore) {
+        self.state.write().await.history = Some(store);
+    }
+    pub async fn disable_history(&self) -> Option<HistoryStore> {
+        self.state.write().await.history.take()
+    }
+    pub async fn history_conversations(&self) -> Result<Vec<ConversationSummary>, AppError> {
+        let state = self.state.read().await;
+        let history = state.history.as_ref().ok_or_else(|| AppError::History("History is not enabled".to_string()))?;
+        Ok(history.conversations())
+    }
+    pub async fn history_thread(
+        &self,
+        conversation: &ConversationKey,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, AppError> {
+        let state = self.state.read().await;
+        let history = state.history.as_ref().ok_or_else(|| AppError::History("History is not enabled".to_string()))?;
+        Ok(history.thread(conversation, before, limit))
+    }
+    pub async fn history_unread_count(&self, conversation: &ConversationKey) -> Result<usize, AppError> {
+        let state = self.state.read().await;
+        let history = state.history.as_ref().ok_or_else(|| AppError::History("History is not enabled".to_string()))?;
+        Ok(history.unread_count(conversation))
+    }
+    pub async fn history_mark_read(&self, conversation: &ConversationKey) -> Result<usize, AppError> {
+        let mut state = self.state.write().await;
+        let history = state.history.as_mut().ok_or_else(|| AppError::History("History is not enabled".to_string()))?;
+        history.mark_read(conversation)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
 pub enum MessageTypes {
     ChannelMsg(ChannelMsg),
     ChannelMsgV3(ChannelMsgV3),
@@ -170,69 +546,209 @@ pub enum MessageTypes {
     ContactMsgV3(ContactMsgV3),
 }
 
+impl CompanionState {
+    pub(crate) fn publish(&self, event: CompanionEvent) {
+        // Having no subscribers is fine
+        let _ = self.events.send(event);
+    }
+}
+
+/// Resolves once `shutdown` is set, or its sender is gone. The watch guard is dropped before
+/// returning, so callers holding the future stay `Send`.
+pub(crate) async fn stopped(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
 impl Companion {
     pub fn new(port: &str) -> Self {
+        Self::with_serial_config(port, SerialConfig::default())
+    }
+    /// A companion reached over TCP at `addr` (host:port), as exposed by WiFi companion firmware.
+    pub fn tcp(addr: &str) -> Self {
+        let mut companion = Self::new(addr);
+        companion.tcp = true;
+        companion
+    }
+    /// A companion driven by a session recording instead of a serial port.
+    pub fn replay(transport: ReplayTransport) -> Self {
+        let mut companion = Self::new("replay");
+        companion.replay = Some(transport);
+        companion
+    }
+    /// Records every frame sent and received to `path`, must be called before `start`.
+    pub fn record_session(&mut self, path: impl AsRef<std::path::Path>) -> Result<(), AppError> {
+        self.recorder = Some(SessionRecorder::create(path)?);
+        Ok(())
+    }
+    pub fn with_serial_config(port: &str, serial_config: SerialConfig) -> Self {
+        let events = broadcast::channel(consts::EVENT_BUFFER_DEPTH).0;
         let (to_radio_tx, to_radio_rx) = mpsc::channel(consts::MPSC_BUFFER_DEPTH);
         let (from_radio_tx, from_radio_rx) = mpsc::channel(consts::MPSC_BUFFER_DEPTH);
         let state = Arc::new(RwLock::new(CompanionState {
             to_radio_tx,
             from_radio_rx,
             contacts: vec![],
-            pending_messages: vec![],
+            contacts_synced: false,
+            app_start: None,
+            app_target_ver: 0,
+            handshake: None,
+            inbox: Inbox::default(),
             newest_advert_time: 0,
             receive_queue: HashMap::new(),
             self_info: None,
             device_info: None,
             pending_acks: HashMap::new(),
             pending_msgs: vec![],
-            battery_millivolts: None,
-            storage_kb: None,
-            storage_used_kb: None,
+            health: HealthMonitor::default(),
+            events: events.clone(),
             command_queue: VecDeque::new(),
             result_queue: VecDeque::new(),
             exports: HashMap::new(),
             tuning_parameters: None,
+            history: None,
+            multipart: MultipartConfig::default(),
+            reassembler: Reassembler::default(),
+            outbound_parts: VecDeque::new(),
+            delivery_policy: DeliveryPolicy::default(),
+            delivery_reports: VecDeque::new(),
+            channel_policy: ChannelPolicy::default(),
+            pending_channel_msgs: vec![],
+            channel_hashes: HashMap::new(),
+            channel_reports: VecDeque::new(),
+            duty_cycle: DutyCycleConfig::default(),
+            duty_cycle_tracker: DutyCycleTracker::default(),
+            clock_sync: ClockSyncConfig::default(),
+            device_clock: None,
+            last_clock_check: None,
+            last_clock_resync: None,
+            topology: Topology::default(),
+            trace_results: VecDeque::new(),
         }));
         Companion {
             port: port.to_string(),
+            serial_config,
             to_radio_rx: Some(to_radio_rx),
             from_radio_tx,
+            connection: Arc::new(watch::channel(ConnectionState::Connecting).0),
+            shutdown: watch::channel(false).0,
+            tasks: std::sync::Mutex::new(vec![]),
+            events,
+            capture: std::sync::Mutex::new(None),
+            recorder: None,
+            replay: None,
+            tcp: false,
             state,
         }
     }
     pub async fn start(&mut self) -> Result<(), AppError> {
         let port = self.port.clone();
+        let serial_config = self.serial_config.clone();
         let from_radio_tx = self.from_radio_tx.clone();
         let mut to_radio_rx = self
             .to_radio_rx
             .take()
             .ok_or_else(|| AppError::Misc("Listener already started".to_string()))?;
 
-        tokio::task::Builder::new()
-            .name("serial-loop")
-            .spawn(async move {
-                serial_loop(port, &mut to_radio_rx, &from_radio_tx).await;
-            });
+        let connection = self.connection.clone();
+        let shutdown = self.shutdown.subscribe();
+        let recorder = self.recorder.clone();
+        let serial_task = match self.replay.take() {
+            Some(replay) => tokio::task::Builder::new().name("replay-transport").spawn(async move {
+                replay.run(&mut to_radio_rx, &from_radio_tx, &connection, shutdown).await;
+            }),
+            None if self.tcp => tokio::task::Builder::new().name("tcp-loop").spawn(async move {
+                let delay = serial_config.reconnect_delay;
+                tcp_loop(port, delay, &mut to_radio_rx, &from_radio_tx, &connection, shutdown, recorder).await;
+            }),
+            None => tokio::task::Builder::new().name("serial-loop").spawn(async move {
+                serial_loop(port, serial_config, &mut to_radio_rx, &from_radio_tx, &connection, shutdown, recorder)
+                    .await;
+            }),
+        }
+        .map_err(|e| AppError::Misc(format!("Failed to spawn serial loop: {e}")))?;
         let state_handle = self.state.clone();
-        tokio::task::Builder::new()
+        let mut shutdown = self.shutdown.subscribe();
+        let processor_task = tokio::task::Builder::new()
             .name("background-processor")
             .spawn(async move {
                 info!("Background processor started.");
                 loop {
                     // We pass the clone into our internal function
-                    if let Err(e) = check_internal(state_handle.clone()).await {
-                        error!("Background processor encountered an error: {}", e);
-                        // Depending on the error, you might want to break or continue
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                        continue;
-                    }
+                    let delay = match check_internal(state_handle.clone()).await {
+                        Ok(()) => tokio::time::Duration::from_millis(250),
+                        Err(e) => {
+                            error!("Background processor encountered an error: {}", e);
+                            tokio::time::Duration::from_secs(1)
+                        }
+                    };
                     // Small sleep to prevent tight-looping if no messages are arriving
-                    tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => (),
+                        _ = shutdown.wait_for(|stop| *stop) => break,
+                    }
                 }
-            });
+                info!("Background processor stopped.");
+            })
+            .map_err(|e| AppError::Misc(format!("Failed to spawn background processor: {e}")))?;
+        let state_handle = self.state.clone();
+        let port = self.port.clone();
+        let mut connection = self.connection.subscribe();
+        let mut shutdown = self.shutdown.subscribe();
+        let monitor_task = tokio::task::Builder::new()
+            .name("connection-monitor")
+            .spawn(async move {
+                let mut reconnecting = false;
+                loop {
+                    tokio::select! {
+                        changed = connection.changed() => if changed.is_err() { break },
+                        _ = shutdown.wait_for(|stop| *stop) => break,
+                    }
+                    let current = *connection.borrow_and_update();
+                    match current {
+                        ConnectionState::Reconnecting { .. } => reconnecting = true,
+                        ConnectionState::Connected if reconnecting => {
+                            reconnecting = false;
+                            fail_in_flight(&mut *state_handle.write().await);
+                            // Contacts, self info and the device clock may all have changed while we were away
+                            let handshake = state_handle.read().await.handshake.clone();
+                            if let Some(handshake) = handshake {
+                                info!("Reconnected to {}, repeating handshake.", port);
+                                if let Err(e) = run_handshake(&state_handle, &port, &handshake).await {
+                                    error!("Handshake after reconnect failed: {e}");
+                                }
+                            }
+                        }
+                        ConnectionState::Closed => break,
+                        _ => (),
+                    }
+                }
+            })
+            .map_err(|e| AppError::Misc(format!("Failed to spawn connection monitor: {e}")))?;
+        self.tasks
+            .lock()
+            .unwrap()
+            .extend([serial_task, processor_task, monitor_task]);
 
         Ok(())
     }
+    /// Stops the background tasks and closes the port.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        let tasks: Vec<JoinHandle<()>> = self.tasks.lock().unwrap().drain(..).collect();
+        for task in tasks {
+            if let Err(e) = task.await {
+                error!("Background task failed during shutdown: {e}");
+            }
+        }
+        self.connection.send_replace(ConnectionState::Closed);
+    }
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.connection.borrow()
+    }
+    /// Watch connection state changes.
+    pub fn watch_connection(&self) -> watch::Receiver<ConnectionState> {
+        self.connection.subscribe()
+    }
     pub async fn command(&self, cmd: Commands) -> Result<(), AppError> {
         send_command(&self.state, cmd).await
     }
@@ -0,0 +1,364 @@
+//! Bridges companion events to an MQTT broker as JSON and takes commands from a topic tree.
+//!
+//! Commands are published to `{command}/{name}` with a JSON body, `name` being one of `send_dm`
+//! (`{"to": "name or key prefix", "text": ".."}`), `send_channel` (`{"channel": 0, "text": ".."}`),
+//! `advert` (`{"flood": true}`) or `command` (`{"command": <Commands>}`, only when
+//! `allow_raw_commands` is set). An optional `id` in the body is echoed in the reply on
+//! `{response}/{name}`.
+use crate::commands::AdvertisementMode;
+use crate::events::CompanionEvent;
+use crate::packet::Payload;
+use crate::{serde_hex, stopped, AppError, Commands, Companion};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, Publish, QoS};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, watch};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const COMMAND_WAIT: Duration = Duration::from_secs(10);
+
+/// Topic bases, the bridge appends a sender, channel or command name where noted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttTopics {
+    /// `{direct}/{sender key prefix}`
+    pub direct: String,
+    /// `{channel}/{channel index}`
+    pub channel: String,
+    /// `{advert}/{public key}`, from adverts heard in the RX log
+    pub advert: String,
+    /// Battery and storage readings, alerts go to `{health}/{alert}`
+    pub health: String,
+    pub rx_log: String,
+    /// `{delivery}/dm` and `{delivery}/channel`
+    pub delivery: String,
+    /// Retained `online`, or `offline` as the last will
+    pub status: String,
+    /// Subscribed as `{command}/+`
+    pub command: String,
+    /// `{response}/{command name}`
+    pub response: String,
+}
+
+impl MqttTopics {
+    pub fn with_prefix(prefix: &str) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        Self {
+            direct: format!("{prefix}/dm"),
+            channel: format!("{prefix}/channel"),
+            advert: format!("{prefix}/advert"),
+            health: format!("{prefix}/health"),
+            rx_log: format!("{prefix}/rxlog"),
+            delivery: format!("{prefix}/delivery"),
+            status: format!("{prefix}/status"),
+            command: format!("{prefix}/cmd"),
+            response: format!("{prefix}/response"),
+        }
+    }
+}
+
+impl Default for MqttTopics {
+    fn default() -> Self {
+        Self::with_prefix("meshcore")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// Username and password
+    pub credentials: Option<(String, String)>,
+    pub keep_alive: Duration,
+    /// Used for every publish and for the command subscription
+    pub qos: QoS,
+    pub topics: MqttTopics,
+    /// Serve `{command}/command`, which passes any companion command straight to the radio
+    pub allow_raw_commands: bool,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "meshcore-bridge".to_string(),
+            credentials: None,
+            keep_alive: Duration::from_secs(30),
+            qos: QoS::AtLeastOnce,
+            topics: MqttTopics::default(),
+            allow_raw_commands: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct DmRequest {
+    pub to: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct ChannelRequest {
+    pub channel: u8,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct AdvertRequest {
+    #[serde(default)]
+    pub flood: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct RawRequest {
+    pub command: Commands,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum BridgeCommand {
+    SendDm(DmRequest),
+    SendChannel(ChannelRequest),
+    Advert(AdvertRequest),
+    Raw(RawRequest),
+}
+
+/// A command taken from the broker, `name` is the last topic level.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CommandRequest {
+    pub name: String,
+    pub id: Value,
+    pub command: Result<BridgeCommand, String>,
+}
+
+fn unix_secs(at: std::time::SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn health_json(reading: &crate::health::HealthReading, alert: Option<&str>) -> Value {
+    json!({
+        "millivolts": reading.millivolts,
+        "battery_percent": reading.battery_percent,
+        "storage_used_kb": reading.storage_used_kb,
+        "storage_total_kb": reading.storage_total_kb,
+        "alert": alert,
+        "received_at": unix_secs(reading.received_at),
+    })
+}
+
+/// The topics and JSON bodies an event is published as. `sender` names the contact of a direct message.
+pub(crate) fn event_messages(topics: &MqttTopics, event: &CompanionEvent, sender: Option<&str>) -> Vec<(String, Value)> {
+    match event {
+        CompanionEvent::Message(message) => {
+            let topic = match message.pubkey_prefix() {
+                Some(prefix) => format!("{}/{}", topics.direct, serde_hex::encode(&prefix.bytes())),
+                None => format!("{}/{}", topics.channel, message.channel_id().unwrap_or_default()),
+            };
+            vec![(topic, message.summary_json(sender))]
+        }
+        CompanionEvent::Health(reading) => vec![(topics.health.clone(), health_json(reading, None))],
+        CompanionEvent::BatteryLow(reading)
+        | CompanionEvent::BatteryRecovered(reading)
+        | CompanionEvent::StorageHigh(reading)
+        | CompanionEvent::StorageRecovered(reading) => {
+            let alert = match event {
+                CompanionEvent::BatteryLow(_) => "battery_low",
+                CompanionEvent::BatteryRecovered(_) => "battery_recovered",
+                CompanionEvent::StorageHigh(_) => "storage_high",
+                _ => "storage_recovered",
+            };
+            vec![(format!("{}/{alert}", topics.health), health_json(reading, Some(alert)))]
+        }
+        CompanionEvent::RxLog(entry) => {
+            let mut messages = vec![];
+            if let Ok(Payload::Advert(advert)) = &entry.payload {
+                let mut body = json!(advert);
+                body["snr_db"] = json!(entry.snr_db);
+                body["rssi"] = json!(entry.rssi);
+                body["received_at"] = json!(unix_secs(entry.received_at));
+                let topic = format!("{}/{}", topics.advert, serde_hex::encode(&advert.public_key.bytes));
+                messages.push((topic, body));
+            }
+            let body = json!({
+                "snr_db": entry.snr_db,
+                "rssi": entry.rssi,
+                "raw": serde_hex::encode(&entry.raw),
+                "packet": entry.packet.as_ref().ok(),
+                "payload": entry.payload.as_ref().ok(),
+                "received_at": unix_secs(entry.received_at),
+            });
+            messages.push((topics.rx_log.clone(), body));
+            messages
+        }
+        CompanionEvent::Delivery(report) => vec![(format!("{}/dm", topics.delivery), json!(report))],
+        CompanionEvent::ChannelDelivery(report) => vec![(format!("{}/channel", topics.delivery), json!(report))],
+    }
+}
+
+/// Reads a publish on the command tree, `None` when the topic is not a command.
+pub(crate) fn parse_command(config: &MqttConfig, topic: &str, payload: &[u8]) -> Option<CommandRequest> {
+    let name = topic.strip_prefix(config.topics.command.as_str())?.strip_prefix('/')?;
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+    let body: Value = match serde_json::from_slice(payload) {
+        Ok(body) => body,
+        Err(e) => {
+            return Some(CommandRequest { name: name.to_string(), id: Value::Null, command: Err(e.to_string()) });
+        }
+    };
+    let id = body.get("id").cloned().unwrap_or(Value::Null);
+    fn parse<T: serde::de::DeserializeOwned>(body: Value) -> Result<T, String> {
+        serde_json::from_value(body).map_err(|e| e.to_string())
+    }
+    let command = match name {
+        "send_dm" => parse(body).map(BridgeCommand::SendDm),
+        "send_channel" => parse(body).map(BridgeCommand::SendChannel),
+        "advert" => parse(body).map(BridgeCommand::Advert),
+        "command" if config.allow_raw_commands => parse(body).map(BridgeCommand::Raw),
+        "command" => Err("raw commands are disabled".to_string()),
+        _ => Err(format!("unknown command {name}")),
+    };
+    Some(CommandRequest { name: name.to_string(), id, command })
+}
+
+pub(crate) async fn execute(companion: &Companion, command: BridgeCommand) -> Result<Value, AppError> {
+    match command {
+        BridgeCommand::SendDm(DmRequest { to, text }) => {
+            let contact = companion.find_contact(&to).await?;
+            let sender_timestamp = companion.send_text(&contact, text).await?;
+            // Lets clients match the report published later on the delivery topic
+            Ok(json!({ "to": contact.adv_name, "sender_timestamp": sender_timestamp }))
+        }
+        BridgeCommand::SendChannel(ChannelRequest { channel, text }) => {
+            let sender_timestamp = companion.send_channel_text(channel, text).await?;
+            Ok(json!({ "channel": channel, "sender_timestamp": sender_timestamp }))
+        }
+        BridgeCommand::Advert(AdvertRequest { flood }) => {
+            let mode = if flood { AdvertisementMode::Flood } else { AdvertisementMode::ZeroHop };
+            companion.command_and_wait(Commands::CmdSendSelfAdvert(mode), COMMAND_WAIT).await?;
+            Ok(Value::Null)
+        }
+        BridgeCommand::Raw(RawRequest { command }) => {
+            let reply = companion.command_and_wait(command, COMMAND_WAIT).await?;
+            serde_json::to_value(reply).map_err(|e| AppError::Mqtt(e.to_string()))
+        }
+    }
+}
+
+async fn answer(companion: Arc<Companion>, client: AsyncClient, config: Arc<MqttConfig>, request: CommandRequest) {
+    let outcome = match request.command {
+        Ok(command) => execute(&companion, command).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    let body = match outcome {
+        Ok(result) => json!({ "id": request.id, "ok": true, "result": result }),
+        Err(error) => json!({ "id": request.id, "ok": false, "error": error }),
+    };
+    let topic = format!("{}/{}", config.topics.response, request.name);
+    if let Err(e) = client.publish(topic, config.qos, false, body.to_string()).await {
+        warn!("Failed to publish MQTT response: {e}");
+    }
+}
+
+/// Polls the connection, rumqttc reconnects on the next poll after an error.
+async fn drive(mut eventloop: EventLoop, client: AsyncClient, config: Arc<MqttConfig>, incoming: mpsc::Sender<Publish>) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}:{}", config.host, config.port);
+                // Clean sessions drop subscriptions, so subscribe again on every connect
+                let subscribed = client.try_subscribe(format!("{}/+", config.topics.command), config.qos);
+                let online = client.try_publish(&config.topics.status, config.qos, true, "online");
+                if let Err(e) = subscribed.and(online) {
+                    warn!("Failed to set up MQTT session: {e}");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                if incoming.send(publish).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => (),
+            Err(e) => {
+                warn!("MQTT connection error: {e}, retrying in {}s", RECONNECT_DELAY.as_secs());
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Publishes companion events and serves commands until `shutdown` is set.
+pub async fn run_bridge(
+    companion: Arc<Companion>,
+    config: MqttConfig,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), AppError> {
+    let config = Arc::new(config);
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(config.keep_alive);
+    options.set_last_will(LastWill::new(&config.topics.status, "offline", config.qos, true));
+    if let Some((username, password)) = &config.credentials {
+        options.set_credentials(username, password);
+    }
+    let (client, eventloop) = AsyncClient::new(options, 64);
+    let (incoming_tx, mut incoming) = mpsc::channel(64);
+    let driver = tokio::task::Builder::new()
+        .name("mqtt-eventloop")
+        .spawn(drive(eventloop, client.clone(), config.clone(), incoming_tx))
+        .map_err(|e| AppError::Mqtt(e.to_string()))?;
+
+    let mut events = companion.subscribe();
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("MQTT bridge fell behind, {missed} events not published");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let sender = match &event {
+                    CompanionEvent::Message(message) => match message.pubkey_prefix() {
+                        Some(prefix) => companion.find_contact_by_key_prefix(prefix.bytes().to_vec()).await,
+                        None => None,
+                    },
+                    _ => None,
+                };
+                let sender = sender.as_ref().map(|contact| contact.adv_name.as_str());
+                for (topic, body) in event_messages(&config.topics, &event, sender) {
+                    if let Err(e) = client.publish(topic, config.qos, false, body.to_string()).await {
+                        warn!("Failed to publish to MQTT: {e}");
+                    }
+                }
+            }
+            Some(publish) = incoming.recv() => {
+                let Some(request) = parse_command(&config, &publish.topic, &publish.payload) else {
+                    continue;
+                };
+                // Commands that wait for the radio must not hold up event publishing
+                let spawned = tokio::task::Builder::new()
+                    .name("mqtt-command")
+                    .spawn(answer(companion.clone(), client.clone(), config.clone(), request));
+                if let Err(e) = spawned {
+                    error!("Failed to spawn MQTT command task: {e}");
+                }
+            }
+            _ = stopped(&mut shutdown) => break,
+        }
+    }
+    // A clean disconnect does not trigger the last will
+    let _ = client.try_publish(&config.topics.status, config.qos, true, "offline");
+    let _ = client.try_disconnect();
+    let mut driver = driver;
+    if tokio::time::timeout(Duration::from_secs(2), &mut driver).await.is_err() {
+        driver.abort();
+    }
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use crate::responses::{MessageSource, ReceivedMessage};
+use crate::AppError;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// Longest UTF-8 encoding of a single character
+const MAX_CHAR_LEN: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultipartConfig {
+    /// Split outgoing text that exceeds the firmware limit instead of letting the radio truncate it
+    pub split: bool,
+    /// Prefix each part with an "(i/n) " marker so receivers can put the message back together
+    pub marker: bool,
+    /// Reassemble incoming marked parts into a single message
+    pub reassemble: bool,
+    /// Incomplete messages are delivered part by part once this much time has passed
+    pub reassembly_timeout: Duration,
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        Self {
+            split: true,
+            marker: true,
+            reassemble: true,
+            reassembly_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Splits `text` into parts of at most `max_bytes` bytes, never breaking a UTF-8 character.
+pub fn split_text(text: &str, max_bytes: usize, marker: bool) -> Result<Vec<String>, AppError> {
+    if text.len() <= max_bytes {
+        return Ok(vec![text.to_string()]);
+    }
+    // Any character has to fit in a part, whatever the overhead of the marker
+    let too_small = || AppError::Misc(format!("cannot split text into parts of {max_bytes} bytes"));
+    if !marker {
+        return (max_bytes >= MAX_CHAR_LEN).then(|| chunk(text, max_bytes)).ok_or_else(too_small);
+    }
+    let mut total = 1;
+    loop {
+        let overhead = format!("({total}/{total}) ").len();
+        let budget = max_bytes.checked_sub(overhead).filter(|b| *b >= MAX_CHAR_LEN).ok_or_else(too_small)?;
+        let chunks = chunk(text, budget);
+        if chunks.len() <= total {
+            let count = chunks.len();
+            return Ok(chunks
+                .into_iter()
+                .enumerate()
+                .map(|(i, c)| format!("({}/{count}) {c}", i + 1))
+                .collect());
+        }
+        total = chunks.len();
+    }
+}
+
+fn chunk(text: &str, max_bytes: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    for c in text.chars() {
+        if current.len() + c.len_utf8() > max_bytes {
+            parts.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+/// Parses a leading "(i/n) " marker, returning the 1-based part index, the part count and the remaining text.
+pub fn parse_marker(text: &str) -> Option<(usize, usize, &str)> {
+    let rest = text.strip_prefix('(')?;
+    let (counter, body) = rest.split_once(") ")?;
+    let (index, total) = counter.split_once('/')?;
+    let index: usize = index.parse().ok()?;
+    let total: usize = total.parse().ok()?;
+    if index == 0 || total < 2 || index > total {
+        return None;
+    }
+    Some((index, total, body))
+}
+
+/// Channel texts arrive as "name: text", so the marker sits after the sender's name.
+fn split_channel_sender(msg: &ReceivedMessage) -> (Option<&str>, &str) {
+    match msg.source() {
+        MessageSource::Channel(_) => match msg.text().split_once(": ") {
+            Some((name, text)) => (Some(name), text),
+            None => (None, msg.text()),
+        },
+        MessageSource::Contact(_) => (None, msg.text()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct GroupKey {
+    source: MessageSource,
+    channel_sender: Option<String>,
+    sender_timestamp: u32,
+    total: usize,
+}
+
+#[derive(Debug)]
+struct Group {
+    first_seen: Instant,
+    parts: BTreeMap<usize, ReceivedMessage>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Reassembler {
+    groups: HashMap<GroupKey, Group>,
+}
+
+impl Reassembler {
+    /// Feeds a received message in, returning whatever is ready for delivery.
+    pub(crate) fn push(&mut self, msg: ReceivedMessage, timeout: Duration) -> Vec<ReceivedMessage> {
+        let mut ready = self.expire(timeout);
+        let (channel_sender, text) = split_channel_sender(&msg);
+        let Some((index, total, _)) = parse_marker(text) else {
+            ready.push(msg);
+            return ready;
+        };
+        let key = GroupKey {
+            source: msg.source(),
+            channel_sender: channel_sender.map(str::to_string),
+            sender_timestamp: msg.sender_timestamp(),
+            total,
+        };
+        let group = self.groups.entry(key.clone()).or_insert_with(|| Group {
+            first_seen: Instant::now(),
+            parts: BTreeMap::new(),
+        });
+        group.parts.insert(index, msg);
+        if group.parts.len() == total {
+            let group = self.groups.remove(&key).unwrap();
+            ready.push(combine(group));
+        }
+        ready
+    }
+
+    /// Gives up on groups older than `timeout`, releasing their parts as individual messages.
+    pub(crate) fn expire(&mut self, timeout: Duration) -> Vec<ReceivedMessage> {
+        let expired: Vec<GroupKey> = self
+            .groups
+            .iter()
+            .filter(|(_, g)| g.first_seen.elapsed() > timeout)
+            .map(|(k, _)| k.clone())
+            .collect();
+        let mut released = vec![];
+        for key in expired {
+            if let Some(group) = self.groups.remove(&key) {
+                warn!("Gave up reassembling {key:?}, {} parts received", group.parts.len());
+                released.extend(group.parts.into_values());
+            }
+        }
+        released
+    }
+}
+
+fn combine(group: Group) -> ReceivedMessage {
+    let mut body = String::new();
+    for part in group.parts.values() {
+        let (_, text) = split_channel_sender(part);
+        if let Some((_, _, text)) = parse_marker(text) {
+            body.push_str(text);
+        }
+    }
+    let last = group.parts.into_values().last().unwrap();
+    let text = match split_channel_sender(&last) {
+        (Some(name), _) => format!("{name}: {body}"),
+        (None, _) => body,
+    };
+    last.with_text(text)
+}
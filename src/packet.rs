@@ -0,0 +1,386 @@
+use crate::contact_mgmt::PublicKey;
+use std::time::SystemTime;
+use thiserror::Error;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PacketRoute {
+    TransportFlood,
+    Flood,
+    Direct,
+    TransportDirect,
+}
+
+impl PacketRoute {
+    fn from_header(header: u8) -> Self {
+        match header & 0x03 {
+            0 => PacketRoute::TransportFlood,
+            1 => PacketRoute::Flood,
+            2 => PacketRoute::Direct,
+            _ => PacketRoute::TransportDirect,
+        }
+    }
+    pub fn has_transport_codes(&self) -> bool {
+        matches!(self, PacketRoute::TransportFlood | PacketRoute::TransportDirect)
+    }
+    pub fn is_flood(&self) -> bool {
+        matches!(self, PacketRoute::TransportFlood | PacketRoute::Flood)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PayloadType {
+    Req,
+    Response,
+    TxtMsg,
+    Ack,
+    Advert,
+    GrpTxt,
+    GrpData,
+    AnonReq,
+    Path,
+    Trace,
+    Multipart,
+    RawCustom,
+    Unknown(u8),
+}
+
+impl From<u8> for PayloadType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => PayloadType::Req,
+            0x01 => PayloadType::Response,
+            0x02 => PayloadType::TxtMsg,
+            0x03 => PayloadType::Ack,
+            0x04 => PayloadType::Advert,
+            0x05 => PayloadType::GrpTxt,
+            0x06 => PayloadType::GrpData,
+            0x07 => PayloadType::AnonReq,
+            0x08 => PayloadType::Path,
+            0x09 => PayloadType::Trace,
+            0x0a => PayloadType::Multipart,
+            0x0f => PayloadType::RawCustom,
+            other => PayloadType::Unknown(other),
+        }
+    }
+}
+
+impl PayloadType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PayloadType::Req => "REQ",
+            PayloadType::Response => "RESPONSE",
+            PayloadType::TxtMsg => "TXT_MSG",
+            PayloadType::Ack => "ACK",
+            PayloadType::Advert => "ADVERT",
+            PayloadType::GrpTxt => "GRP_TXT",
+            PayloadType::GrpData => "GRP_DATA",
+            PayloadType::AnonReq => "ANON_REQ",
+            PayloadType::Path => "PATH",
+            PayloadType::Trace => "TRACE",
+            PayloadType::Multipart => "MULTIPART",
+            PayloadType::RawCustom => "RAW_CUSTOM",
+            PayloadType::Unknown(_) => "UNKNOWN",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq, Serialize)]
+pub enum PacketError {
+    #[error("Packet truncated: {0}")]
+    Truncated(&'static str),
+    #[error("Path length {0} exceeds the maximum of 64")]
+    PathTooLong(usize),
+}
+
+/// An over-the-air MeshCore packet as seen in the firmware's RX log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Packet {
+    pub route: PacketRoute,
+    pub payload_type: PayloadType,
+    pub payload_version: u8,
+    pub transport_codes: Option<[u16; 2]>,
+    /// One byte hash (first byte of the public key) per hop
+    pub path: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn parse(raw: &[u8]) -> Result<Self, PacketError> {
+        let (&header, mut rest) = raw.split_first().ok_or(PacketError::Truncated("header"))?;
+        let route = PacketRoute::from_header(header);
+        let transport_codes = if route.has_transport_codes() {
+            if rest.len() < 4 {
+                return Err(PacketError::Truncated("transport codes"));
+            }
+            let codes = [
+                u16::from_le_bytes([rest[0], rest[1]]),
+                u16::from_le_bytes([rest[2], rest[3]]),
+            ];
+            rest = &rest[4..];
+            Some(codes)
+        } else {
+            None
+        };
+        let (&path_len, rest) = rest.split_first().ok_or(PacketError::Truncated("path length"))?;
+        let path_len = path_len as usize;
+        if path_len > 64 {
+            return Err(PacketError::PathTooLong(path_len));
+        }
+        if rest.len() < path_len {
+            return Err(PacketError::Truncated("path"));
+        }
+        let (path, payload) = rest.split_at(path_len);
+        Ok(Self {
+            route,
+            payload_type: ((header >> 2) & 0x0f).into(),
+            payload_version: header >> 6,
+            transport_codes,
+            path: path.to_vec(),
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+/// Peer to peer payload (REQ, RESPONSE, TXT_MSG, PATH) encrypted with the shared secret.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Encrypted {
+    pub dest_hash: u8,
+    pub src_hash: u8,
+    pub mac: u16,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Channel payload (GRP_TXT, GRP_DATA) encrypted with the channel secret.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GroupEncrypted {
+    pub channel_hash: u8,
+    pub mac: u16,
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AdvertType {
+    None,
+    Chat,
+    Repeater,
+    Room,
+    Sensor,
+    Unknown(u8),
+}
+
+impl From<u8> for AdvertType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => AdvertType::None,
+            1 => AdvertType::Chat,
+            2 => AdvertType::Repeater,
+            3 => AdvertType::Room,
+            4 => AdvertType::Sensor,
+            other => AdvertType::Unknown(other),
+        }
+    }
+}
+
+const ADVERT_HAS_LOCATION: u8 = 0x10;
+const ADVERT_HAS_FEATURE1: u8 = 0x20;
+const ADVERT_HAS_FEATURE2: u8 = 0x40;
+const ADVERT_HAS_NAME: u8 = 0x80;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Advert {
+    pub public_key: PublicKey,
+    pub timestamp: u32,
+    pub signature: Vec<u8>,
+    pub node_type: AdvertType,
+    /// Latitude and longitude in degrees
+    pub location: Option<(f64, f64)>,
+    pub feature1: Option<u16>,
+    pub feature2: Option<u16>,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Payload {
+    Req(Encrypted),
+    Response(Encrypted),
+    TxtMsg(Encrypted),
+    /// CRC of the acknowledged message
+    Ack { checksum: u32 },
+    Advert(Advert),
+    GrpTxt(GroupEncrypted),
+    GrpData(GroupEncrypted),
+    AnonReq {
+        dest_hash: u8,
+        /// Sender key, the recipient derives the shared secret from it
+        public_key: PublicKey,
+        mac: u16,
+        ciphertext: Vec<u8>,
+    },
+    Path(Encrypted),
+    /// The packet path carries the SNR of each hop so far
+    Trace {
+        tag: u32,
+        auth_code: u32,
+        flags: u8,
+        path_hashes: Vec<u8>,
+    },
+    Multipart {
+        /// Parts still to follow this one
+        remaining: u8,
+        inner_type: PayloadType,
+        data: Vec<u8>,
+    },
+    RawCustom(Vec<u8>),
+    Unknown(Vec<u8>),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize, field: &'static str) -> Result<&'a [u8], PacketError> {
+        if self.data.len() < len {
+            return Err(PacketError::Truncated(field));
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+    fn u8(&mut self, field: &'static str) -> Result<u8, PacketError> {
+        Ok(self.take(1, field)?[0])
+    }
+    fn u16(&mut self, field: &'static str) -> Result<u16, PacketError> {
+        Ok(u16::from_le_bytes(self.take(2, field)?.try_into().unwrap()))
+    }
+    fn u32(&mut self, field: &'static str) -> Result<u32, PacketError> {
+        Ok(u32::from_le_bytes(self.take(4, field)?.try_into().unwrap()))
+    }
+    fn i32(&mut self, field: &'static str) -> Result<i32, PacketError> {
+        Ok(i32::from_le_bytes(self.take(4, field)?.try_into().unwrap()))
+    }
+    fn public_key(&mut self) -> Result<PublicKey, PacketError> {
+        Ok(PublicKey::from_bytes(self.take(32, "public key")?.try_into().unwrap()))
+    }
+    fn rest(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data).to_vec()
+    }
+    fn encrypted(&mut self) -> Result<Encrypted, PacketError> {
+        Ok(Encrypted {
+            dest_hash: self.u8("destination hash")?,
+            src_hash: self.u8("source hash")?,
+            mac: self.u16("MAC")?,
+            ciphertext: self.rest(),
+        })
+    }
+    fn group_encrypted(&mut self) -> Result<GroupEncrypted, PacketError> {
+        Ok(GroupEncrypted {
+            channel_hash: self.u8("channel hash")?,
+            mac: self.u16("MAC")?,
+            ciphertext: self.rest(),
+        })
+    }
+    fn advert(&mut self) -> Result<Advert, PacketError> {
+        let public_key = self.public_key()?;
+        let timestamp = self.u32("advert timestamp")?;
+        let signature = self.take(64, "advert signature")?.to_vec();
+        let flags = self.u8("advert flags")?;
+        let location = if flags & ADVERT_HAS_LOCATION != 0 {
+            let lat = self.i32("latitude")?;
+            let lon = self.i32("longitude")?;
+            Some((lat as f64 / 1e6, lon as f64 / 1e6))
+        } else {
+            None
+        };
+        let feature1 = if flags & ADVERT_HAS_FEATURE1 != 0 { Some(self.u16("feature 1")?) } else { None };
+        let feature2 = if flags & ADVERT_HAS_FEATURE2 != 0 { Some(self.u16("feature 2")?) } else { None };
+        let name = if flags & ADVERT_HAS_NAME != 0 {
+            Some(String::from_utf8_lossy(&self.rest()).trim_end_matches('\0').to_string())
+        } else {
+            None
+        };
+        Ok(Advert {
+            public_key,
+            timestamp,
+            signature,
+            node_type: (flags & 0x0f).into(),
+            location,
+            feature1,
+            feature2,
+            name,
+        })
+    }
+}
+
+impl Payload {
+    pub fn decode(payload_type: PayloadType, data: &[u8]) -> Result<Self, PacketError> {
+        let mut reader = Reader { data };
+        Ok(match payload_type {
+            PayloadType::Req => Payload::Req(reader.encrypted()?),
+            PayloadType::Response => Payload::Response(reader.encrypted()?),
+            PayloadType::TxtMsg => Payload::TxtMsg(reader.encrypted()?),
+            PayloadType::Ack => Payload::Ack {
+                checksum: reader.u32("ACK checksum")?,
+            },
+            PayloadType::Advert => Payload::Advert(reader.advert()?),
+            PayloadType::GrpTxt => Payload::GrpTxt(reader.group_encrypted()?),
+            PayloadType::GrpData => Payload::GrpData(reader.group_encrypted()?),
+            PayloadType::AnonReq => Payload::AnonReq {
+                dest_hash: reader.u8("destination hash")?,
+                public_key: reader.public_key()?,
+                mac: reader.u16("MAC")?,
+                ciphertext: reader.rest(),
+            },
+            PayloadType::Path => Payload::Path(reader.encrypted()?),
+            PayloadType::Trace => Payload::Trace {
+                tag: reader.u32("trace tag")?,
+                auth_code: reader.u32("trace auth code")?,
+                flags: reader.u8("trace flags")?,
+                path_hashes: reader.rest(),
+            },
+            PayloadType::Multipart => {
+                let header = reader.u8("multipart header")?;
+                Payload::Multipart {
+                    remaining: header >> 4,
+                    inner_type: (header & 0x0f).into(),
+                    data: reader.rest(),
+                }
+            }
+            PayloadType::RawCustom => Payload::RawCustom(reader.rest()),
+            PayloadType::Unknown(_) => Payload::Unknown(reader.rest()),
+        })
+    }
+}
+
+impl Packet {
+    pub fn decode_payload(&self) -> Result<Payload, PacketError> {
+        Payload::decode(self.payload_type, &self.payload)
+    }
+}
+
+/// A packet the radio heard, published as `CompanionEvent::RxLog`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RxLogEvent {
+    pub snr_db: f32,
+    pub rssi: i8,
+    pub raw: Vec<u8>,
+    pub received_at: SystemTime,
+    pub packet: Result<Packet, PacketError>,
+    pub payload: Result<Payload, PacketError>,
+}
+
+impl RxLogEvent {
+    pub fn new(snr_db: f32, rssi: i8, raw: Vec<u8>) -> Self {
+        let packet = Packet::parse(&raw);
+        let payload = packet.as_ref().map_err(Clone::clone).and_then(Packet::decode_payload);
+        Self {
+            snr_db,
+            rssi,
+            raw,
+            received_at: SystemTime::now(),
+            packet,
+            payload,
+        }
+    }
+}
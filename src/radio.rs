@@ -0,0 +1,111 @@
+use crate::commands::RadioParameters;
+use thiserror::Error;
+
+// Bounds enforced by the firmware when handling CMD_SET_RADIO_PARAMS
+pub const MIN_FREQ_KHZ: u32 = 300_000;
+pub const MAX_FREQ_KHZ: u32 = 2_500_000;
+pub const MIN_BW_HZ: u32 = 7_000;
+pub const MAX_BW_HZ: u32 = 500_000;
+pub const MIN_SF: u8 = 5;
+pub const MAX_SF: u8 = 12;
+pub const MIN_CR: u8 = 5;
+pub const MAX_CR: u8 = 8;
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum RadioParamsError {
+    #[error("Frequency {0} kHz is outside {MIN_FREQ_KHZ}-{MAX_FREQ_KHZ} kHz")]
+    Frequency(u32),
+    #[error("Bandwidth {0} Hz is outside {MIN_BW_HZ}-{MAX_BW_HZ} Hz")]
+    Bandwidth(u32),
+    #[error("Spreading factor {0} is outside {MIN_SF}-{MAX_SF}")]
+    SpreadingFactor(u8),
+    #[error("Coding rate 4/{0} is outside 4/{MIN_CR}-4/{MAX_CR}")]
+    CodingRate(u8),
+    #[error("Radio still reports {actual}, expected {expected}")]
+    NotApplied {
+        expected: RadioParameters,
+        actual: RadioParameters,
+    },
+}
+
+/// Community radio settings, as offered by the MeshCore apps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RadioPreset {
+    Usa,
+    EuUkNarrow,
+    EuUkLongRange,
+    EuUkMediumRange,
+    Australia,
+    AustraliaNarrow,
+    NewZealand,
+    NewZealandNarrow,
+    CzechNarrow,
+    Portugal433,
+    Vietnam,
+}
+
+impl RadioPreset {
+    pub const ALL: [RadioPreset; 11] = [
+        RadioPreset::Usa,
+        RadioPreset::EuUkNarrow,
+        RadioPreset::EuUkLongRange,
+        RadioPreset::EuUkMediumRange,
+        RadioPreset::Australia,
+        RadioPreset::AustraliaNarrow,
+        RadioPreset::NewZealand,
+        RadioPreset::NewZealandNarrow,
+        RadioPreset::CzechNarrow,
+        RadioPreset::Portugal433,
+        RadioPreset::Vietnam,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RadioPreset::Usa => "USA/Canada (Recommended)",
+            RadioPreset::EuUkNarrow => "EU/UK (Narrow)",
+            RadioPreset::EuUkLongRange => "EU/UK (Long Range)",
+            RadioPreset::EuUkMediumRange => "EU/UK (Medium Range)",
+            RadioPreset::Australia => "Australia",
+            RadioPreset::AustraliaNarrow => "Australia/NZ (Narrow)",
+            RadioPreset::NewZealand => "New Zealand",
+            RadioPreset::NewZealandNarrow => "New Zealand (Narrow)",
+            RadioPreset::CzechNarrow => "Czech Republic (Narrow)",
+            RadioPreset::Portugal433 => "Portugal 433",
+            RadioPreset::Vietnam => "Vietnam",
+        }
+    }
+
+    /// (frequency kHz, bandwidth Hz, spreading factor, coding rate)
+    fn settings(&self) -> (u32, u32, u8, u8) {
+        match self {
+            RadioPreset::Usa => (910_525, 62_500, 7, 5),
+            RadioPreset::EuUkNarrow => (869_618, 62_500, 8, 8),
+            RadioPreset::EuUkLongRange => (869_525, 250_000, 11, 5),
+            RadioPreset::EuUkMediumRange => (869_525, 250_000, 10, 5),
+            RadioPreset::Australia => (915_800, 250_000, 10, 5),
+            RadioPreset::AustraliaNarrow => (916_575, 62_500, 7, 8),
+            RadioPreset::NewZealand => (917_375, 250_000, 11, 5),
+            RadioPreset::NewZealandNarrow => (917_375, 62_500, 7, 5),
+            RadioPreset::CzechNarrow => (869_432, 62_500, 7, 5),
+            RadioPreset::Portugal433 => (433_375, 62_500, 9, 6),
+            RadioPreset::Vietnam => (920_250, 250_000, 11, 5),
+        }
+    }
+
+    pub fn parameters(&self) -> RadioParameters {
+        let (freq, bw, sf, cr) = self.settings();
+        RadioParameters::new(freq, bw, sf, cr).expect("presets are within firmware bounds")
+    }
+
+    /// Looks a preset up by its display name, ignoring case.
+    pub fn from_name(name: &str) -> Option<RadioPreset> {
+        RadioPreset::ALL
+            .into_iter()
+            .find(|p| p.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The preset whose settings match, if any.
+    pub fn matching(params: &RadioParameters) -> Option<RadioPreset> {
+        RadioPreset::ALL.into_iter().find(|p| p.parameters().same_settings(params))
+    }
+}
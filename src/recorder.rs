@@ -0,0 +1,150 @@
+use crate::history::Direction;
+use crate::serial_actor::{ConnectionState, SerialFrame};
+use crate::AppError;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+// How long lockstep replay waits for the companion to send the next recorded command
+const LOCKSTEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One line of a session recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    /// Milliseconds since the recording started
+    pub at_ms: u64,
+    pub direction: Direction,
+    /// Frame payload without the delimiter and length, hex encoded in the file
+    #[serde(with = "crate::serde_hex")]
+    pub frame: Vec<u8>,
+}
+
+/// Appends every frame crossing the serial port to a JSON lines file.
+#[derive(Debug, Clone)]
+pub struct SessionRecorder {
+    inner: Arc<Mutex<RecorderInner>>,
+}
+
+#[derive(Debug)]
+struct RecorderInner {
+    writer: BufWriter<File>,
+    started: Instant,
+}
+
+impl SessionRecorder {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, AppError> {
+        let file = File::create(path.as_ref()).map_err(|e| AppError::Recording(e.to_string()))?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(RecorderInner {
+                writer: BufWriter::new(file),
+                started: Instant::now(),
+            })),
+        })
+    }
+
+    pub(crate) fn record(&self, direction: Direction, frame: &SerialFrame) {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = RecordedFrame {
+            at_ms: inner.started.elapsed().as_millis() as u64,
+            direction,
+            frame: frame.frame.clone(),
+        };
+        let line = serde_json::to_string(&entry).expect("recorded frames always serialize");
+        // Flushed per frame so a crash still leaves a usable recording
+        if let Err(e) = writeln!(inner.writer, "{line}").and_then(|_| inner.writer.flush()) {
+            error!("Failed to record serial frame: {e}");
+        }
+    }
+}
+
+pub fn read_recording(path: impl AsRef<Path>) -> Result<Vec<RecordedFrame>, AppError> {
+    let reader = BufReader::new(File::open(path.as_ref()).map_err(|e| AppError::Recording(e.to_string()))?);
+    let mut frames = vec![];
+    for (lineno, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| AppError::Recording(e.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let frame = serde_json::from_str(&line)
+            .map_err(|e| AppError::Recording(format!("line {}: {e}", lineno + 1)))?;
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayPacing {
+    /// Deliver every inbound frame straight away
+    Immediate,
+    /// Keep the recorded gaps between inbound frames
+    Realtime,
+    /// Hold inbound frames back until the companion has sent the commands recorded before them
+    Lockstep,
+}
+
+/// Stands in for the serial port, feeding a recording back into a `Companion`.
+#[derive(Debug, Clone)]
+pub struct ReplayTransport {
+    frames: Vec<RecordedFrame>,
+    pacing: ReplayPacing,
+}
+
+impl ReplayTransport {
+    pub fn new(frames: Vec<RecordedFrame>, pacing: ReplayPacing) -> Self {
+        Self { frames, pacing }
+    }
+    pub fn from_file(path: impl AsRef<Path>, pacing: ReplayPacing) -> Result<Self, AppError> {
+        Ok(Self::new(read_recording(path)?, pacing))
+    }
+
+    pub(crate) async fn run(
+        self,
+        to_radio: &mut mpsc::Receiver<SerialFrame>,
+        from_radio: &mpsc::Sender<SerialFrame>,
+        connection: &watch::Sender<ConnectionState>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        connection.send_replace(ConnectionState::Connected);
+        let started = Instant::now();
+        let replay = async {
+            for recorded in self.frames {
+                match (recorded.direction, self.pacing) {
+                    (Direction::Inbound, ReplayPacing::Realtime) => {
+                        let due = started + Duration::from_millis(recorded.at_ms);
+                        tokio::time::sleep_until(due.into()).await;
+                    }
+                    (Direction::Outbound, ReplayPacing::Lockstep) => {
+                        match tokio::time::timeout(LOCKSTEP_TIMEOUT, to_radio.recv()).await {
+                            Ok(Some(sent)) if sent.frame != recorded.frame => {
+                                warn!("Replay diverged: recorded {:02x?}, companion sent {:02x?}", recorded.frame, sent.frame);
+                            }
+                            Ok(Some(_)) => (),
+                            Ok(None) => return,
+                            Err(_) => warn!("Replay timed out waiting for the companion to send {:02x?}", recorded.frame),
+                        }
+                        continue;
+                    }
+                    (Direction::Outbound, _) => continue,
+                    _ => (),
+                }
+                if from_radio.send(SerialFrame::from_data(recorded.frame)).await.is_err() {
+                    return;
+                }
+            }
+            info!("Replay finished.");
+            // Keep swallowing commands like an idle radio would
+            while let Some(frame) = to_radio.recv().await {
+                debug!("Replay dropping outbound frame {:02x?}", frame.frame);
+            }
+        };
+        tokio::select! {
+            _ = replay => (),
+            _ = shutdown.wait_for(|stop| *stop) => (),
+        }
+        connection.send_replace(ConnectionState::Closed);
+    }
+}
@@ -1,13 +1,23 @@
 use std::fmt;
 use std::io::{Cursor, Read};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
-use crate::{consts, AppError, Commands, CompanionState, HexData, InferredAdvert, MessageTypes};
-use crate::commands::{send_command, GetContacts, MessageEnvelope, SendingMessageTypes};
+use crate::{consts, AppError, Commands, CompanionEvent, CompanionState, HexData, InferredAdvert, MessageTypes};
+use crate::airtime::{grp_txt_packet_len, txt_msg_packet_len};
+use crate::commands::{budget_wait, outbound_airtime, send_channel_txt_msg, send_command, send_txt_msg, transmit_channel_msg, transmit_txt_msg, GetContacts, MessageEnvelope, RadioParameters, SendChannelTxtMsg, SendTxtMsg, SendingMessageTypes};
+use crate::clock::DeviceClock;
+use crate::health::HealthReading;
+use crate::delivery::{ChannelDeliveryReport, DeliveryOutcome, DeliveryReport, RouteType};
+use crate::packet::{Packet, Payload, PayloadType, RxLogEvent};
+use crate::topology::TraceResult;
+use crate::geo::Position;
 use crate::commands::SendingMessageTypes::TxtMsg;
 use crate::consts::CMD_GET_CONTACTS;
 use crate::contact_mgmt::{Contact, PublicKey};
+use crate::history::{ConversationKey, DeliveryStatus, Direction};
+use crate::inbox::InboxOutcome;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Debug)]
 pub enum Responses {
@@ -29,10 +39,10 @@ pub enum Responses {
     AdvertPath,
     Stats,
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SelfInfo {
     code: u8,
-    r#type: u8,
+    pub(crate) r#type: u8,
     tx_power_dbm: u8,
     max_tx_power: u8,
     pub(crate) public_key: PublicKey,
@@ -46,9 +56,33 @@ pub struct SelfInfo {
     radio_bw: u32,
     radio_sf: u8,
     radio_cr: u8,
-    name: String,
+    pub(crate) name: String,
 }
 impl SelfInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+    pub fn tx_power_dbm(&self) -> u8 {
+        self.tx_power_dbm
+    }
+    pub fn max_tx_power(&self) -> u8 {
+        self.max_tx_power
+    }
+    pub fn position(&self) -> Option<Position> {
+        Position::from_micro(self.adv_lat, self.adv_lon)
+    }
+    pub fn radio_parameters(&self) -> RadioParameters {
+        RadioParameters {
+            code: consts::CMD_SET_RADIO_PARAMS,
+            radio_freq: self.radio_freq,
+            radio_bw: self.radio_bw,
+            radio_sf: self.radio_sf,
+            radio_cr: self.radio_cr,
+        }
+    }
     pub fn from_frame(frame: &Vec<u8>) -> Self {
         let mut cursor = Cursor::new(frame);
         let mut code = [0u8; 1];
@@ -107,17 +141,22 @@ impl SelfInfo {
 #[derive(Debug)]
 pub struct DeviceInfo {
     code: u8,
-    firmware_version: u8,
-    max_contacts_div_2: u8,
-    max_channels: u8,
+    pub(crate) firmware_version: u8,
+    pub(crate) max_contacts_div_2: u8,
+    pub(crate) max_channels: u8,
     ble_pin: u32,
-    firmware_build_date: String,
-    manufacturer_model: String,
-    semantic_version: String,
+    pub(crate) firmware_build_date: String,
+    pub(crate) manufacturer_model: String,
+    pub(crate) semantic_version: String,
 }
 
 impl DeviceInfo {
-    pub fn from_frame(frame: &Vec<u8>) -> Self {
+    pub fn from_frame(frame: &[u8]) -> Self {
+        // Older firmware only reports the version, leave the rest zeroed
+        let mut frame = frame.to_vec();
+        if frame.len() < 80 {
+            frame.resize(80, 0);
+        }
         let mut cursor = Cursor::new(frame);
         let mut code = [0u8; 1];
         cursor.read_exact(&mut code).unwrap();
@@ -154,8 +193,13 @@ impl DeviceInfo {
         }
     }
 }
-#[derive(Clone)]
-pub struct PubkeyPrefix([u8;6]);
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PubkeyPrefix(pub(crate) [u8;6]);
+impl PubkeyPrefix {
+    pub fn bytes(&self) -> [u8; 6] {
+        self.0
+    }
+}
 impl fmt::Display for PubkeyPrefix {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for byte in &self.0 {
@@ -164,6 +208,16 @@ impl fmt::Display for PubkeyPrefix {
         Ok(())
     }
 }
+impl Serialize for PubkeyPrefix {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::serde_hex::serialize(&self.0, serializer)
+    }
+}
+impl<'de> Deserialize<'de> for PubkeyPrefix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self(crate::serde_hex::deserialize(deserializer)?))
+    }
+}
 impl fmt::Debug for PubkeyPrefix {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for byte in &self.0 {
@@ -172,7 +226,7 @@ impl fmt::Debug for PubkeyPrefix {
         Ok(())
     }
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct ContactMsg {
     code: u8,
     pub pubkey_prefix: PubkeyPrefix,
@@ -211,7 +265,7 @@ impl ContactMsg {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct ContactMsgV3 {
     code: u8,
     snr: u8,
@@ -256,7 +310,7 @@ impl ContactMsgV3 {
         }
     }
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct ChannelMsg {
     code: u8,
     pub channel_id: u8,
@@ -294,7 +348,7 @@ impl ChannelMsg {
         }
     }
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct ChannelMsgV3 {
     code: u8,
     snr: u8,
@@ -340,6 +394,161 @@ impl ChannelMsgV3 {
         }
     }
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextKind {
+    Plain,
+    CliData,
+    SignedPlain,
+    Other(u8),
+}
+impl From<u8> for TextKind {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => TextKind::Plain,
+            1 => TextKind::CliData,
+            2 => TextKind::SignedPlain,
+            other => TextKind::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hops {
+    Direct,
+    Flood(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageSource {
+    Contact(PubkeyPrefix),
+    Channel(u8),
+}
+
+/// A received direct or channel message, regardless of which frame version carried it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReceivedMessage {
+    message: MessageTypes,
+    received_at: SystemTime,
+}
+impl ReceivedMessage {
+    pub fn new(message: MessageTypes, received_at: SystemTime) -> Self {
+        Self { message, received_at }
+    }
+    pub fn source(&self) -> MessageSource {
+        match &self.message {
+            MessageTypes::ContactMsg(m) => MessageSource::Contact(m.pubkey_prefix),
+            MessageTypes::ContactMsgV3(m) => MessageSource::Contact(m.pubkey_prefix),
+            MessageTypes::ChannelMsg(m) => MessageSource::Channel(m.channel_id),
+            MessageTypes::ChannelMsgV3(m) => MessageSource::Channel(m.channel_id),
+        }
+    }
+    pub fn pubkey_prefix(&self) -> Option<PubkeyPrefix> {
+        match self.source() {
+            MessageSource::Contact(prefix) => Some(prefix),
+            MessageSource::Channel(_) => None,
+        }
+    }
+    pub fn channel_id(&self) -> Option<u8> {
+        match self.source() {
+            MessageSource::Channel(id) => Some(id),
+            MessageSource::Contact(_) => None,
+        }
+    }
+    pub fn text(&self) -> &str {
+        match &self.message {
+            MessageTypes::ContactMsg(m) => &m.text,
+            MessageTypes::ContactMsgV3(m) => &m.text,
+            MessageTypes::ChannelMsg(m) => &m.text,
+            MessageTypes::ChannelMsgV3(m) => &m.text,
+        }
+    }
+    /// Signal to noise ratio in dB. Only V3 frames carry it.
+    pub fn snr_db(&self) -> Option<f32> {
+        match &self.message {
+            MessageTypes::ContactMsgV3(m) => Some(m.snr as i8 as f32 / 4.0),
+            MessageTypes::ChannelMsgV3(m) => Some(m.snr as i8 as f32 / 4.0),
+            _ => None,
+        }
+    }
+    pub fn path_len(&self) -> u8 {
+        match &self.message {
+            MessageTypes::ContactMsg(m) => m.path_len,
+            MessageTypes::ContactMsgV3(m) => m.path_len,
+            MessageTypes::ChannelMsg(m) => m.path_len,
+            MessageTypes::ChannelMsgV3(m) => m.path_len,
+        }
+    }
+    /// The firmware reports 0xff as the path length of directly routed messages.
+    pub fn hops(&self) -> Hops {
+        match self.path_len() {
+            0xff => Hops::Direct,
+            n => Hops::Flood(n),
+        }
+    }
+    pub fn text_kind(&self) -> TextKind {
+        match &self.message {
+            MessageTypes::ContactMsg(m) => m.txt_type.into(),
+            MessageTypes::ContactMsgV3(m) => m.txt_type.into(),
+            MessageTypes::ChannelMsg(m) => m.txt_type.into(),
+            MessageTypes::ChannelMsgV3(m) => m.txt_type.into(),
+        }
+    }
+    pub fn sender_timestamp(&self) -> u32 {
+        match &self.message {
+            MessageTypes::ContactMsg(m) => m.sender_timestamp,
+            MessageTypes::ContactMsgV3(m) => m.sender_timestamp,
+            MessageTypes::ChannelMsg(m) => m.sender_timestamp,
+            MessageTypes::ChannelMsgV3(m) => m.sender_timestamp,
+        }
+    }
+    /// The sender's clock when the message was sent, as reported by the sender.
+    pub fn sender_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.sender_timestamp() as u64)
+    }
+    /// Local time the frame arrived from the radio.
+    pub fn received_at(&self) -> SystemTime {
+        self.received_at
+    }
+    pub fn resolve_contact<'a>(&self, contacts: &'a [Contact]) -> Option<&'a Contact> {
+        let prefix = self.pubkey_prefix()?;
+        contacts.iter().find(|c| c.public_key.prefix_bytes() == prefix.0)
+    }
+    /// Flat JSON for bridges and HTTP clients, `sender` names the contact of a direct message.
+    pub fn summary_json(&self, sender: Option<&str>) -> serde_json::Value {
+        let received_at = self.received_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut body = serde_json::json!({
+            "text": self.text(),
+            "snr_db": self.snr_db(),
+            "path_len": self.path_len(),
+            "sender_timestamp": self.sender_timestamp(),
+            "received_at": received_at,
+        });
+        match self.source() {
+            MessageSource::Contact(prefix) => {
+                body["pubkey_prefix"] = serde_json::json!(prefix);
+                body["from"] = serde_json::json!(sender);
+            }
+            MessageSource::Channel(channel) => body["channel"] = serde_json::json!(channel),
+        }
+        body
+    }
+    pub fn message(&self) -> &MessageTypes {
+        &self.message
+    }
+    pub fn into_message(self) -> MessageTypes {
+        self.message
+    }
+    pub(crate) fn with_text(mut self, text: String) -> Self {
+        match &mut self.message {
+            MessageTypes::ContactMsg(m) => m.text = text,
+            MessageTypes::ContactMsgV3(m) => m.text = text,
+            MessageTypes::ChannelMsg(m) => m.text = text,
+            MessageTypes::ChannelMsgV3(m) => m.text = text,
+        }
+        self
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Confirmation {
     code: u8,
@@ -443,6 +652,72 @@ impl LoginSuccess {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct LogRxData {
+    code: u8,
+    snr: i8,
+    pub rssi: i8,
+    pub raw: Vec<u8>,
+}
+impl LogRxData {
+    pub fn from_frame(frame: &[u8]) -> Result<Self, AppError> {
+        let [code, snr, rssi, raw @ ..] = frame else {
+            return Err(AppError::Misc("Log rx data frame too short".to_string()));
+        };
+        Ok(Self {
+            code: *code,
+            snr: *snr as i8,
+            rssi: *rssi as i8,
+            raw: raw.to_vec(),
+        })
+    }
+    /// The firmware sends SNR as a signed byte in quarter dB steps.
+    pub fn snr_db(&self) -> f32 {
+        self.snr as f32 / 4.0
+    }
+}
+
+/// `PUSH_CODE_TRACE_DATA`, the result of a `CmdSendTracePath`.
+pub struct TraceData;
+impl TraceData {
+    pub fn from_frame(frame: &[u8]) -> Result<TraceResult, AppError> {
+        let header = frame.get(..12).ok_or_else(|| AppError::Misc("Trace data frame too short".to_string()))?;
+        let path_len = header[2] as usize;
+        let tag = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let rest = &frame[12..];
+        if rest.len() < path_len * 2 + 1 {
+            return Err(AppError::Misc("Trace data frame too short".to_string()));
+        }
+        let snr = |b: u8| b as i8 as f32 / 4.0;
+        Ok(TraceResult {
+            tag,
+            path: rest[..path_len].to_vec(),
+            hop_snrs: rest[path_len..path_len * 2].iter().map(|b| snr(*b)).collect(),
+            final_snr: snr(rest[path_len * 2]),
+        })
+    }
+}
+
+/// `RESP_CODE_ADVERT_PATH`, the path of the last advert heard from a contact.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdvertPath {
+    pub recv_timestamp: u32,
+    pub path: Vec<u8>,
+}
+impl AdvertPath {
+    pub fn from_frame(frame: &[u8]) -> Result<Self, AppError> {
+        let header = frame.get(..6).ok_or_else(|| AppError::Misc("Advert path frame too short".to_string()))?;
+        let path_len = header[5] as usize;
+        let path = frame
+            .get(6..6 + path_len)
+            .ok_or_else(|| AppError::Misc("Advert path frame too short".to_string()))?;
+        Ok(Self {
+            recv_timestamp: u32::from_le_bytes(header[1..5].try_into().unwrap()),
+            path: path.to_vec(),
+        })
+    }
+}
+
 #[derive(Debug,Clone)]
 pub struct BattAndStorage {
     code: u8,
@@ -470,7 +745,7 @@ impl BattAndStorage {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct TuningParameters {
     pub(crate) code: u8,
     pub rxdelay_base: u32,
@@ -558,9 +833,17 @@ pub(crate) async fn check_internal(state: Arc<RwLock<CompanionState>>) -> Result
                 let confirmation = Confirmation::from_frame(&frame);
                 {
                     let mut state = state.write().await;
-                    if state.pending_acks.contains_key(&confirmation.ack_code) {
+                    if let Some(envelope) = state.pending_acks.remove(&confirmation.ack_code) {
                         info!("Received send confirmation: {confirmation:?}");
-                        state.pending_acks.remove(&confirmation.ack_code);
+                        if let TxtMsg(msg) = &envelope.msg {
+                            record_outbound_status(&mut state, msg, DeliveryStatus::Delivered);
+                            if envelope.flood_fallback {
+                                info!("Flood fallback delivered message {msg:?}");
+                            }
+                            let report = delivery_report(&envelope, msg, DeliveryOutcome::Delivered, Some(confirmation.round_trip));
+                            state.publish(CompanionEvent::Delivery(report.clone()));
+                            state.delivery_reports.push_back(report);
+                        }
                     } else {
                         warn!("Received send confirmation for unknown ack code: {confirmation:?}");
                     }
@@ -587,14 +870,83 @@ pub(crate) async fn check_internal(state: Arc<RwLock<CompanionState>>) -> Result
                 let _ = send_command(&state, Commands::CmdGetContacts(get_contacts)).await;
             }
             consts::PUSH_CODE_LOG_RX_DATA => {
-                let snr = i32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]);
-                let rssi = frame[5];
-                let data = frame[6..].to_vec();
-                debug!("Received log rx data: snr: {snr}, rssi: {rssi}, data: {data:?}");
+                let rx = match LogRxData::from_frame(&frame) {
+                    Ok(rx) => rx,
+                    Err(e) => {
+                        warn!("{e}: {frame:02x?}");
+                        continue;
+                    }
+                };
+                debug!("Received log rx data: snr: {}, rssi: {}, data: {:02x?}", rx.snr_db(), rx.rssi, rx.raw);
+                let event = RxLogEvent::new(rx.snr_db(), rx.rssi, rx.raw);
+                let mut lock = state.write().await;
+                match &event.packet {
+                    Ok(packet) => {
+                        match_channel_repeat(&mut lock, packet, event.payload.as_ref().ok());
+                        lock.topology.add_packet(packet, event.payload.as_ref().ok(), event.snr_db);
+                    }
+                    Err(e) => debug!("Could not parse rx log packet: {e}"),
+                }
+                lock.publish(CompanionEvent::RxLog(event));
+            }
+            consts::PUSH_CODE_TRACE_DATA => {
+                let trace = match TraceData::from_frame(&frame) {
+                    Ok(trace) => trace,
+                    Err(e) => {
+                        warn!("{e}: {frame:02x?}");
+                        continue;
+                    }
+                };
+                info!("Received trace {} through {:02x?}, final SNR {}", trace.tag, trace.path, trace.final_snr);
+                let mut lock = state.write().await;
+                lock.topology.add_trace(&trace);
+                lock.trace_results.push_back(trace);
+            }
+            consts::RESP_CODE_ADVERT_PATH => {
+                let advert_path = match AdvertPath::from_frame(&frame) {
+                    Ok(advert_path) => advert_path,
+                    Err(e) => {
+                        warn!("{e}: {frame:02x?}");
+                        continue;
+                    }
+                };
+                let mut lock = state.write().await;
+                match lock.command_queue.pop_front() {
+                    Some(Commands::CmdGetAdvertPath(public_key)) => {
+                        debug!("Advert path for {public_key}: {:02x?}", advert_path.path);
+                        lock.topology.add_advert_path(&public_key, &advert_path.path, None);
+                        lock.result_queue.push_back(Ok(Commands::CmdGetAdvertPath(public_key)));
+                    }
+                    other => error!("Received advert path, but the pending command was {other:?}"),
+                }
             }
             consts::RESP_CODE_CURR_TIME => {
                 let curr_time = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]);
-                info!("Received radio's current time: {curr_time}");
+                let clock = DeviceClock::new(curr_time);
+                info!("Received radio's current time: {curr_time}, drift {}s", clock.drift_secs());
+                let resync = {
+                    let mut lock = state.write().await;
+                    lock.device_clock = Some(clock);
+                    // Firmware refuses to move its clock backwards, so don't retry more than once per interval
+                    let config = lock.clock_sync.clone();
+                    let due = lock.last_clock_resync.is_none_or(|at| at.elapsed() >= config.check_interval);
+                    if config.auto_sync && clock.exceeds(config.max_drift) && due {
+                        lock.last_clock_resync = Some(Instant::now());
+                        true
+                    } else {
+                        false
+                    }
+                };
+                if resync {
+                    warn!("Device clock is off by {}s, setting it to host time.", clock.drift_secs());
+                    // an error here must not drop the frames still waiting behind this one
+                    for cmd in [Commands::CmdSetDeviceTime, Commands::CmdGetDeviceTime] {
+                        if let Err(e) = send_command(&state, cmd).await {
+                            error!("Failed to resync the device clock: {e}");
+                            break;
+                        }
+                    }
+                }
             }
             consts::RESP_CODE_OK => {
                 {
@@ -641,15 +993,21 @@ pub(crate) async fn check_internal(state: Arc<RwLock<CompanionState>>) -> Result
                 let suggested_timeout = frame[6..10].try_into().map(u32::from_le_bytes).unwrap();
                 {
                     let mut state = state.write().await;
-                    if let Some(msg) = state.pending_msgs.pop() {
-                        let mut msg_timeout = msg.clone();
-                        msg_timeout.timeout = Some(suggested_timeout);
-                        let envelope = MessageEnvelope {
-                            msg: TxtMsg(msg_timeout),
-                            last_attempt_timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+                    if let Some(mut envelope) = state.pending_msgs.pop() {
+                        if let TxtMsg(msg) = &mut envelope.msg {
+                            msg.timeout = Some(suggested_timeout);
+                        }
+                        envelope.last_attempt_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+                        envelope.route = match tx_type {
+                            0 => Some(RouteType::Flood),
+                            1 => Some(RouteType::Direct),
+                            _ => None,
                         };
+                        if let TxtMsg(msg) = &envelope.msg {
+                            record_outbound_status(&mut state, msg, DeliveryStatus::Sent);
+                        }
+                        info!("Assigning {exp_ack} ack code for msg {:?}", envelope.msg);
                         state.pending_acks.insert(exp_ack.clone(), envelope);
-                        info!("Assigning {exp_ack} ack code for msg {msg:?}");
                     } else {
                         info!("Received ack for message we aren't tracking.  Maybe a login.");
                     }
@@ -689,51 +1047,55 @@ pub(crate) async fn check_internal(state: Arc<RwLock<CompanionState>>) -> Result
             consts::RESP_CODE_CONTACT => {
                 let contact = Contact::from_frame(&frame);
                 debug!("Received contact: {contact:?}");
-                state.write().await.contacts.push(contact);
+                let mut lock = state.write().await;
+                lock.topology.add_contact_path(&contact);
+                lock.contacts.push(contact);
             }
             consts::RESP_CODE_END_OF_CONTACTS => {
                 let last_modified = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]);
                 info!("Received end of contacts, newest advert time: {last_modified}");
-                state.write().await.newest_advert_time = last_modified;
+                let mut state = state.write().await;
+                state.newest_advert_time = last_modified;
+                state.contacts_synced = true;
             }
             consts::RESP_CODE_CONTACT_MSG_RECV => {
                 let contact_msg = ContactMsg::from_frame(&frame);
                 debug!("Received contact message: {contact_msg:?}");
-                state
-                    .write()
-                    .await
-                    .pending_messages
-                    .push(MessageTypes::ContactMsg(contact_msg));
+                {
+                    let mut lock = state.write().await;
+                    let msg = MessageTypes::ContactMsg(contact_msg);
+                    accept_inbound(&mut lock, msg);
+                }
                 let _ = send_command(&state, Commands::CmdSyncNextMessage).await;
             }
             consts::RESP_CODE_CONTACT_MSG_RECV_V3 => {
                 let contact_msg = ContactMsgV3::from_frame(&frame);
                 debug!("Received channel message: {contact_msg:?}");
-                state
-                    .write()
-                    .await
-                    .pending_messages
-                    .push(MessageTypes::ContactMsgV3(contact_msg));
+                {
+                    let mut lock = state.write().await;
+                    let msg = MessageTypes::ContactMsgV3(contact_msg);
+                    accept_inbound(&mut lock, msg);
+                }
                 let _ = send_command(&state, Commands::CmdSyncNextMessage).await;
             }
             consts::RESP_CODE_CHANNEL_MSG_RECV => {
                 let msg = ChannelMsg::from_frame(&frame);
                 debug!("Received channel message: {msg:?}");
-                state
-                    .write()
-                    .await
-                    .pending_messages
-                    .push(MessageTypes::ChannelMsg(msg));
+                {
+                    let mut lock = state.write().await;
+                    let msg = MessageTypes::ChannelMsg(msg);
+                    accept_inbound(&mut lock, msg);
+                }
                 let _ = send_command(&state, Commands::CmdSyncNextMessage).await;
             }
             consts::RESP_CODE_CHANNEL_MSG_RECV_V3 => {
                 let msg = ChannelMsgV3::from_frame(&frame);
                 debug!("Received channel message: {msg:?}");
-                state
-                    .write()
-                    .await
-                    .pending_messages
-                    .push(MessageTypes::ChannelMsgV3(msg));
+                {
+                    let mut lock = state.write().await;
+                    let msg = MessageTypes::ChannelMsgV3(msg);
+                    accept_inbound(&mut lock, msg);
+                }
                 let _ = send_command(&state, Commands::CmdSyncNextMessage).await;
             }
             consts::RESP_CODE_NO_MORE_MESSAGES => {
@@ -743,9 +1105,10 @@ pub(crate) async fn check_internal(state: Arc<RwLock<CompanionState>>) -> Result
                 let msg = BattAndStorage::from_frame(&frame);
                 {
                     let mut lock = state.write().await;
-                    lock.battery_millivolts = Some(msg.milli_volts.clone());
-                    lock.storage_kb = Some(msg.total_kb.clone());
-                    lock.storage_used_kb = Some(msg.used_kb.clone());
+                    let reading = HealthReading::new(msg.milli_volts, msg.used_kb, msg.total_kb);
+                    for event in lock.health.record(reading) {
+                        lock.publish(event);
+                    }
                 }
                 debug!("Received battery and storage info: {msg:#?}");
             }
@@ -760,45 +1123,424 @@ pub(crate) async fn check_internal(state: Arc<RwLock<CompanionState>>) -> Result
     //endregion
 
     //region check for messages that require re-delivery attempts
-    let mut pending_sends = vec![];
+    // one resend per pass, as the firmware only tracks a single message awaiting its ack code
+    let overdue = {
+        let mut lock = state.write().await;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let policy = lock.delivery_policy.clone();
+        let overdue_ack = lock
+            .pending_acks
+            .iter()
+            .find(|(_, envelope)| match &envelope.msg {
+                TxtMsg(msg) => {
+                    let suggested = msg.timeout.unwrap_or(consts::DEFAULT_ACK_TIMEOUT_MS);
+                    now.saturating_sub(envelope.last_attempt_timestamp) > policy.ack_timeout_ms(suggested, msg.attempt)
+                }
+                SendingMessageTypes::ChannelMsg(_) => false,
+            })
+            .map(|(ack_code, _)| ack_code.clone());
+        match overdue_ack {
+            Some(ack_code) if lock.pending_msgs.is_empty() => {
+                // a message that has run out of attempts only needs its failure reported
+                let resend_len = lock.pending_acks.get(&ack_code).and_then(|envelope| match &envelope.msg {
+                    TxtMsg(msg)
+                        if msg.attempt + 1 < policy.max_attempts
+                            || (policy.flood_fallback && !envelope.flood_fallback && envelope.route == Some(RouteType::Direct)) =>
+                    {
+                        Some(txt_msg_packet_len(msg.text.len()))
+                    }
+                    _ => None,
+                });
+                let fit = match resend_len {
+                    Some(len) => {
+                        let airtime = outbound_airtime(&lock, len);
+                        budget_fit(&mut lock, airtime, Duration::ZERO)
+                    }
+                    None => BudgetFit::Now,
+                };
+                if fit == BudgetFit::Later {
+                    debug!("Deferring resend of {ack_code:?} until the duty cycle budget has room");
+                    None
+                } else {
+                    lock.pending_acks.remove(&ack_code).map(|e| (ack_code, e, policy, fit == BudgetFit::Now))
+                }
+            }
+            _ => None,
+        }
+    };
+    if let Some((ack_code, mut envelope, policy, fits)) = overdue
+        && let TxtMsg(mut msg) = envelope.msg.clone()
     {
-        let mut lock = state.read().await;
-        for (k, v) in lock.pending_acks.iter() {
-            pending_sends.push((k.clone(), v.clone()));
+        let original = envelope.clone();
+        if envelope.route == Some(RouteType::Direct) {
+            envelope.direct_failures += 1;
+        }
+        let mut resend = fits && msg.attempt + 1 < policy.max_attempts;
+        if fits
+            && policy.flood_fallback
+            && !envelope.flood_fallback
+            && envelope.route == Some(RouteType::Direct)
+            && envelope.direct_failures >= policy.direct_failures_before_flood
+        {
+            let contact = state
+                .read()
+                .await
+                .contacts
+                .iter()
+                .find(|c| c.public_key.prefix_bytes() == msg.pubkey_prefix)
+                .map(|c| c.public_key);
+            if let Some(public_key) = contact {
+                warn!("Direct route to {public_key} failed {} times, resetting path and falling back to flood.", envelope.direct_failures);
+                send_command(&state, Commands::CmdResetPath(public_key)).await?;
+                envelope.flood_fallback = true;
+                resend = true;
+            } else {
+                warn!("Cannot fall back to flood for {msg:?}, contact is unknown.");
+            }
+        }
+        if resend {
+            msg.attempt += 1;
+            info!("Resending message {msg:?}");
+            envelope.msg = TxtMsg(msg);
+            if let Err(e) = transmit_txt_msg(&state, envelope).await {
+                warn!("Deferring resend: {e}");
+                state.write().await.pending_acks.insert(ack_code, original);
+            }
+        } else {
+            if fits {
+                warn!("Message {msg:?}, failed to receive ack after {} attempts.", msg.attempt + 1);
+            } else {
+                warn!("Message {msg:?} can never fit the duty cycle allowance, giving up.");
+            }
+            report_txt_failed(&mut *state.write().await, &envelope, &msg);
         }
     }
-    for (ack_code, _) in pending_sends {
+    //endregion
+
+    //region confirm or resend tracked channel messages
+    let resends = {
         let mut lock = state.write().await;
-        if let Some(mut envelope) = lock.pending_acks.remove(&ack_code) {
-            match envelope.clone().msg {
-                SendingMessageTypes::TxtMsg(prev_msg) => {
-                    let mut msg = prev_msg;
-                    if msg.attempt > 2 {
-                        warn!("Message {msg:?}, failed to receive ack after 3 attempts.");
-                    } else {
-                        let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-                        if current_time - envelope.last_attempt_timestamp > msg.timeout.unwrap() as u128 {
-                            msg.attempt += 1;
-                            info!("Resending message {msg:?}");
-                            drop(lock);
-                            let _ = send_command(&state, Commands::CmdSendTxtMsg(msg.clone())).await;
-                        } else {
-                            lock.pending_acks.insert(ack_code, envelope);
-                        }
+        let policy = lock.channel_policy.clone();
+        let name_len = lock.self_info.as_ref().map(|i| i.name.len()).unwrap_or(0);
+        let mut resends = vec![];
+        let mut resend_airtime = Duration::ZERO;
+        let mut still_pending = vec![];
+        for mut envelope in std::mem::take(&mut lock.pending_channel_msgs) {
+            if envelope.last_attempt.elapsed() < policy.repeat_window {
+                still_pending.push(envelope);
+                continue;
+            }
+            let outcome = if envelope.repeaters.len() >= policy.min_repeaters.max(1) {
+                info!("Channel message {:?} heard repeated by {} repeaters", envelope.msg, envelope.repeaters.len());
+                DeliveryOutcome::Delivered
+            } else if envelope.attempts <= policy.max_resends {
+                // resends earlier in this sweep have not been recorded against the budget yet
+                let airtime = outbound_airtime(&lock, grp_txt_packet_len(name_len, envelope.msg.text.len()));
+                match budget_fit(&mut lock, airtime, resend_airtime) {
+                    BudgetFit::Now => {
+                        resend_airtime += airtime.unwrap_or_default();
+                        // resending the identical packet lets repeaters that did hear it drop it as already seen
+                        info!("Nobody repeated channel message {:?}, resending", envelope.msg);
+                        envelope.attempts += 1;
+                        envelope.last_attempt = Instant::now();
+                        resends.push(envelope.msg.clone());
+                        still_pending.push(envelope);
+                        continue;
+                    }
+                    BudgetFit::Later => {
+                        debug!("Deferring resend of channel message {:?} until the duty cycle budget has room", envelope.msg);
+                        still_pending.push(envelope);
+                        continue;
+                    }
+                    BudgetFit::Never => {
+                        warn!("Channel message {:?} can never fit the duty cycle allowance, giving up", envelope.msg);
+                        DeliveryOutcome::Failed
                     }
                 }
-                SendingMessageTypes::ChannelMsg(msg) => {
-                    todo!()
+            } else {
+                warn!("Channel message {:?} was not heard repeated after {} attempts", envelope.msg, envelope.attempts);
+                DeliveryOutcome::Failed
+            };
+            report_channel_outcome(&mut lock, &envelope.msg, outcome, envelope.attempts, envelope.repeaters.len());
+        }
+        lock.pending_channel_msgs = still_pending;
+        resends
+    };
+    for msg in resends {
+        if let Err(e) = transmit_channel_msg(&state, msg).await {
+            warn!("Channel resend not sent: {e}");
+        }
+    }
+    //endregion
+
+    //region release message parts that will never be completed
+    {
+        let mut lock = state.write().await;
+        let timeout = lock.multipart.reassembly_timeout;
+        for msg in lock.reassembler.expire(timeout) {
+            deliver_inbound(&mut lock, msg);
+        }
+    }
+    //endregion
+
+    //region poll battery and storage on schedule
+    let poll_health = {
+        let mut lock = state.write().await;
+        lock.self_info.is_some() && lock.health.poll_due()
+    };
+    if poll_health {
+        send_command(&state, Commands::CmdGetBattAndStorage).await?;
+    }
+    //endregion
+
+    //region read the device clock on schedule
+    let check_clock = {
+        let mut lock = state.write().await;
+        let due = lock.last_clock_check.is_none_or(|at| at.elapsed() >= lock.clock_sync.check_interval);
+        // Wait for the handshake before talking to the radio
+        if lock.clock_sync.auto_sync && lock.self_info.is_some() && due {
+            lock.last_clock_check = Some(Instant::now());
+            true
+        } else {
+            false
+        }
+    };
+    if check_clock {
+        send_command(&state, Commands::CmdGetDeviceTime).await?;
+    }
+    //endregion
+
+    //region dispatch queued parts of split messages
+    let next_part = {
+        let mut lock = state.write().await;
+        // Parts wait in the queue rather than holding up the background loop
+        let fit = match lock.outbound_parts.front() {
+            Some(Commands::CmdSendTxtMsg(_)) if !lock.pending_msgs.is_empty() || !lock.pending_acks.is_empty() => BudgetFit::Later,
+            Some(Commands::CmdSendTxtMsg(msg)) => {
+                let airtime = outbound_airtime(&lock, txt_msg_packet_len(msg.text.len()));
+                budget_fit(&mut lock, airtime, Duration::ZERO)
+            }
+            Some(Commands::CmdSendChannelTxtMsg(msg)) => {
+                let name_len = lock.self_info.as_ref().map(|i| i.name.len()).unwrap_or(0);
+                let airtime = outbound_airtime(&lock, grp_txt_packet_len(name_len, msg.text.len()));
+                budget_fit(&mut lock, airtime, Duration::ZERO)
+            }
+            Some(_) => BudgetFit::Now,
+            None => BudgetFit::Later,
+        };
+        match fit {
+            BudgetFit::Now => lock.outbound_parts.pop_front(),
+            BudgetFit::Later => None,
+            BudgetFit::Never => {
+                match lock.outbound_parts.pop_front() {
+                    Some(Commands::CmdSendTxtMsg(msg)) => {
+                        warn!("Message {msg:?} can never fit the duty cycle allowance, giving up.");
+                        report_txt_failed(&mut lock, &MessageEnvelope::new(TxtMsg(msg.clone())), &msg);
+                    }
+                    Some(Commands::CmdSendChannelTxtMsg(msg)) => {
+                        warn!("Channel message {msg:?} can never fit the duty cycle allowance, giving up");
+                        report_channel_outcome(&mut lock, &msg, DeliveryOutcome::Failed, 0, 0);
+                    }
+                    _ => (),
                 }
+                None
             }
-        } else {
-            warn!("msg existed in first sweep but not second, this should not happen.")
         }
+    };
+    match next_part {
+        Some(Commands::CmdSendTxtMsg(msg)) => send_txt_msg(&state, msg).await?,
+        Some(Commands::CmdSendChannelTxtMsg(msg)) => send_channel_txt_msg(&state, msg).await?,
+        Some(cmd) => send_command(&state, cmd).await?,
+        None => (),
     }
     //endregion
 
     Ok(())
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BudgetFit {
+    Now,
+    Later,
+    /// The packet is larger than the whole duty cycle allowance
+    Never,
+}
+/// Whether a packet of the given airtime fits the duty cycle budget on top of airtime already
+/// scheduled. Unknown airtime always fits, as it cannot be checked.
+fn budget_fit(state: &mut CompanionState, airtime: Option<Duration>, scheduled: Duration) -> BudgetFit {
+    let Some(airtime) = airtime else {
+        return BudgetFit::Now;
+    };
+    match budget_wait(state, scheduled + airtime) {
+        Some(wait) if wait.is_zero() => BudgetFit::Now,
+        _ if budget_wait(state, airtime).is_none() => BudgetFit::Never,
+        _ => BudgetFit::Later,
+    }
+}
+fn report_txt_failed(state: &mut CompanionState, envelope: &MessageEnvelope, msg: &SendTxtMsg) {
+    record_outbound_status(state, msg, DeliveryStatus::Failed);
+    let report = delivery_report(envelope, msg, DeliveryOutcome::Failed, None);
+    state.publish(CompanionEvent::Delivery(report.clone()));
+    state.delivery_reports.push_back(report);
+}
+fn report_channel_outcome(state: &mut CompanionState, msg: &SendChannelTxtMsg, outcome: DeliveryOutcome, attempts: u8, repeaters: usize) {
+    let status = match outcome {
+        DeliveryOutcome::Delivered => DeliveryStatus::Delivered,
+        DeliveryOutcome::Failed => DeliveryStatus::Failed,
+    };
+    record_channel_status(state, msg, status);
+    let report = ChannelDeliveryReport {
+        channel_idx: msg.channel_idx,
+        sender_timestamp: msg.sender_timestamp,
+        text: msg.text.clone(),
+        outcome,
+        attempts,
+        repeaters,
+    };
+    state.publish(CompanionEvent::ChannelDelivery(report.clone()));
+    state.channel_reports.push_back(report);
+}
+fn delivery_report(envelope: &MessageEnvelope, msg: &SendTxtMsg, outcome: DeliveryOutcome, round_trip_ms: Option<u32>) -> DeliveryReport {
+    DeliveryReport {
+        pubkey_prefix: msg.pubkey_prefix.into(),
+        sender_timestamp: msg.sender_timestamp,
+        text: msg.text.clone(),
+        outcome,
+        attempts: msg.attempt + 1,
+        flood_fallback: envelope.flood_fallback,
+        round_trip_ms,
+    }
+}
+
+/// Forgets what the radio was working on before it reconnected: queued commands and message parts
+/// are dropped, and messages awaiting an ack code, ack or repeat are reported as failed.
+pub(crate) fn fail_in_flight(state: &mut CompanionState) {
+    state.command_queue.clear();
+    state.outbound_parts.clear();
+    let envelopes: Vec<MessageEnvelope> = state
+        .pending_msgs
+        .drain(..)
+        .chain(state.pending_acks.drain().map(|(_, envelope)| envelope))
+        .collect();
+    for envelope in envelopes {
+        let TxtMsg(msg) = &envelope.msg else {
+            continue;
+        };
+        warn!("Message {msg:?} was in flight when the connection dropped.");
+        report_txt_failed(state, &envelope, msg);
+    }
+    for envelope in std::mem::take(&mut state.pending_channel_msgs) {
+        warn!("Channel message {:?} was in flight when the connection dropped.", envelope.msg);
+        report_channel_outcome(state, &envelope.msg, DeliveryOutcome::Failed, envelope.attempts, envelope.repeaters.len());
+    }
+}
+
+/// Attributes a flood-routed GRP_TXT packet to the oldest tracked channel send it is a repeat of.
+/// The payload is encrypted, so the first repeat is taken on size, channel hash and being heard
+/// straight from the first repeater; every later repeat must carry the same bytes.
+fn match_channel_repeat(state: &mut CompanionState, packet: &Packet, payload: Option<&Payload>) {
+    if packet.payload_type != PayloadType::GrpTxt || !packet.route.is_flood() {
+        return;
+    }
+    let (Some(&repeater), Some(Payload::GrpTxt(group))) = (packet.path.last(), payload) else {
+        return;
+    };
+    let window = state.channel_policy.repeat_window;
+    let CompanionState { pending_channel_msgs, channel_hashes, .. } = state;
+    let envelope = pending_channel_msgs.iter_mut().find(|e| {
+        if e.last_attempt.elapsed() >= window {
+            return false;
+        }
+        match &e.fingerprint {
+            Some(fingerprint) => fingerprint == group,
+            None => {
+                e.expected_payload_len == packet.payload.len()
+                    && packet.path.len() == 1
+                    && channel_hashes.get(&e.msg.channel_idx).is_none_or(|hash| *hash == group.channel_hash)
+            }
+        }
+    });
+    let Some(envelope) = envelope else {
+        return;
+    };
+    if envelope.fingerprint.is_none() {
+        channel_hashes.insert(envelope.msg.channel_idx, group.channel_hash);
+        envelope.fingerprint = Some(group.clone());
+    }
+    if envelope.repeaters.insert(repeater) {
+        debug!("Channel message {:?} repeated by {repeater:02x}", envelope.msg);
+    }
+}
+
+fn accept_inbound(state: &mut CompanionState, msg: MessageTypes) {
+    let msg = ReceivedMessage::new(msg, SystemTime::now());
+    if state.inbox.is_duplicate(&msg) {
+        debug!("Suppressed duplicate message: {msg:?}");
+        return;
+    }
+    let ready = if state.multipart.reassemble {
+        let timeout = state.multipart.reassembly_timeout;
+        state.reassembler.push(msg, timeout)
+    } else {
+        vec![msg]
+    };
+    for msg in ready {
+        deliver_inbound(state, msg);
+    }
+}
+
+fn deliver_inbound(state: &mut CompanionState, msg: ReceivedMessage) {
+    state.publish(CompanionEvent::Message(msg.clone()));
+    if state.inbox.enqueue(msg.clone()) == InboxOutcome::Overflowed {
+        warn!("Inbox full, dropped incoming message: {msg:?}");
+    }
+    record_inbound(state, &msg);
+}
+
+fn record_inbound(state: &mut CompanionState, msg: &ReceivedMessage) {
+    let Some(history) = state.history.as_mut() else {
+        return;
+    };
+    let conversation = match msg.source() {
+        MessageSource::Contact(prefix) => ConversationKey::contact(&prefix.0),
+        MessageSource::Channel(id) => ConversationKey::Channel(id),
+    };
+    if let Err(e) = history.record(
+        conversation,
+        Direction::Inbound,
+        msg.text(),
+        msg.sender_timestamp(),
+        msg.snr_db(),
+        Some(msg.path_len()),
+        DeliveryStatus::Received,
+    ) {
+        error!("Failed to record inbound message: {e}");
+    }
+}
+
+fn record_channel_status(state: &mut CompanionState, msg: &SendChannelTxtMsg, status: DeliveryStatus) {
+    let Some(history) = state.history.as_mut() else {
+        return;
+    };
+    let conversation = ConversationKey::Channel(msg.channel_idx);
+    if let Some(id) = history.find_outbound(&conversation, msg.sender_timestamp, &msg.text)
+        && let Err(e) = history.update_status(id, status)
+    {
+        error!("Failed to update message history: {e}");
+    }
+}
+
+pub(crate) fn record_outbound_status(state: &mut CompanionState, msg: &SendTxtMsg, status: DeliveryStatus) {
+    let Some(history) = state.history.as_mut() else {
+        return;
+    };
+    let conversation = ConversationKey::contact(&msg.pubkey_prefix);
+    if let Some(id) = history.find_outbound(&conversation, msg.sender_timestamp, &msg.text)
+        && let Err(e) = history.update_status(id, status)
+    {
+        error!("Failed to update message history: {e}");
+    }
+}
+
 impl From<[u8;6]> for PubkeyPrefix {
     fn from(bytes: [u8;6]) -> Self {
         Self(bytes)
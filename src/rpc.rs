@@ -0,0 +1,278 @@
+//! JSON-RPC 2.0 over a Unix domain socket, one JSON message per line.
+//!
+//! `command` takes any `Commands` value in its serde form, e.g. `{"CmdSetAdvertName": "base"}`.
+//! After `subscribe` the connection also receives `event` notifications carrying a `CompanionEvent`.
+use crate::contact_mgmt::PublicKey;
+use crate::events::CompanionEvent;
+use crate::{stopped, AppError, Commands, Companion};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The companion rejected the call, the message is the `AppError`
+pub const COMPANION_ERROR: i64 = -32000;
+
+const DEFAULT_WAIT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl From<AppError> for RpcError {
+    fn from(e: AppError) -> Self {
+        Self::new(COMPANION_ERROR, e.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    /// Absent for notifications, which get no response
+    id: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct WaitParams {
+    command: Commands,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct NameParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct KeyParams {
+    public_key: PublicKey,
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+}
+
+fn result_value(result: Option<Result<Commands, AppError>>) -> Result<Value, RpcError> {
+    match result {
+        None => Ok(Value::Null),
+        Some(Ok(cmd)) => Ok(json!({ "Ok": to_value(cmd)? })),
+        Some(Err(e)) => Ok(json!({ "Err": e.to_string() })),
+    }
+}
+
+/// Runs one method against the companion, everything except the subscription methods.
+pub async fn dispatch(companion: &Companion, method: &str, params_value: Value) -> Result<Value, RpcError> {
+    match method {
+        "command" => {
+            companion.command(params(params_value)?).await?;
+            Ok(Value::Null)
+        }
+        "command_and_wait" => {
+            let WaitParams { command, timeout_ms } = params(params_value)?;
+            let timeout = timeout_ms.map_or(DEFAULT_WAIT, Duration::from_millis);
+            to_value(companion.command_and_wait(command, timeout).await?)
+        }
+        "self_info" => to_value(companion.get_self_info().await),
+        "public_key" => to_value(companion.get_public_key().await),
+        "capabilities" => to_value(companion.capabilities().await),
+        "connection_state" => to_value(companion.connection_state()),
+        "contacts" => to_value(companion.get_contacts().await),
+        "find_contact" => {
+            let NameParams { name } = params(params_value)?;
+            to_value(companion.find_contact_by_name(&name).await)
+        }
+        "retrieve_export" => {
+            let KeyParams { public_key } = params(params_value)?;
+            to_value(companion.retrieve_export(public_key).await)
+        }
+        "pop_received" => to_value(companion.pop_received().await),
+        "pop_delivery_report" => to_value(companion.pop_delivery_report().await),
+        "pop_channel_report" => to_value(companion.pop_channel_report().await),
+        "pop_trace_result" => to_value(companion.pop_trace_result().await),
+        "pop_result" => result_value(companion.pop_result().await),
+        "health" => to_value(companion.health().await),
+        "device_clock" => to_value(companion.device_clock().await),
+        "tuning_parameters" => to_value(companion.get_tuning_parameters().await),
+        "duty_cycle_status" => to_value(companion.duty_cycle_status().await),
+        "node_positions" => to_value(companion.node_positions().await),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {method}"))),
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+async fn handle_request(
+    companion: &Companion,
+    request: Value,
+    events: &mut Option<broadcast::Receiver<CompanionEvent>>,
+) -> Option<Value> {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let request: Request = match serde_json::from_value::<Request>(request) {
+        Ok(parsed) if parsed.jsonrpc == "2.0" => parsed,
+        _ => return Some(error_response(id, RpcError::new(INVALID_REQUEST, "not a JSON-RPC 2.0 request"))),
+    };
+    let result = match request.method.as_str() {
+        "subscribe" => {
+            *events = Some(companion.subscribe());
+            Ok(Value::Bool(true))
+        }
+        "unsubscribe" => Ok(Value::Bool(events.take().is_some())),
+        method => dispatch(companion, method, request.params).await,
+    };
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => error_response(id, error),
+    })
+}
+
+/// Handles one line from a client, a single request or a batch.
+async fn handle_message(
+    companion: &Companion,
+    line: &str,
+    events: &mut Option<broadcast::Receiver<CompanionEvent>>,
+) -> Option<Value> {
+    let message: Value = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string()))),
+    };
+    match message {
+        Value::Array(batch) if !batch.is_empty() => {
+            let mut responses = vec![];
+            for request in batch {
+                responses.extend(handle_request(companion, request, events).await);
+            }
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        request => handle_request(companion, request, events).await,
+    }
+}
+
+async fn next_event(events: &mut Option<broadcast::Receiver<CompanionEvent>>) -> Result<CompanionEvent, RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn send_line(writer: &mut OwnedWriteHalf, message: Value) -> std::io::Result<()> {
+    let mut line = message.to_string();
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
+async fn serve_client(companion: Arc<Companion>, stream: UnixStream, mut shutdown: watch::Receiver<bool>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut events = None;
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line,
+            event = next_event(&mut events) => {
+                let outgoing = match event {
+                    Ok(event) => json!({ "jsonrpc": "2.0", "method": "event", "params": event }),
+                    Err(RecvError::Lagged(missed)) => {
+                        json!({ "jsonrpc": "2.0", "method": "events_lagged", "params": { "missed": missed } })
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if send_line(&mut writer, outgoing).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            _ = stopped(&mut shutdown) => break,
+        };
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("RPC client read failed: {e}");
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = handle_message(&companion, &line, &mut events).await
+            && send_line(&mut writer, response).await.is_err()
+        {
+            break;
+        }
+    }
+    debug!("RPC client disconnected");
+}
+
+/// Accepts clients on `path` until `shutdown` is set, each client is served by its own task.
+pub async fn serve_unix(
+    companion: Arc<Companion>,
+    path: impl AsRef<Path>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), AppError> {
+    let path: PathBuf = path.as_ref().to_path_buf();
+    // A socket file left behind by a previous run would make bind fail
+    match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(&path).map_err(|e| AppError::Rpc(e.to_string()))?;
+        }
+        Ok(_) => {
+            return Err(AppError::Rpc(format!("{} exists and is not a socket", path.display())));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(AppError::Rpc(e.to_string())),
+    }
+    let listener = UnixListener::bind(&path).map_err(|e| AppError::Rpc(e.to_string()))?;
+    // Anyone who can connect can send messages as this node
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| AppError::Rpc(e.to_string()))?;
+    info!("JSON-RPC listening on {}", path.display());
+    let clients = shutdown.clone();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let spawned = tokio::task::Builder::new()
+                        .name("rpc-client")
+                        .spawn(serve_client(companion.clone(), stream, clients.clone()));
+                    if let Err(e) = spawned {
+                        error!("Failed to spawn RPC client task: {e}");
+                    }
+                }
+                Err(e) => warn!("RPC accept failed: {e}"),
+            },
+            _ = stopped(&mut shutdown) => break,
+        }
+    }
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
@@ -0,0 +1,27 @@
+//! `#[serde(with = "crate::serde_hex")]` for byte fields that read better as hex strings in JSON.
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub(crate) fn serialize<T: AsRef<[u8]>, S: Serializer>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(bytes.as_ref()))
+}
+
+pub(crate) fn deserialize<'de, T: TryFrom<Vec<u8>>, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    let bytes = decode(&hex).ok_or_else(|| serde::de::Error::custom("invalid hex string"))?;
+    let len = bytes.len();
+    T::try_from(bytes).map_err(|_| serde::de::Error::custom(format!("unexpected length {len}")))
+}
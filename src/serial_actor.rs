@@ -1,12 +1,17 @@
-use crate::{consts, AppError};
-use crate::consts::{SERIAL_INBOUND, SERIAL_LOOP_SLEEP_MS, SERIAL_OUTBOUND, TIMEOUT_SERIAL_MS};
-use serde::{Deserialize, Serialize};
+use crate::consts;
+use crate::history::Direction;
+use crate::recorder::SessionRecorder;
+use crate::consts::{DEFAULT_BAUD_RATE, SERIAL_INBOUND, SERIAL_OUTBOUND};
+use serde::Serialize;
 use std::io::ErrorKind;
-use std::process::exit;
 use thiserror::Error;
-use tokio::sync::mpsc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
 use tokio::time::Duration;
-use tracing::{debug, error, info, trace};
+pub use tokio_serial::{DataBits, FlowControl, Parity, StopBits};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use tracing::{debug, error, info, trace, warn};
 
 #[derive(PartialEq, Clone, Default)]
 pub struct SerialFrame {
@@ -26,6 +31,13 @@ impl std::fmt::Debug for SerialFrame {
     }
 }
 impl SerialFrame {
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(3 + self.frame.len());
+        data.push(self.delimiter);
+        data.extend_from_slice(&self.frame_length.to_le_bytes());
+        data.extend_from_slice(&self.frame);
+        data
+    }
     pub fn from_data(data: Vec<u8>) -> Self {
         SerialFrame {
             delimiter: consts::SERIAL_OUTBOUND,
@@ -35,105 +47,285 @@ impl SerialFrame {
     }
 }
 
-    pub async fn serial_loop(
-        port: String,
-        to_radio: &mut mpsc::Receiver<SerialFrame>,
-        from_radio: &mpsc::Sender<SerialFrame>,
-    ) {
-        // Use a loop here to allow for reconnection if the serial port drops
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    /// Wait between attempts to (re)open the port
+    pub reconnect_delay: Duration,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            baud_rate: DEFAULT_BAUD_RATE,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            reconnect_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl SerialConfig {
+    fn open(&self, port: &str) -> tokio_serial::Result<SerialStream> {
+        tokio_serial::new(port, self.baud_rate)
+            .data_bits(self.data_bits)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+            .flow_control(self.flow_control)
+            .open_native_async()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ConnectionState {
+    /// Waiting for the port to open for the first time
+    Connecting,
+    Connected,
+    /// The port dropped or failed to open, `attempt` counts tries since the last connection
+    Reconnecting { attempt: u32 },
+    /// Shut down, the port is closed and will not be reopened
+    Closed,
+}
+
+pub async fn serial_loop(
+    port: String,
+    config: SerialConfig,
+    to_radio: &mut mpsc::Receiver<SerialFrame>,
+    from_radio: &mpsc::Sender<SerialFrame>,
+    connection: &watch::Sender<ConnectionState>,
+    shutdown: watch::Receiver<bool>,
+    recorder: Option<SessionRecorder>,
+) {
+    let open = || async { config.open(&port) };
+    let link = Link {
+        name: format!("serial port {port}"),
+        reconnect_delay: config.reconnect_delay,
+        to_radio,
+        from_radio,
+        connection,
+        recorder,
+    };
+    link.run(open, shutdown).await;
+}
+
+/// Same as `serial_loop` but for companions exposing the frame protocol over TCP, like WiFi firmware.
+pub async fn tcp_loop(
+    addr: String,
+    reconnect_delay: Duration,
+    to_radio: &mut mpsc::Receiver<SerialFrame>,
+    from_radio: &mpsc::Sender<SerialFrame>,
+    connection: &watch::Sender<ConnectionState>,
+    shutdown: watch::Receiver<bool>,
+    recorder: Option<SessionRecorder>,
+) {
+    let open = || TcpStream::connect(addr.as_str());
+    let link = Link {
+        name: format!("TCP connection to {addr}"),
+        reconnect_delay,
+        to_radio,
+        from_radio,
+        connection,
+        recorder,
+    };
+    link.run(open, shutdown).await;
+}
+
+struct Link<'a> {
+    name: String,
+    reconnect_delay: Duration,
+    to_radio: &'a mut mpsc::Receiver<SerialFrame>,
+    from_radio: &'a mpsc::Sender<SerialFrame>,
+    connection: &'a watch::Sender<ConnectionState>,
+    recorder: Option<SessionRecorder>,
+}
+
+impl Link<'_> {
+    async fn run<S, E, F, Fut>(self, mut open: F, mut shutdown: watch::Receiver<bool>)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<S, E>>,
+    {
+        let Link { name, reconnect_delay, to_radio, from_radio, connection, recorder } = self;
+        let mut attempt = 0;
+        let mut connected_once = false;
+        // Use a loop here to allow for reconnection if the link drops
         loop {
-            let mut fd = match serialport::new(&port, 115200)
-                .timeout(Duration::from_millis(TIMEOUT_SERIAL_MS))
-                .open() {
-                    Ok(port) => port,
-                    Err(e) => {
-                        error!("Failed to open serial port {}: {}. Retrying in 5s...", port, e);
-                        tokio::time::sleep(Duration::from_secs(5)).await;
-                        continue;
+            let stream = match open().await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    attempt += 1;
+                    if connected_once {
+                        connection.send_replace(ConnectionState::Reconnecting { attempt });
                     }
-                };
+                    error!("Failed to open {}: {}. Retrying in {:?}...", name, e, reconnect_delay);
+                    tokio::select! {
+                        _ = tokio::time::sleep(reconnect_delay) => continue,
+                        _ = shutdown.wait_for(|stop| *stop) => break,
+                    }
+                }
+            };
+            info!("Opened {}.", name);
+            attempt = 0;
+            connected_once = true;
+            connection.send_replace(ConnectionState::Connected);
 
-            info!("Serial port {} opened successfully.", port);
-
-            let mut buffer = [0; 1024];
-            let mut accumulator = Vec::new();
-            
-            // Inner loop for the actual communication
-            loop {
-                // transmit outgoing messages
-                // Use while let instead of if let to drain the queue
-                while let Ok(msg) = to_radio.try_recv() {
-                    let mut data = Vec::with_capacity(3 + msg.frame.len());
-                    data.push(msg.delimiter);
-                    data.extend_from_slice(&msg.frame_length.to_le_bytes());
-                    data.extend_from_slice(&msg.frame);
-
-                    info!("Sending serial frame: {:02x?}", data);
-                    if let Err(e) = fd.write_all(&data) {
-                        error!("Failed to write to serial port: {}. Restarting connection...", e);
-                        break; // Break inner loop to trigger reconnect
+            tokio::select! {
+                result = run_transport(stream, to_radio, from_radio, recorder.clone()) => {
+                    if let Err(e) = result {
+                        error!("{} failed: {}", name, e);
                     }
                 }
+                _ = shutdown.wait_for(|stop| *stop) => break,
+            }
+            if from_radio.is_closed() {
+                info!("Companion dropped, closing {}.", name);
+                break;
+            }
+            attempt += 1;
+            connection.send_replace(ConnectionState::Reconnecting { attempt });
+            error!("Lost {}. Attempting to reconnect in 1s...", name);
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(1)) => (),
+                _ = shutdown.wait_for(|stop| *stop) => break,
+            }
+        }
+        info!("Closed {}.", name);
+        connection.send_replace(ConnectionState::Closed);
+    }
+}
+
+/// Pumps frames over any byte stream until it fails: a reader task decodes inbound frames
+/// while outbound frames are written as soon as they are queued.
+pub(crate) async fn run_transport<S>(
+    stream: S,
+    to_radio: &mut mpsc::Receiver<SerialFrame>,
+    from_radio: &mpsc::Sender<SerialFrame>,
+    recorder: Option<SessionRecorder>,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    // Aborted on drop as well, so the read half is released even if this future is cancelled
+    let mut reader_task = AbortOnDrop(tokio::spawn(read_frames(reader, from_radio.clone(), recorder.clone())));
+    loop {
+        tokio::select! {
+            read_result = &mut reader_task.0 => {
+                break match read_result {
+                    Ok(result) => result,
+                    Err(e) => Err(std::io::Error::other(e)),
+                };
+            }
+            msg = to_radio.recv() => {
+                let Some(msg) = msg else {
+                    break Ok(());
+                };
+                let data = msg.to_bytes();
+                info!("Sending serial frame: {:02x?}", data);
+                if let Err(e) = writer.write_all(&data).await {
+                    break Err(e);
+                }
+                if let Err(e) = writer.flush().await {
+                    break Err(e);
+                }
+                if let Some(recorder) = &recorder {
+                    recorder.record(Direction::Outbound, &msg);
+                }
+            }
+        }
+    }
+}
+
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
 
-                // check for incoming messages
-                match fd.read(&mut buffer) {
-                    Ok(d) if d > 0 => {
-                        accumulator.extend_from_slice(&buffer[..d]);
-
-                        loop {
-                            let formatted = accumulator
-                                .clone()
-                                .iter()
-                                .map(|b| format!("0x{:02x}", b))
-                                .collect::<Vec<_>>()
-                                .join(",");
-                            debug!("accumulator: {formatted}");
-
-                            match decode_frame(&accumulator) {
-                                Ok((frame, None)) => {
-                                    from_radio
-                                        .send(frame)
-                                        .await
-                                        .unwrap_or_else(|e| error!("Failed to send serial frame: {}", e));
-                                    accumulator.clear();
-                                    break;
-                                }
-                                Ok((frame, Some(residual))) => {
-                                    trace!("Residual frame: {:02x?}", residual);
-                                    from_radio
-                                        .send(frame)
-                                        .await
-                                        .unwrap_or_else(|e| error!("Failed to send serial frame: {}", e));
-                                    accumulator = residual;
-                                }
-                                Err(e) if e.kind() == DecodeErrorKind::FrameTooShort => {
-                                    break;
-                                }
-                                Err(e) if e.kind() == DecodeErrorKind::FrameTooLong => {
-                                    accumulator.clear();
-                                    break;
-                                }
-                                Err(e) => {
-                                    println!("Failed to decode frame: {}", e);
-                                    break;
-                                }
-                            }
+async fn read_frames<R>(
+    mut reader: R,
+    from_radio: mpsc::Sender<SerialFrame>,
+    recorder: Option<SessionRecorder>,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = [0; 1024];
+    let mut accumulator = Vec::new();
+    loop {
+        let d = reader.read(&mut buffer).await?;
+        if d == 0 {
+            return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "stream closed"));
+        }
+        accumulator.extend_from_slice(&buffer[..d]);
+
+        loop {
+            let formatted = accumulator
+                .iter()
+                .map(|b| format!("0x{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(",");
+            debug!("accumulator: {formatted}");
+
+            match decode_frame(&accumulator) {
+                Ok((frame, residual)) => {
+                    if let Some(recorder) = &recorder {
+                        recorder.record(Direction::Inbound, &frame);
+                    }
+                    if from_radio.send(frame).await.is_err() {
+                        return Ok(());
+                    }
+                    match residual {
+                        Some(residual) => {
+                            trace!("Residual frame: {:02x?}", residual);
+                            accumulator = residual;
+                        }
+                        None => {
+                            accumulator.clear();
+                            break;
                         }
                     }
-                    Ok(_) => (),
-                    Err(ref e) if e.kind() == ErrorKind::TimedOut => (),
-                    Err(e) => {
-                        error!("Serial read error: {}. Restarting connection...", e);
-                        break; // Break inner loop to trigger reconnect
+                }
+                Err(e) if e.kind() == DecodeErrorKind::FrameTooShort => {
+                    break;
+                }
+                Err(e) if e.kind() == DecodeErrorKind::FrameTooLong => {
+                    accumulator.clear();
+                    break;
+                }
+                Err(e) if e.kind() == DecodeErrorKind::InvalidDelimiter => {
+                    // resynchronise on the next byte that could start an inbound frame
+                    match accumulator.iter().skip(1).position(|b| *b == SERIAL_INBOUND) {
+                        Some(skip) => {
+                            warn!("Discarding {} bytes without a frame delimiter", skip + 1);
+                            accumulator.drain(..=skip);
+                        }
+                        None => {
+                            warn!("Discarding {} bytes without a frame delimiter", accumulator.len());
+                            accumulator.clear();
+                            break;
+                        }
                     }
                 }
-                tokio::time::sleep(Duration::from_millis(SERIAL_LOOP_SLEEP_MS)).await;
+                Err(e) => {
+                    warn!("Failed to decode frame: {}", e);
+                    accumulator.clear();
+                    break;
+                }
             }
-            
-            error!("Serial loop inner loop exited. Attempting to reconnect in 1s...");
-            tokio::time::sleep(Duration::from_secs(1)).await;
         }
     }
+}
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DecodeErrorKind {
     InvalidDelimiter,
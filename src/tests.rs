@@ -1,7 +1,38 @@
 #[cfg(test)]
 mod tests {
-    use crate::consts::SERIAL_INBOUND;
-    use crate::serial_actor::{DecodeError, SerialFrame, decode_frame};
+    use crate::consts::{SERIAL_INBOUND, SERIAL_OUTBOUND};
+    use crate::serial_actor::{DecodeError, SerialFrame, decode_frame, run_transport};
+    use crate::history::{ConversationKey, DeliveryStatus, Direction, HistoryStore};
+    use crate::inbox::{Inbox, InboxConfig, InboxOutcome, OverflowPolicy};
+    use crate::responses::{ChannelMsgV3, ContactMsg, Hops, MessageSource, ReceivedMessage, TextKind};
+    use crate::multipart::{parse_marker, split_text, Reassembler};
+    use crate::commands::{AppStart, ChannelEnvelope, RadioParameters, Reboot, MessageEnvelope, SendChannelTxtMsg, SendTxtMsg, SendingMessageTypes};
+    use crate::airtime::{airtime, DutyCycleConfig, DutyCycleTracker, RateLimitMode};
+    use crate::capture::{CaptureConfig, PacketCapture, PcapngWriter, RotationPolicy, LINKTYPE_MESHCORE};
+    use crate::packet::{AdvertType, Packet, PacketError, PacketRoute, Payload, PayloadType, RxLogEvent};
+    use crate::radio::{RadioParamsError, RadioPreset};
+    use crate::topology::NodeId;
+    use crate::geo::{node_positions, to_geojson, to_gpx, to_kml, Position};
+    use crate::recorder::{read_recording, ReplayPacing, ReplayTransport, SessionRecorder};
+    use crate::consts::{CMD_RESET_PATH, CMD_SEND_TXT_MSG};
+    use crate::contact_mgmt::{Contact, PublicKey};
+    use crate::delivery::{ChannelPolicy, DeliveryOutcome, DeliveryPolicy, RouteType};
+    use crate::events::CompanionEvent;
+    use crate::health::{lipo_percent, HealthConfig};
+    use crate::responses::{check_internal, fail_in_flight, AckCode, DeviceInfo, SelfInfo};
+    use crate::Commands;
+    use crate::{AppError, Companion, ConnectionState, HandshakeConfig, MessageTypes};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    fn contact_msg(prefix: u8, sender_timestamp: u32, text: &str) -> ReceivedMessage {
+        let mut frame = vec![crate::consts::RESP_CODE_CONTACT_MSG_RECV, prefix, 0, 0, 0, 0, 0, 0xff, 0];
+        frame.extend_from_slice(&sender_timestamp.to_le_bytes());
+        frame.extend_from_slice(text.as_bytes());
+        ReceivedMessage::new(MessageTypes::ContactMsg(ContactMsg::from_frame(&frame)), SystemTime::now())
+    }
+    fn text_of(msg: Option<ReceivedMessage>) -> String {
+        msg.expect("inbox unexpectedly empty").text().to_string()
+    }
 
     #[test]
     fn decode_frame_full() {
@@ -62,4 +93,1254 @@ mod tests {
         let result = decode_frame(&frame);
         assert_eq!(result, Err(DecodeError::FrameTooLong));
     }
+
+    #[test]
+    fn history_persists_and_pages() {
+        let path = std::env::temp_dir().join(format!("meshcore_history_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let alice = ConversationKey::contact(&[0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f]);
+        {
+            let mut store = HistoryStore::open(&path).unwrap();
+            for i in 0..5u32 {
+                store
+                    .record(alice.clone(), Direction::Inbound, &format!("msg {i}"), i, Some(2.5), Some(1), DeliveryStatus::Received)
+                    .unwrap();
+            }
+            let sent = store
+                .record(ConversationKey::Channel(0), Direction::Outbound, "hello", 9, None, None, DeliveryStatus::Sent)
+                .unwrap();
+            store.update_status(sent, DeliveryStatus::Delivered).unwrap();
+            assert_eq!(store.unread_count(&alice), 5);
+            assert_eq!(store.mark_read(&alice).unwrap(), 5);
+        }
+        let store = HistoryStore::open(&path).unwrap();
+        assert_eq!(store.unread_count(&alice), 0);
+        let conversations = store.conversations();
+        assert_eq!(conversations.len(), 2);
+        assert_eq!(conversations[0].conversation, ConversationKey::Channel(0));
+        assert_eq!(conversations[0].last_message.status, DeliveryStatus::Delivered);
+        let newest = store.thread(&alice, None, 2);
+        assert_eq!(newest.iter().map(|e| e.text.as_str()).collect::<Vec<_>>(), vec!["msg 3", "msg 4"]);
+        let older = store.thread(&alice, Some(newest[0].id), 10);
+        assert_eq!(older.len(), 3);
+        assert_eq!(older[0].text, "msg 0");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn inbox_is_fifo_and_suppresses_duplicates() {
+        let mut inbox = Inbox::default();
+        assert_eq!(inbox.push(contact_msg(1, 100, "first")), InboxOutcome::Queued);
+        assert_eq!(inbox.push(contact_msg(1, 101, "second")), InboxOutcome::Queued);
+        assert_eq!(inbox.push(contact_msg(1, 100, "first")), InboxOutcome::Duplicate);
+        // same text and time from a different sender is not a duplicate
+        assert_eq!(inbox.push(contact_msg(2, 100, "first")), InboxOutcome::Queued);
+        assert_eq!(inbox.duplicates(), 1);
+        assert_eq!(text_of(inbox.pop()), "first");
+        assert_eq!(text_of(inbox.pop()), "second");
+        assert_eq!(text_of(inbox.pop()), "first");
+        assert!(inbox.pop().is_none());
+    }
+    #[test]
+    fn inbox_overflow_policies() {
+        let config = InboxConfig { capacity: 2, overflow: OverflowPolicy::DropOldest, dedup_window: Duration::ZERO };
+        let mut inbox = Inbox::new(config.clone());
+        for (i, text) in ["a", "b", "c"].iter().enumerate() {
+            inbox.push(contact_msg(1, i as u32, text));
+        }
+        assert_eq!(text_of(inbox.pop()), "b");
+
+        let mut inbox = Inbox::new(InboxConfig { overflow: OverflowPolicy::DropNewest, ..config });
+        inbox.push(contact_msg(1, 0, "a"));
+        inbox.push(contact_msg(1, 1, "b"));
+        assert_eq!(inbox.push(contact_msg(1, 2, "c")), InboxOutcome::Overflowed);
+        assert_eq!(inbox.dropped(), 1);
+        assert_eq!(text_of(inbox.pop()), "a");
+    }
+
+    #[test]
+    fn received_message_accessors() {
+        let mut frame = vec![crate::consts::RESP_CODE_CHANNEL_MSG_RECV_V3, 0xf6, 0, 0, 3, 2, 2];
+        frame.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        frame.extend_from_slice(b"bob: hi\0");
+        let received_at = SystemTime::now();
+        let msg = ReceivedMessage::new(MessageTypes::ChannelMsgV3(ChannelMsgV3::from_frame(&frame)), received_at);
+        assert_eq!(msg.source(), MessageSource::Channel(3));
+        assert_eq!(msg.snr_db(), Some(-2.5));
+        assert_eq!(msg.hops(), Hops::Flood(2));
+        assert_eq!(msg.text_kind(), TextKind::SignedPlain);
+        assert_eq!(msg.text(), "bob: hi");
+        assert_eq!(msg.sender_time(), UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(msg.received_at(), received_at);
+        assert!(msg.resolve_contact(&[]).is_none());
+
+        let direct = contact_msg(1, 0, "x");
+        assert_eq!(direct.hops(), Hops::Direct);
+        assert_eq!(direct.snr_db(), None);
+        assert_eq!(direct.text_kind(), TextKind::Plain);
+    }
+
+    #[test]
+    fn split_text_respects_limit_and_char_boundaries() {
+        let text = "héllo wörld 🚀 ".repeat(20);
+        let parts = split_text(&text, 160, true).unwrap();
+        assert!(parts.len() > 1);
+        let mut rebuilt = String::new();
+        for (i, part) in parts.iter().enumerate() {
+            assert!(part.len() <= 160, "part {i} is {} bytes", part.len());
+            let (index, total, body) = parse_marker(part).unwrap();
+            assert_eq!((index, total), (i + 1, parts.len()));
+            rebuilt.push_str(body);
+        }
+        assert_eq!(rebuilt, text);
+        assert_eq!(split_text("short", 160, true), Ok(vec!["short".to_string()]));
+        assert!(split_text(&text, 160, false).unwrap().iter().all(|p| parse_marker(p).is_none() && p.len() <= 160));
+        // Too small for a marker plus the widest character
+        assert!(split_text(&text, 9, true).is_err());
+        assert!(split_text(&text, 3, false).is_err());
+    }
+    #[tokio::test]
+    async fn new_message_waits_for_queued_parts() {
+        let mut companion = Companion::new("/dev/null");
+        let _to_radio = companion.to_radio_rx.take().unwrap();
+        let msg = |text: &str| SendTxtMsg {
+            code: CMD_SEND_TXT_MSG,
+            txt_type: 0,
+            attempt: 0,
+            sender_timestamp: 1,
+            pubkey_prefix: [7; 6],
+            text: text.to_string(),
+            timeout: None,
+        };
+        companion.state.write().await.outbound_parts.push_back(Commands::CmdSendTxtMsg(msg("(2/2) rest")));
+        let result = companion.command(Commands::CmdSendTxtMsg(msg("hello"))).await;
+        assert!(matches!(result, Err(AppError::Congestion(_))));
+        assert!(companion.state.read().await.pending_msgs.is_empty());
+    }
+    #[tokio::test]
+    async fn new_channel_message_waits_for_queued_parts() {
+        let mut companion = Companion::new("/dev/null");
+        let mut to_radio = companion.to_radio_rx.take().unwrap();
+        let msg = |text: &str| SendChannelTxtMsg {
+            code: crate::consts::CMD_SEND_CHANNEL_TXT_MSG,
+            txt_type: 0,
+            channel_idx: 0,
+            sender_timestamp: 1,
+            text: text.to_string(),
+        };
+        {
+            let mut state = companion.state.write().await;
+            state.outbound_parts.push_back(Commands::CmdSendChannelTxtMsg(msg("(2/3) middle")));
+            state.outbound_parts.push_back(Commands::CmdSendChannelTxtMsg(msg("(3/3) end")));
+        }
+        let result = companion.command(Commands::CmdSendChannelTxtMsg(msg("hello"))).await;
+        assert!(matches!(result, Err(AppError::Congestion(_))));
+        assert!(to_radio.try_recv().is_err());
+        // the sweep still gets the queued parts out, one per pass
+        check_internal(companion.state.clone()).await.unwrap();
+        let sent = std::iter::from_fn(|| to_radio.try_recv().ok())
+            .find(|frame| frame.frame[0] == crate::consts::CMD_SEND_CHANNEL_TXT_MSG)
+            .unwrap();
+        assert!(sent.frame.ends_with(b"(2/3) middle"));
+        assert_eq!(companion.state.read().await.outbound_parts.len(), 1);
+    }
+    #[test]
+    fn reassembler_joins_parts_in_order() {
+        let timeout = Duration::from_secs(60);
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.push(contact_msg(1, 50, "(2/2) world"), timeout).is_empty());
+        let other = reassembler.push(contact_msg(1, 51, "unrelated"), timeout);
+        assert_eq!(other.len(), 1);
+        let ready = reassembler.push(contact_msg(1, 50, "(1/2) hello "), timeout);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].text(), "hello world");
+        assert_eq!(ready[0].sender_timestamp(), 50);
+
+        assert!(reassembler.push(contact_msg(1, 60, "(1/3) lonely"), timeout).is_empty());
+        let released = reassembler.expire(Duration::ZERO);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].text(), "(1/3) lonely");
+    }
+
+    #[test]
+    fn delivery_policy_backoff() {
+        let policy = DeliveryPolicy { timeout_multiplier: 2.0, backoff_multiplier: 1.5, ..DeliveryPolicy::default() };
+        assert_eq!(policy.ack_timeout_ms(1000, 0), 2000);
+        assert_eq!(policy.ack_timeout_ms(1000, 2), 4500);
+    }
+    #[tokio::test]
+    async fn repeated_direct_failure_resets_path_and_floods() {
+        let mut companion = Companion::new("/dev/null");
+        let mut to_radio = companion.to_radio_rx.take().unwrap();
+        let public_key = PublicKey::from_bytes([7u8; 32]);
+        let msg = SendTxtMsg {
+            code: CMD_SEND_TXT_MSG,
+            txt_type: 0,
+            attempt: 1,
+            sender_timestamp: 1,
+            pubkey_prefix: public_key.prefix_bytes(),
+            text: "ping".to_string(),
+            timeout: Some(100),
+        };
+        {
+            let mut state = companion.state.write().await;
+            state.contacts.push(Contact {
+                public_key,
+                adv_type: 1,
+                flags: 0,
+                out_path_len: 1,
+                out_path: [0u8; 64],
+                adv_name: "peer".to_string(),
+                last_advert: 0,
+                adv_lat: 0,
+                adv_lon: 0,
+                lastmod: 0,
+                logged_in: None,
+            });
+            let mut envelope = MessageEnvelope::new(SendingMessageTypes::TxtMsg(msg));
+            envelope.last_attempt_timestamp = 0;
+            envelope.route = Some(RouteType::Direct);
+            envelope.direct_failures = 1;
+            state.pending_acks.insert(AckCode([1, 2, 3, 4]), envelope);
+        }
+        check_internal(companion.state.clone()).await.unwrap();
+        assert_eq!(to_radio.try_recv().unwrap().frame[0], CMD_RESET_PATH);
+        let resend = to_radio.try_recv().unwrap();
+        assert_eq!(resend.frame[0], CMD_SEND_TXT_MSG);
+        assert_eq!(resend.frame[2], 2);
+        let state = companion.state.read().await;
+        assert!(state.pending_msgs[0].flood_fallback);
+    }
+
+    #[tokio::test]
+    async fn reconnect_fails_messages_in_flight() {
+        let mut companion = Companion::new("/dev/null");
+        let _to_radio = companion.to_radio_rx.take().unwrap();
+        let mut events = companion.subscribe();
+        let msg = |text: &str| SendTxtMsg {
+            code: CMD_SEND_TXT_MSG,
+            txt_type: 0,
+            attempt: 0,
+            sender_timestamp: 1,
+            pubkey_prefix: [7u8; 6],
+            text: text.to_string(),
+            timeout: None,
+        };
+        let channel_msg = SendChannelTxtMsg {
+            code: crate::consts::CMD_SEND_CHANNEL_TXT_MSG,
+            txt_type: 0,
+            channel_idx: 0,
+            sender_timestamp: 1,
+            text: "hello".to_string(),
+        };
+        {
+            let mut state = companion.state.write().await;
+            state.pending_msgs.push(MessageEnvelope::new(SendingMessageTypes::TxtMsg(msg("awaiting code"))));
+            state
+                .pending_acks
+                .insert(AckCode([1, 2, 3, 4]), MessageEnvelope::new(SendingMessageTypes::TxtMsg(msg("awaiting ack"))));
+            state.command_queue.push_back(Commands::CmdGetBattAndStorage);
+            state.outbound_parts.push_back(Commands::CmdSendTxtMsg(msg("next part")));
+            state.pending_channel_msgs.push(ChannelEnvelope::new(channel_msg, "node"));
+            fail_in_flight(&mut state);
+            assert!(state.pending_msgs.is_empty() && state.pending_acks.is_empty());
+            assert!(state.command_queue.is_empty() && state.outbound_parts.is_empty());
+            assert!(state.pending_channel_msgs.is_empty());
+        }
+        for _ in 0..2 {
+            let Ok(CompanionEvent::Delivery(report)) = events.try_recv() else {
+                panic!("expected a delivery event");
+            };
+            assert_eq!(report.outcome, DeliveryOutcome::Failed);
+        }
+        let Ok(CompanionEvent::ChannelDelivery(report)) = events.try_recv() else {
+            panic!("expected a channel delivery event");
+        };
+        assert_eq!((report.text.as_str(), report.outcome), ("hello", DeliveryOutcome::Failed));
+        assert!(companion.pop_delivery_report().await.is_some());
+        assert!(companion.pop_delivery_report().await.is_some());
+        assert!(companion.pop_channel_report().await.is_some());
+    }
+
+    #[test]
+    fn parse_packet_header_and_path() {
+        let raw = [0x15, 0x02, 0xaa, 0xbb, 0x01, 0x02, 0x03];
+        let packet = Packet::parse(&raw).unwrap();
+        assert_eq!(packet.route, PacketRoute::Flood);
+        assert_eq!(packet.payload_type, PayloadType::GrpTxt);
+        assert_eq!(packet.payload_version, 0);
+        assert_eq!(packet.transport_codes, None);
+        assert_eq!(packet.path, vec![0xaa, 0xbb]);
+        assert_eq!(packet.payload, vec![0x01, 0x02, 0x03]);
+
+        let raw = [0x14, 0x34, 0x12, 0x78, 0x56, 0x00, 0x09];
+        let packet = Packet::parse(&raw).unwrap();
+        assert_eq!(packet.route, PacketRoute::TransportFlood);
+        assert_eq!(packet.transport_codes, Some([0x1234, 0x5678]));
+        assert!(packet.path.is_empty());
+        assert!(Packet::parse(&[0x15, 0x05, 0x01]).is_err());
+    }
+    #[test]
+    fn pcapng_capture_rotates_files() {
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        let header_len = writer.written();
+        writer.write_packet(SystemTime::UNIX_EPOCH, &[0x0e, 0x00, 1, 2, 3, 4], -2.5, -90).unwrap();
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len() as u64, header_len + 72);
+        assert_eq!(&bytes[..4], &[0x0a, 0x0d, 0x0d, 0x0a]);
+        let idb = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        assert_eq!(u16::from_le_bytes([bytes[idb + 8], bytes[idb + 9]]), LINKTYPE_MESHCORE);
+        let epb = &bytes[header_len as usize..];
+        assert_eq!(&epb[..4], &[6, 0, 0, 0]);
+        assert_eq!(&epb[28..34], &[0x0e, 0x00, 1, 2, 3, 4]);
+        assert!(epb.windows(20).any(|w| w == b"snr=-2.50dB rssi=-90"));
+
+        let dir = std::env::temp_dir().join(format!("meshcore_capture_{}", std::process::id()));
+        let mut config = CaptureConfig::new(&dir);
+        config.rotation = RotationPolicy {
+            max_bytes: Some(200),
+            max_age: None,
+            max_files: Some(2),
+        };
+        let mut capture = PacketCapture::open(config).unwrap();
+        let event = RxLogEvent::new(1.0, -80, vec![0x15, 0x00, 0xaa, 0x12, 0x34]);
+        for _ in 0..10 {
+            capture.record(&event).unwrap();
+        }
+        assert_eq!(capture.files().len(), 2);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        assert!(capture.files().iter().all(|f| std::fs::metadata(f).unwrap().len() <= 200 + 72));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[tokio::test]
+    async fn topology_from_paths_traces_and_adverts() {
+        let companion = Companion::new("/dev/null");
+        let repeater = [0x20u8; 32];
+        let contact_key = PublicKey::from_bytes([0x30; 32]);
+
+        // a repeater advert heard directly
+        let mut advert = vec![crate::consts::PUSH_CODE_LOG_RX_DATA, 24, 0xa6, 0x11, 0x00];
+        advert.extend_from_slice(&repeater);
+        advert.extend_from_slice(&0u32.to_le_bytes());
+        advert.extend_from_slice(&[0; 64]);
+        advert.push(0x80 | 0x02);
+        advert.extend_from_slice(b"hilltop");
+        // a contact we reach through the repeater and an unknown hop
+        let mut out_path = [0u8; 64];
+        out_path[..2].copy_from_slice(&[0x20, 0x40]);
+        let contact = Contact {
+            public_key: contact_key,
+            adv_type: 1,
+            flags: 0,
+            out_path_len: 2,
+            out_path,
+            adv_name: "peer".to_string(),
+            last_advert: 0,
+            adv_lat: 0,
+            adv_lon: 0,
+            lastmod: 0,
+            logged_in: None,
+        };
+        let mut contact_frame = vec![crate::consts::RESP_CODE_CONTACT];
+        contact_frame.extend_from_slice(&contact.to_frame());
+        contact_frame.extend_from_slice(&0u32.to_le_bytes());
+        // a trace out through the repeater, which heard us at 6 dB while we heard it at 5 dB
+        let mut trace = vec![crate::consts::PUSH_CODE_TRACE_DATA, 0, 1, 0];
+        trace.extend_from_slice(&7u32.to_le_bytes());
+        trace.extend_from_slice(&0u32.to_le_bytes());
+        trace.extend_from_slice(&[0x20, 24, 20]);
+        // a truncated trace is skipped without losing the frames queued behind it
+        let truncated = vec![crate::consts::PUSH_CODE_TRACE_DATA, 0, 1];
+        companion.from_radio_tx.send(SerialFrame::from_data(truncated)).await.unwrap();
+        for frame in [advert, contact_frame, trace] {
+            companion.from_radio_tx.send(SerialFrame::from_data(frame)).await.unwrap();
+            check_internal(companion.state.clone()).await.unwrap();
+        }
+        companion.command(Commands::CmdGetAdvertPath(contact_key)).await.unwrap();
+        let advert_path = vec![crate::consts::RESP_CODE_ADVERT_PATH, 0, 0, 0, 0, 1, 0x20];
+        companion.from_radio_tx.send(SerialFrame::from_data(advert_path)).await.unwrap();
+        check_internal(companion.state.clone()).await.unwrap();
+
+        assert_eq!(companion.pop_trace_result().await.unwrap().hop_snrs, vec![6.0]);
+        let topology = companion.topology().await;
+        let repeater_id = NodeId::Key(repeater);
+        assert_eq!(topology.resolve(0x20), repeater_id);
+        assert_eq!(topology.resolve(0x40), NodeId::Hop(0x40));
+        let inbound = topology.link(repeater_id, NodeId::Local).unwrap();
+        assert_eq!(inbound.observations, 3);
+        assert_eq!(inbound.last_snr, Some(5.0));
+        assert_eq!(inbound.avg_snr, Some(5.5));
+        let outbound = topology.link(NodeId::Local, repeater_id).unwrap();
+        assert_eq!((outbound.observations, outbound.avg_snr), (2, Some(6.0)));
+        assert!(topology.link(NodeId::Hop(0x40), NodeId::Key(contact_key.bytes)).is_some());
+        assert!(topology.link(NodeId::Key(contact_key.bytes), repeater_id).is_some());
+
+        let dot = topology.to_dot();
+        assert!(dot.contains("\"202020202020\" [label=\"hilltop\", shape=box];"));
+        assert!(dot.contains("\"202020202020\" -> \"local\" [label=\"5.5 dB x3\"];"));
+        let graphml = topology.to_graphml();
+        assert!(graphml.contains("<node id=\"hop 40\"><data key=\"name\">hop 40</data><data key=\"type\">unresolved</data></node>"));
+        assert_eq!(graphml.matches("<edge ").count(), topology.links().count());
+    }
+
+    #[test]
+    fn node_positions_export_with_distance_and_bearing() {
+        let mut frame = vec![crate::consts::RESP_CODE_SELF_INFO, 1, 22, 22];
+        frame.extend_from_slice(&[9u8; 32]);
+        frame.extend_from_slice(&47_000_000i32.to_le_bytes());
+        frame.extend_from_slice(&(-122_000_000i32).to_le_bytes());
+        frame.extend_from_slice(&[0u8; 4]);
+        frame.extend_from_slice(&910_525u32.to_le_bytes());
+        frame.extend_from_slice(&62_500u32.to_le_bytes());
+        frame.extend_from_slice(&[7, 5]);
+        frame.extend_from_slice(b"base");
+        let self_info = SelfInfo::from_frame(&frame);
+        let contact = |key: u8, name: &str, adv_type: u8, lat: i32, lon: i32| Contact {
+            public_key: PublicKey::from_bytes([key; 32]),
+            adv_type,
+            flags: 0,
+            out_path_len: -1,
+            out_path: [0u8; 64],
+            adv_name: name.to_string(),
+            last_advert: 1_700_000_000,
+            adv_lat: lat,
+            adv_lon: lon,
+            lastmod: 0,
+            logged_in: None,
+        };
+        let contacts = vec![
+            contact(1, "north", 2, 47_100_000, -122_000_000),
+            contact(2, "nowhere", 1, 0, 0),
+            contact(3, "east <&>", 1, 47_000_000, -121_900_000),
+        ];
+
+        let nodes = node_positions(Some(&self_info), &contacts);
+        assert_eq!(nodes.len(), 3);
+        assert!(nodes[0].is_self);
+        assert_eq!(nodes[0].position, Position { lat: 47.0, lon: -122.0 });
+        let north = &nodes[1];
+        assert!((north.distance_km.unwrap() - 11.12).abs() < 0.01);
+        assert!(north.bearing_deg.unwrap().abs() < 0.01);
+        assert!((nodes[2].bearing_deg.unwrap() - 90.0).abs() < 0.1);
+
+        let geojson: serde_json::Value = serde_json::from_str(&to_geojson(&nodes)).unwrap();
+        assert_eq!(geojson["features"].as_array().unwrap().len(), 3);
+        assert_eq!(geojson["features"][1]["geometry"]["coordinates"], serde_json::json!([-122.0, 47.1]));
+        assert_eq!(geojson["features"][1]["properties"]["type"], "repeater");
+        let gpx = to_gpx(&nodes);
+        assert!(gpx.contains("<wpt lat=\"47.100000\" lon=\"-122.000000\"><time>2023-11-14T22:13:20Z</time><name>north</name>"));
+        assert!(gpx.contains("<name>east &lt;&amp;&gt;</name>"));
+        let kml = to_kml(&nodes);
+        assert_eq!(kml.matches("<Placemark>").count(), 3);
+        assert!(kml.contains("<coordinates>-121.900000,47.000000</coordinates>"));
+    }
+
+    #[test]
+    fn dissect_advert_and_ack_payloads() {
+        // Flood advert from a repeater with a location and a name
+        let mut raw = vec![0x11, 0x00];
+        raw.extend_from_slice(&[0xab; 32]);
+        raw.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        raw.extend_from_slice(&[0x55; 64]);
+        raw.push(0x80 | 0x10 | 0x02);
+        raw.extend_from_slice(&47_500_000i32.to_le_bytes());
+        raw.extend_from_slice(&(-122_250_000i32).to_le_bytes());
+        raw.extend_from_slice(b"hilltop");
+        let event = RxLogEvent::new(-2.5, -90, raw);
+        assert_eq!(event.packet.as_ref().unwrap().payload_type, PayloadType::Advert);
+        let Ok(Payload::Advert(advert)) = event.payload else {
+            panic!("expected an advert, got {:?}", event.payload);
+        };
+        assert_eq!(advert.public_key.bytes, [0xab; 32]);
+        assert_eq!(advert.timestamp, 1_700_000_000);
+        assert_eq!(advert.node_type, AdvertType::Repeater);
+        assert_eq!(advert.location, Some((47.5, -122.25)));
+        assert_eq!(advert.feature1, None);
+        assert_eq!(advert.name.as_deref(), Some("hilltop"));
+
+        let ack = Packet::parse(&[0x0e, 0x01, 0xaa, 0x78, 0x56, 0x34, 0x12]).unwrap();
+        assert_eq!(ack.route, PacketRoute::Direct);
+        assert_eq!(ack.decode_payload(), Ok(Payload::Ack { checksum: 0x12345678 }));
+
+        let truncated = RxLogEvent::new(0.0, 0, vec![0x0e, 0x00, 0x78]);
+        assert!(truncated.packet.is_ok());
+        assert_eq!(truncated.payload, Err(PacketError::Truncated("ACK checksum")));
+    }
+    #[tokio::test]
+    async fn channel_send_confirmed_by_repeat_in_rx_log() {
+        let companion = Companion::new("/dev/null");
+        let msg = SendChannelTxtMsg {
+            code: crate::consts::CMD_SEND_CHANNEL_TXT_MSG,
+            txt_type: 0,
+            channel_idx: 0,
+            sender_timestamp: 1,
+            text: "hi".to_string(),
+        };
+        let envelope = ChannelEnvelope::new(msg, "");
+        assert_eq!(envelope.expected_payload_len, 19);
+        companion.state.write().await.pending_channel_msgs.push(envelope);
+
+        let rx_log = |path: &[u8], payload: [u8; 19]| {
+            let mut frame = vec![crate::consts::PUSH_CODE_LOG_RX_DATA, 0x28, 0xb0, 0x15, path.len() as u8];
+            frame.extend_from_slice(path);
+            frame.extend_from_slice(&payload);
+            SerialFrame::from_data(frame)
+        };
+        let mut ours = [0u8; 19];
+        ours[0] = 0x11;
+        // too short to parse, must not stop the frames after it
+        companion.from_radio_tx.send(SerialFrame::from_data(vec![crate::consts::PUSH_CODE_LOG_RX_DATA, 0x28])).await.unwrap();
+        // same size but relayed twice already, so not a first-hop repeat of ours
+        companion.from_radio_tx.send(rx_log(&[0x01, 0x02], [0x22; 19])).await.unwrap();
+        companion.from_radio_tx.send(rx_log(&[0xab], ours)).await.unwrap();
+        // same size and channel, different ciphertext
+        companion.from_radio_tx.send(rx_log(&[0xcd], [0x11; 19])).await.unwrap();
+        companion.from_radio_tx.send(rx_log(&[0xab, 0xef], ours)).await.unwrap();
+        check_internal(companion.state.clone()).await.unwrap();
+        let state = companion.state.read().await;
+        let repeaters = &state.pending_channel_msgs[0].repeaters;
+        assert_eq!(repeaters.len(), 2);
+        assert!(repeaters.contains(&0xab) && repeaters.contains(&0xef));
+        assert_eq!(state.channel_hashes.get(&0), Some(&0x11));
+    }
+
+    #[tokio::test]
+    async fn received_messages_are_published_and_queued() {
+        let companion = Companion::new("/dev/null");
+        let mut events = companion.subscribe();
+        let mut frame = vec![crate::consts::RESP_CODE_CONTACT_MSG_RECV, 7, 0, 0, 0, 0, 0, 0xff, 0];
+        frame.extend_from_slice(&5u32.to_le_bytes());
+        frame.extend_from_slice(b"ping");
+        companion.from_radio_tx.send(SerialFrame::from_data(frame)).await.unwrap();
+        check_internal(companion.state.clone()).await.unwrap();
+
+        let Ok(CompanionEvent::Message(msg)) = events.try_recv() else {
+            panic!("expected a message event");
+        };
+        assert_eq!(msg.text(), "ping");
+        assert!(matches!(msg.source(), MessageSource::Contact(prefix) if prefix.bytes() == [7, 0, 0, 0, 0, 0]));
+        assert_eq!(companion.pop_received().await, Some(msg));
+    }
+
+    #[tokio::test]
+    async fn handshake_times_out_naming_missing_responses() {
+        let mut companion = Companion::new("/dev/null");
+        let _to_radio = companion.to_radio_rx.take().unwrap();
+        let config = HandshakeConfig {
+            timeout: Duration::from_millis(100),
+            ..HandshakeConfig::default()
+        };
+        match companion.handshake(&config).await {
+            Err(AppError::Timeout(reason)) => {
+                assert!(reason.contains("self info"));
+                assert!(reason.contains("end of contacts"));
+            }
+            other => panic!("expected a timeout, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_completes_handshake_with_device_answers() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let radio = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            loop {
+                let mut header = [0u8; 3];
+                if socket.read_exact(&mut header).await.is_err() {
+                    break;
+                }
+                assert_eq!(header[0], SERIAL_OUTBOUND);
+                let mut command = vec![0u8; u16::from_le_bytes([header[1], header[2]]) as usize];
+                socket.read_exact(&mut command).await.unwrap();
+                let response = match command[0] {
+                    crate::consts::CMD_APP_START => {
+                        let mut frame = vec![crate::consts::RESP_CODE_SELF_INFO, 1, 22, 22];
+                        frame.extend_from_slice(&[9u8; 32]);
+                        frame.extend_from_slice(&[0u8; 12]);
+                        frame.extend_from_slice(&910_525u32.to_le_bytes());
+                        frame.extend_from_slice(&62_500u32.to_le_bytes());
+                        frame.extend_from_slice(&[7, 5]);
+                        frame.extend_from_slice(b"node");
+                        frame
+                    }
+                    crate::consts::CMD_DEVICE_QEURY => {
+                        let mut frame = vec![crate::consts::RESP_CODE_DEVICE_INFO, 3, 175, 8];
+                        frame.extend_from_slice(&[0u8; 4]);
+                        frame.extend_from_slice(b"01 Jan 2025\0");
+                        frame
+                    }
+                    crate::consts::CMD_GET_CONTACTS => {
+                        let mut frame = vec![crate::consts::RESP_CODE_END_OF_CONTACTS];
+                        frame.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+                        frame
+                    }
+                    _ => continue,
+                };
+                let mut framed = vec![SERIAL_INBOUND];
+                framed.extend_from_slice(&(response.len() as u16).to_le_bytes());
+                framed.extend_from_slice(&response);
+                socket.write_all(&framed).await.unwrap();
+            }
+        });
+
+        let companion = tokio::time::timeout(
+            Duration::from_secs(5),
+            crate::CompanionBuilder::tcp(&addr).handshake_timeout(Duration::from_secs(3)).connect(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let self_info = companion.get_self_info().await.unwrap();
+        assert_eq!(self_info.name(), "node");
+        assert_eq!(self_info.radio_parameters().radio_freq, 910_525);
+        let caps = companion.capabilities().await.unwrap();
+        assert_eq!(caps.max_contacts, 350);
+        assert_eq!(caps.firmware_build_date, "01 Jan 2025");
+        let state = companion.state.read().await;
+        assert!(state.contacts_synced);
+        assert_eq!(state.newest_advert_time, 1_700_000_000);
+        drop(state);
+        companion.shutdown().await;
+        radio.abort();
+    }
+
+    #[test]
+    fn app_start_frame_round_trip() {
+        let app = AppStart {
+            app_ver: 3,
+            reserved: [1, 2, 3, 4, 5, 6],
+            app_name: "bot".to_string(),
+            ..AppStart::default()
+        };
+        // the builder handshake announces the same version as a hand-built AppStart
+        assert_eq!(HandshakeConfig::default().app_version, AppStart::default().app_ver);
+        let frame = app.to_frame();
+        assert_eq!(frame, vec![crate::consts::CMD_APP_START, 3, 1, 2, 3, 4, 5, 6, b'b', b'o', b't']);
+        assert_eq!(AppStart::from_frame(&frame), app);
+    }
+
+    #[test]
+    fn reboot_frame_round_trip() {
+        let frame = Reboot::default().to_frame();
+        assert_eq!(frame, vec![0x13, 0x72, 0x65, 0x62, 0x6f, 0x6f, 0x74]);
+        assert_eq!(Reboot::from_frame(&frame), Reboot::default());
+    }
+
+    #[tokio::test]
+    async fn capabilities_reject_commands_the_firmware_lacks() {
+        let mut companion = Companion::new("/dev/null");
+        let _to_radio = companion.to_radio_rx.take().unwrap();
+        assert!(companion.capabilities().await.is_none());
+
+        let mut frame = vec![crate::consts::RESP_CODE_DEVICE_INFO, 3, 175, 8];
+        frame.extend_from_slice(&[0u8; 4]);
+        frame.extend_from_slice(b"01 Jan 2025\0");
+        companion.state.write().await.device_info = Some(DeviceInfo::from_frame(&frame));
+
+        let caps = companion.capabilities().await.unwrap();
+        assert_eq!(caps.max_contacts, 350);
+        assert_eq!(caps.max_channels, 8);
+        assert_eq!(caps.firmware_build_date, "01 Jan 2025");
+        assert!(!caps.v3_messages, "no device query with a target version was sent");
+        assert!(caps.optional_commands().contains(&crate::consts::CMD_SEND_TRACE_PATH));
+        assert!(!caps.optional_commands().contains(&crate::consts::CMD_GET_STATS));
+        companion.state.write().await.app_target_ver = 3;
+        assert!(companion.capabilities().await.unwrap().v3_messages);
+
+        assert_eq!(
+            companion.command(Commands::CmdGetStats).await,
+            Err(AppError::UnsupportedCommand(Commands::CmdGetStats))
+        );
+        assert!(companion.command(Commands::CmdGetBattAndStorage).await.is_ok());
+    }
+
+    #[test]
+    fn radio_parameters_are_validated() {
+        assert_eq!(RadioParameters::new(299_999, 62_500, 7, 5), Err(RadioParamsError::Frequency(299_999)));
+        assert_eq!(RadioParameters::new(910_525, 6_000, 7, 5), Err(RadioParamsError::Bandwidth(6_000)));
+        assert_eq!(RadioParameters::new(910_525, 62_500, 13, 5), Err(RadioParamsError::SpreadingFactor(13)));
+        assert_eq!(RadioParameters::new(910_525, 62_500, 7, 4), Err(RadioParamsError::CodingRate(4)));
+        let usa = RadioParameters::from_mhz(910.525, 62.5, 7, 5).unwrap();
+        assert_eq!(usa, crate::consts::USA_RADIO_PRESET);
+        assert_eq!(usa.to_string(), "910.525 MHz, BW 62.5 kHz, SF7, CR 4/5");
+        assert_eq!(RadioPreset::matching(&usa), Some(RadioPreset::Usa));
+        assert_eq!(RadioPreset::from_name("eu/uk (narrow)"), Some(RadioPreset::EuUkNarrow));
+        for preset in RadioPreset::ALL {
+            assert!(preset.parameters().validate().is_ok(), "{}", preset.name());
+            // matching() would report the wrong preset for a duplicate
+            assert_eq!(RadioPreset::matching(&preset.parameters()), Some(preset), "{}", preset.name());
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_radio_parameters_are_not_sent() {
+        let mut companion = Companion::new("/dev/null");
+        let mut to_radio = companion.to_radio_rx.take().unwrap();
+        let params = RadioParameters { radio_sf: 4, ..crate::consts::USA_RADIO_PRESET };
+        assert_eq!(
+            companion.command(Commands::CmdSetRadioParams(params)).await,
+            Err(AppError::RadioParams(RadioParamsError::SpreadingFactor(4)))
+        );
+        assert!(to_radio.try_recv().is_err());
+    }
+
+    #[test]
+    fn airtime_and_duty_cycle_window() {
+        let usa = crate::consts::USA_RADIO_PRESET;
+        assert_eq!(airtime(&usa, 20).as_micros(), 129_536);
+        let long_range = RadioParameters::new(869_525, 125_000, 12, 5).unwrap();
+        assert!(airtime(&long_range, 20) > Duration::from_secs(1));
+
+        let config = DutyCycleConfig { budget: 0.01, window: Duration::from_secs(100), ..DutyCycleConfig::default() };
+        let mut tracker = DutyCycleTracker::default();
+        let start = Instant::now();
+        assert_eq!(tracker.wait_for(start, Duration::from_millis(600), &config), Some(Duration::ZERO));
+        tracker.record(start, Duration::from_millis(600));
+        assert_eq!(tracker.wait_for(start, Duration::from_millis(600), &config), Some(Duration::from_secs(100)));
+        assert_eq!(tracker.wait_for(start, Duration::from_secs(2), &config), None);
+        let later = start + Duration::from_secs(100);
+        assert_eq!(tracker.status(later, &config).used, Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_rejects_sends_over_budget() {
+        let mut companion = Companion::new("/dev/null");
+        let mut to_radio = companion.to_radio_rx.take().unwrap();
+        let mut frame = vec![crate::consts::RESP_CODE_SELF_INFO, 1, 22, 22];
+        frame.extend_from_slice(&[9u8; 32]);
+        frame.extend_from_slice(&[0u8; 12]);
+        frame.extend_from_slice(&910_525u32.to_le_bytes());
+        frame.extend_from_slice(&62_500u32.to_le_bytes());
+        frame.extend_from_slice(&[7, 5]);
+        frame.extend_from_slice(b"node");
+        companion.state.write().await.self_info = Some(SelfInfo::from_frame(&frame));
+        companion
+            .set_duty_cycle_config(DutyCycleConfig {
+                limit: true,
+                budget: 0.1,
+                window: Duration::from_secs(2),
+                mode: RateLimitMode::Reject,
+                ..DutyCycleConfig::default()
+            })
+            .await;
+        let msg = |text: &str| SendChannelTxtMsg {
+            code: crate::consts::CMD_SEND_CHANNEL_TXT_MSG,
+            txt_type: 0,
+            channel_idx: 0,
+            sender_timestamp: 1,
+            text: text.to_string(),
+        };
+        let path = std::env::temp_dir().join(format!("meshcore_rate_history_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        companion.enable_history(HistoryStore::open(&path).unwrap()).await;
+        assert!(companion.command(Commands::CmdSendChannelTxtMsg(msg("first"))).await.is_ok());
+        assert!(companion.duty_cycle_status().await.used > Duration::ZERO);
+        assert!(matches!(
+            companion.command(Commands::CmdSendChannelTxtMsg(msg("second"))).await,
+            Err(AppError::DutyCycle(_))
+        ));
+        // only the message that went out is tracked and recorded
+        let thread = companion.history_thread(&ConversationKey::Channel(0), None, 10).await.unwrap();
+        assert_eq!(thread.iter().map(|e| (e.text.as_str(), e.status)).collect::<Vec<_>>(), vec![("first", DeliveryStatus::Pending)]);
+        assert_eq!(companion.state.read().await.pending_channel_msgs.len(), 1);
+        let _ = std::fs::remove_file(&path);
+
+        // retries that do not fit the budget stay queued for a later sweep
+        while to_radio.try_recv().is_ok() {}
+        companion
+            .set_channel_policy(ChannelPolicy { repeat_window: Duration::ZERO, ..ChannelPolicy::default() })
+            .await;
+        let mut envelope = MessageEnvelope::new(SendingMessageTypes::TxtMsg(SendTxtMsg {
+            code: CMD_SEND_TXT_MSG,
+            txt_type: 0,
+            attempt: 0,
+            sender_timestamp: 1,
+            pubkey_prefix: [7u8; 6],
+            text: "ping".to_string(),
+            timeout: Some(100),
+        }));
+        envelope.last_attempt_timestamp = 0;
+        companion.state.write().await.pending_acks.insert(AckCode([1, 2, 3, 4]), envelope);
+        check_internal(companion.state.clone()).await.unwrap();
+        while let Ok(sent) = to_radio.try_recv() {
+            assert!(![CMD_SEND_TXT_MSG, crate::consts::CMD_SEND_CHANNEL_TXT_MSG].contains(&sent.frame[0]));
+        }
+        let state = companion.state.read().await;
+        assert!(state.pending_acks.contains_key(&AckCode([1, 2, 3, 4])));
+        assert_eq!(state.pending_channel_msgs.len(), 1);
+        assert_eq!(state.pending_channel_msgs[0].attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn retries_larger_than_the_allowance_fail() {
+        let mut companion = Companion::new("/dev/null");
+        let mut to_radio = companion.to_radio_rx.take().unwrap();
+        let mut frame = vec![crate::consts::RESP_CODE_SELF_INFO, 1, 22, 22];
+        frame.extend_from_slice(&[9u8; 32]);
+        frame.extend_from_slice(&[0u8; 12]);
+        frame.extend_from_slice(&910_525u32.to_le_bytes());
+        frame.extend_from_slice(&62_500u32.to_le_bytes());
+        frame.extend_from_slice(&[7, 5]);
+        frame.extend_from_slice(b"node");
+        companion.state.write().await.self_info = Some(SelfInfo::from_frame(&frame));
+        // 10ms of airtime is less than any single packet
+        companion
+            .set_duty_cycle_config(DutyCycleConfig {
+                limit: true,
+                budget: 0.1,
+                window: Duration::from_millis(100),
+                mode: RateLimitMode::Reject,
+                ..DutyCycleConfig::default()
+            })
+            .await;
+        companion
+            .set_channel_policy(ChannelPolicy { repeat_window: Duration::ZERO, ..ChannelPolicy::default() })
+            .await;
+        let dm = |text: &str| SendTxtMsg {
+            code: CMD_SEND_TXT_MSG,
+            txt_type: 0,
+            attempt: 0,
+            sender_timestamp: 1,
+            pubkey_prefix: [7u8; 6],
+            text: text.to_string(),
+            timeout: Some(100),
+        };
+        let channel_msg = |text: &str| SendChannelTxtMsg {
+            code: crate::consts::CMD_SEND_CHANNEL_TXT_MSG,
+            txt_type: 0,
+            channel_idx: 0,
+            sender_timestamp: 1,
+            text: text.to_string(),
+        };
+        {
+            let mut state = companion.state.write().await;
+            let mut envelope = MessageEnvelope::new(SendingMessageTypes::TxtMsg(dm("ping")));
+            envelope.last_attempt_timestamp = 0;
+            state.pending_acks.insert(AckCode([1, 2, 3, 4]), envelope);
+            state.pending_channel_msgs.push(ChannelEnvelope::new(channel_msg("hello"), "node"));
+            state.outbound_parts.push_back(Commands::CmdSendChannelTxtMsg(channel_msg("part")));
+        }
+        check_internal(companion.state.clone()).await.unwrap();
+        while let Ok(sent) = to_radio.try_recv() {
+            assert!(![CMD_SEND_TXT_MSG, crate::consts::CMD_SEND_CHANNEL_TXT_MSG].contains(&sent.frame[0]));
+        }
+        let report = companion.pop_delivery_report().await.unwrap();
+        assert_eq!((report.outcome, report.attempts), (DeliveryOutcome::Failed, 1));
+        let reports = [companion.pop_channel_report().await.unwrap(), companion.pop_channel_report().await.unwrap()];
+        assert!(reports.iter().all(|r| r.outcome == DeliveryOutcome::Failed));
+        let state = companion.state.read().await;
+        assert!(state.pending_acks.is_empty() && state.pending_channel_msgs.is_empty() && state.outbound_parts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn transport_writes_immediately_and_wakes_on_reads() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (client, mut radio) = tokio::io::duplex(256);
+        let (to_radio_tx, mut to_radio_rx) = tokio::sync::mpsc::channel(4);
+        let (from_radio_tx, mut from_radio_rx) = tokio::sync::mpsc::channel(4);
+        let transport = tokio::spawn(async move { run_transport(client, &mut to_radio_rx, &from_radio_tx, None).await });
+
+        to_radio_tx.send(SerialFrame::from_data(vec![0x16, 0x03])).await.unwrap();
+        let mut written = [0u8; 5];
+        radio.read_exact(&mut written).await.unwrap();
+        assert_eq!(written, [SERIAL_OUTBOUND, 2, 0, 0x16, 0x03]);
+
+        // line noise ahead of the frame is skipped up to the next delimiter
+        radio.write_all(&[0x00, 0x88, 0x2d]).await.unwrap();
+        // one frame split across two writes, followed by a second frame
+        radio.write_all(&[SERIAL_INBOUND, 3, 0, 0x0a]).await.unwrap();
+        radio.write_all(&[0x0b, 0x0c, SERIAL_INBOUND, 1, 0, 0x0d]).await.unwrap();
+        let first = tokio::time::timeout(Duration::from_secs(1), from_radio_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(first.frame, vec![0x0a, 0x0b, 0x0c]);
+        let second = from_radio_rx.recv().await.unwrap();
+        assert_eq!(second.frame, vec![0x0d]);
+
+        drop(radio);
+        let result = tokio::time::timeout(Duration::from_secs(1), transport).await.unwrap().unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn recorded_session_replays_into_companion() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let path = std::env::temp_dir().join(format!("meshcore_session_{}.jsonl", std::process::id()));
+        let recorder = SessionRecorder::create(&path).unwrap();
+        let (client, mut radio) = tokio::io::duplex(256);
+        let (to_radio_tx, mut to_radio_rx) = tokio::sync::mpsc::channel(4);
+        let (from_radio_tx, mut from_radio_rx) = tokio::sync::mpsc::channel(4);
+        let transport = tokio::spawn(async move {
+            run_transport(client, &mut to_radio_rx, &from_radio_tx, Some(recorder)).await
+        });
+
+        to_radio_tx
+            .send(SerialFrame::from_data(vec![crate::consts::CMD_GET_BATT_AND_STORAGE]))
+            .await
+            .unwrap();
+        let mut command = [0u8; 4];
+        radio.read_exact(&mut command).await.unwrap();
+        let mut response = vec![SERIAL_INBOUND, 11, 0, crate::consts::RESP_CODE_BATT_AND_STORAGE];
+        response.extend_from_slice(&3900u16.to_le_bytes());
+        response.extend_from_slice(&10u32.to_le_bytes());
+        response.extend_from_slice(&1000u32.to_le_bytes());
+        radio.write_all(&response).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), from_radio_rx.recv()).await.unwrap().unwrap();
+        drop(radio);
+        let _ = tokio::time::timeout(Duration::from_secs(1), transport).await.unwrap();
+
+        let frames = read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let directions: Vec<Direction> = frames.iter().map(|f| f.direction).collect();
+        assert_eq!(directions, vec![Direction::Outbound, Direction::Inbound]);
+        assert_eq!(frames[1].frame, response[3..].to_vec());
+
+        // lockstep replay holds the response back until the command is sent again
+        let mut companion = Companion::replay(ReplayTransport::new(frames, ReplayPacing::Lockstep));
+        companion.start().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(companion.battery_millivolts().await, None);
+        companion.command(Commands::CmdGetBattAndStorage).await.unwrap();
+        let millivolts = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let Some(millivolts) = companion.battery_millivolts().await {
+                    break millivolts;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(millivolts, 3900);
+        companion.shutdown().await;
+        assert_eq!(companion.connection_state(), ConnectionState::Closed);
+    }
+
+    #[tokio::test]
+    async fn tcp_companion_waits_for_command_result() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let radio = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            loop {
+                let mut header = [0u8; 3];
+                socket.read_exact(&mut header).await.unwrap();
+                assert_eq!(header[0], SERIAL_OUTBOUND);
+                let mut frame = vec![0u8; u16::from_le_bytes([header[1], header[2]]) as usize];
+                socket.read_exact(&mut frame).await.unwrap();
+                if frame[0] == crate::consts::CMD_SET_ADVERT_NAME {
+                    assert_eq!(&frame[1..], b"tcp node");
+                    socket
+                        .write_all(&[SERIAL_INBOUND, 1, 0, crate::consts::RESP_CODE_OK])
+                        .await
+                        .unwrap();
+                    return socket;
+                }
+            }
+        });
+
+        let mut companion = Companion::tcp(&addr);
+        companion.start().await.unwrap();
+        let cmd = Commands::CmdSetAdvertName("tcp node".to_string());
+        let done = companion.command_and_wait(cmd.clone(), Duration::from_secs(2)).await.unwrap();
+        assert_eq!(done, cmd);
+        let _socket = radio.await.unwrap();
+
+        let err = companion
+            .command_and_wait(cmd, Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::Timeout(_)));
+        companion.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn rpc_serves_methods_and_events_to_several_clients() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        let path = std::env::temp_dir().join(format!("meshcored_{}.sock", std::process::id()));
+        let companion = std::sync::Arc::new(Companion::new("/dev/null"));
+        let (stop, shutdown) = tokio::sync::watch::channel(false);
+        let server = tokio::spawn(crate::rpc::serve_unix(companion.clone(), path.clone(), shutdown));
+        let connect = || async {
+            for _ in 0..50 {
+                if let Ok(stream) = tokio::net::UnixStream::connect(&path).await {
+                    let (reader, writer) = stream.into_split();
+                    return (BufReader::new(reader).lines(), writer);
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            panic!("RPC socket never came up");
+        };
+        let (mut lines_a, mut writer_a) = connect().await;
+        let (mut lines_b, mut writer_b) = connect().await;
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let call = async |writer: &mut tokio::net::unix::OwnedWriteHalf, lines: &mut tokio::io::Lines<_>, request: &str| {
+            writer.write_all(format!("{request}\n").as_bytes()).await.unwrap();
+            let line: Option<String> = tokio::time::timeout(Duration::from_secs(2), lines.next_line()).await.unwrap().unwrap();
+            serde_json::from_str::<serde_json::Value>(&line.unwrap()).unwrap()
+        };
+
+        let response = call(&mut writer_a, &mut lines_a, r#"{"jsonrpc":"2.0","method":"contacts","id":1}"#).await;
+        assert_eq!(response["result"], serde_json::json!([]));
+        let response = call(&mut writer_b, &mut lines_b, r#"{"jsonrpc":"2.0","method":"nope","id":"x"}"#).await;
+        assert_eq!(response["error"]["code"], crate::rpc::METHOD_NOT_FOUND);
+        assert_eq!(response["id"], "x");
+        let response = call(&mut writer_b, &mut lines_b, r#"{"jsonrpc":"2.0","method":"command","params":{"CmdSetAdvertName":7},"id":2}"#).await;
+        assert_eq!(response["error"]["code"], crate::rpc::INVALID_PARAMS);
+        let reset = format!(r#"{{"jsonrpc":"2.0","method":"command","params":{{"CmdResetPath":"{}"}},"id":4}}"#, "ab".repeat(32));
+        let response = call(&mut writer_b, &mut lines_b, &reset).await;
+        assert_eq!(response["result"], serde_json::Value::Null);
+        assert_eq!(companion.state.read().await.command_queue.back(), Some(&Commands::CmdResetPath(PublicKey { bytes: [0xab; 32] })));
+        let response = call(&mut writer_b, &mut lines_b, r#"{"jsonrpc":"2.0","method":"command","params":"CmdGetStats","id":5}"#).await;
+        assert_eq!(response["error"]["code"], crate::rpc::COMPANION_ERROR);
+        let response = call(&mut writer_a, &mut lines_a, r#"{"jsonrpc":"2.0","method":"subscribe","id":3}"#).await;
+        assert_eq!(response["result"], true);
+
+        companion.state.read().await.publish(CompanionEvent::Health(crate::health::HealthReading::new(3900, 10, 100)));
+        let line = tokio::time::timeout(Duration::from_secs(2), lines_a.next_line()).await.unwrap().unwrap().unwrap();
+        let notification: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(notification["method"], "event");
+        assert_eq!(notification["params"]["Health"]["millivolts"], 3900);
+
+        stop.send_replace(true);
+        tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap().unwrap();
+        assert!(!path.exists());
+
+        // a regular file in the way is left alone
+        std::fs::write(&path, b"keep").unwrap();
+        let (_stop, shutdown) = tokio::sync::watch::channel(false);
+        assert!(matches!(crate::rpc::serve_unix(companion.clone(), path.clone(), shutdown).await, Err(AppError::Rpc(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), b"keep");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn gateway_requires_token_and_streams_events() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let companion = std::sync::Arc::new(Companion::new("/dev/null"));
+        let (stop, shutdown) = tokio::sync::watch::channel(false);
+        let spawn_server = async |config: crate::gateway::GatewayConfig| {
+            let router = crate::gateway::router(companion.clone(), config, shutdown.clone());
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut stopping = shutdown.clone();
+            let server = tokio::spawn(async move {
+                let stopped = async move {
+                    let _ = stopping.wait_for(|stop| *stop).await;
+                };
+                axum::serve(listener, router).with_graceful_shutdown(stopped).await
+            });
+            (addr, server)
+        };
+        let config = crate::gateway::GatewayConfig { token: "secret".to_string(), ..Default::default() };
+        let (addr, server) = spawn_server(config).await;
+        let request_to = async |addr: std::net::SocketAddr, head: &str| {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("{head}Host: test\r\nConnection: close\r\n\r\n").as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            let (status, body) = response.split_once("\r\n\r\n").unwrap();
+            (status.split(' ').nth(1).unwrap().to_string(), body.to_string())
+        };
+        let request = async |head: &str| request_to(addr, head).await;
+
+        let (status, _) = request("GET /api/contacts HTTP/1.1\r\n").await;
+        assert_eq!(status, "401");
+        let (status, _) = request("GET /api/contacts HTTP/1.1\r\nAuthorization: Bearer wrong\r\n").await;
+        assert_eq!(status, "401");
+        let (status, body) = request("GET /api/contacts HTTP/1.1\r\nAuthorization: Bearer secret\r\n").await;
+        assert_eq!((status.as_str(), body.as_str()), ("200", "[]"));
+        let (status, body) = request("GET /api/contacts/alice HTTP/1.1\r\nAuthorization: Bearer secret\r\n").await;
+        assert_eq!(status, "404");
+        assert!(body.contains("no contact named or keyed alice"));
+        let (status, body) = request("GET /api/channels HTTP/1.1\r\nAuthorization: Bearer secret\r\n").await;
+        assert_eq!(status, "200");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()[0]["index"], 0);
+        // draining the inbox is not safe to repeat, so it is not a GET
+        let (status, _) = request("GET /api/messages/pop HTTP/1.1\r\nAuthorization: Bearer secret\r\n").await;
+        assert_eq!(status, "405");
+        let (status, body) = request("POST /api/messages/pop HTTP/1.1\r\nAuthorization: Bearer secret\r\n").await;
+        assert_eq!((status.as_str(), body.as_str()), ("200", "[]"));
+
+        let mut ws = tokio::net::TcpStream::connect(addr).await.unwrap();
+        ws.write_all(
+            b"GET /api/events?access_token=secret HTTP/1.1\r\nHost: test\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(ws.read_u8().await.unwrap());
+        }
+        assert!(String::from_utf8(head).unwrap().starts_with("HTTP/1.1 101"));
+        // The subscription starts once the upgrade completes, so publish until a frame arrives
+        let mut frame_head = [0u8; 2];
+        for _ in 0..20 {
+            companion.state.read().await.publish(CompanionEvent::Health(crate::health::HealthReading::new(3900, 10, 100)));
+            if tokio::time::timeout(Duration::from_millis(100), ws.read_exact(&mut frame_head)).await.is_ok() {
+                break;
+            }
+        }
+        assert_eq!(frame_head[0], 0x81);
+        let len = match frame_head[1] {
+            126 => ws.read_u16().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0u8; len];
+        ws.read_exact(&mut payload).await.unwrap();
+        let event: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(event["Health"]["millivolts"], 3900);
+
+        // a router built without a token must not let an empty bearer through
+        let (open_addr, open_server) = spawn_server(crate::gateway::GatewayConfig::default()).await;
+        let (status, _) = request_to(open_addr, "GET /api/contacts HTTP/1.1\r\nAuthorization: Bearer \r\n").await;
+        assert_eq!(status, "401");
+        let (status, _) = request_to(open_addr, "GET /api/contacts?access_token= HTTP/1.1\r\n").await;
+        assert_eq!(status, "401");
+
+        stop.send_replace(true);
+        tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap().unwrap();
+        tokio::time::timeout(Duration::from_secs(2), open_server).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_tasks_while_waiting_for_port() {
+        let mut companion = Companion::new("/dev/meshcore-test-missing-port");
+        let mut connection = companion.watch_connection();
+        companion.start().await.unwrap();
+        assert_eq!(companion.connection_state(), ConnectionState::Connecting);
+        tokio::time::timeout(Duration::from_secs(2), companion.shutdown()).await.unwrap();
+        assert_eq!(*connection.borrow_and_update(), ConnectionState::Closed);
+        assert!(companion.tasks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn drifted_device_clock_is_resynced() {
+        let mut companion = Companion::new("/dev/null");
+        let mut to_radio = companion.to_radio_rx.take().unwrap();
+        let behind = crate::clock::host_time() - 3600;
+        let mut frame = vec![crate::consts::RESP_CODE_CURR_TIME];
+        frame.extend_from_slice(&behind.to_le_bytes());
+        companion.from_radio_tx.send(SerialFrame::from_data(frame.clone())).await.unwrap();
+        check_internal(companion.state.clone()).await.unwrap();
+
+        let drift = companion.clock_drift().await.unwrap();
+        assert!((-3602..=-3598).contains(&drift));
+        let set_time = to_radio.try_recv().unwrap();
+        assert_eq!(set_time.frame[0], crate::consts::CMD_SET_DEVICE_TIME);
+        assert_eq!(set_time.frame.len(), 5);
+        assert_eq!(to_radio.try_recv().unwrap().frame, vec![crate::consts::CMD_GET_DEVICE_TIME]);
+
+        // a device that refuses the new time is not hammered with retries
+        companion.from_radio_tx.send(SerialFrame::from_data(frame)).await.unwrap();
+        check_internal(companion.state.clone()).await.unwrap();
+        assert!(to_radio.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn battery_and_storage_readings_raise_threshold_events() {
+        assert_eq!(lipo_percent(4200), 100);
+        assert_eq!(lipo_percent(3000), 0);
+        assert_eq!(lipo_percent(3825), 50);
+
+        let companion = Companion::new("/dev/null");
+        companion.set_health_config(HealthConfig { history_len: 2, ..HealthConfig::default() }).await;
+        let mut events = companion.subscribe();
+        let reading = |millivolts: u16, used_kb: u32| {
+            let mut frame = vec![crate::consts::RESP_CODE_BATT_AND_STORAGE];
+            frame.extend_from_slice(&millivolts.to_le_bytes());
+            frame.extend_from_slice(&used_kb.to_le_bytes());
+            frame.extend_from_slice(&1000u32.to_le_bytes());
+            SerialFrame::from_data(frame)
+        };
+        for (millivolts, used_kb) in [(4100, 100), (3700, 950), (4000, 500)] {
+            companion.from_radio_tx.send(reading(millivolts, used_kb)).await.unwrap();
+            check_internal(companion.state.clone()).await.unwrap();
+        }
+
+        let mut raised = vec![];
+        while let Ok(event) = events.try_recv() {
+            match event {
+                CompanionEvent::Health(_) => (),
+                CompanionEvent::BatteryLow(r) => raised.push(("battery low", r.millivolts)),
+                CompanionEvent::BatteryRecovered(r) => raised.push(("battery recovered", r.millivolts)),
+                CompanionEvent::StorageHigh(r) => raised.push(("storage high", r.millivolts)),
+                CompanionEvent::StorageRecovered(r) => raised.push(("storage recovered", r.millivolts)),
+                _ => (),
+            }
+        }
+        assert_eq!(
+            raised,
+            vec![
+                ("battery low", 3700),
+                ("storage high", 3700),
+                ("battery recovered", 4000),
+                ("storage recovered", 4000),
+            ]
+        );
+        assert_eq!(companion.storage_kb().await, Some((500, 1000)));
+        assert_eq!(companion.battery_millivolts().await, Some(4000));
+        let history = companion.health_history().await;
+        assert_eq!(history.iter().map(|r| r.millivolts).collect::<Vec<_>>(), vec![3700, 4000]);
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn mqtt_bridge_topics_and_commands() {
+        use crate::mqtt::{event_messages, parse_command, BridgeCommand, DmRequest, MqttConfig, MqttTopics};
+        let topics = MqttTopics::with_prefix("home/mesh/");
+        let config = MqttConfig { topics: topics.clone(), ..MqttConfig::default() };
+        let mut frame = vec![crate::consts::RESP_CODE_CONTACT_MSG_RECV, 0xab, 0xcd, 0, 0, 0, 1, 2, 0];
+        frame.extend_from_slice(&5u32.to_le_bytes());
+        frame.extend_from_slice(b"ping");
+        let received = ReceivedMessage::new(
+            crate::MessageTypes::ContactMsg(ContactMsg::from_frame(&frame)),
+            UNIX_EPOCH + Duration::from_secs(60),
+        );
+        let messages = event_messages(&topics, &CompanionEvent::Message(received), Some("alice"));
+        assert_eq!(messages.len(), 1);
+        let (topic, body) = &messages[0];
+        assert_eq!(topic, "home/mesh/dm/abcd00000001");
+        assert_eq!(body["from"], "alice");
+        assert_eq!(body["text"], "ping");
+        assert_eq!(body["path_len"], 2);
+        assert_eq!(body["received_at"], 60);
+
+        let request = parse_command(&config, "home/mesh/cmd/send_dm", br#"{"id": 7, "to": "alice", "text": "hi"}"#).unwrap();
+        assert_eq!(request.name, "send_dm");
+        assert_eq!(request.id, 7);
+        assert_eq!(request.command, Ok(BridgeCommand::SendDm(DmRequest { to: "alice".to_string(), text: "hi".to_string() })));
+        let request = parse_command(&config, "home/mesh/cmd/reboot", b"{}").unwrap();
+        assert_eq!(request.command, Err("unknown command reboot".to_string()));
+        assert!(parse_command(&config, "home/mesh/cmd/send_channel", b"{\"text\": \"x\"}").unwrap().command.is_err());
+        assert_eq!(parse_command(&config, "home/mesh/dm/abcd00000001", b"{}"), None);
+        let request = parse_command(&config, "home/mesh/cmd/command", br#"{"command": "CmdGetStats"}"#).unwrap();
+        assert_eq!(request.command, Err("raw commands are disabled".to_string()));
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[tokio::test]
+    async fn mqtt_raw_command_reports_unsupported_commands() {
+        use crate::mqtt::{execute, parse_command, MqttConfig};
+        let mut companion = Companion::new("/dev/null");
+        let _to_radio = companion.to_radio_rx.take().unwrap();
+        let config = MqttConfig { allow_raw_commands: true, ..MqttConfig::default() };
+        let request = parse_command(&config, "meshcore/cmd/command", br#"{"command": "CmdGetStats"}"#).unwrap();
+        assert_eq!(
+            execute(&companion, request.command.unwrap()).await,
+            Err(AppError::UnsupportedCommand(Commands::CmdGetStats))
+        );
+    }
+
+    #[test]
+    fn contact_url_carries_advert() {
+        use crate::contact_mgmt::advert_from_url;
+        assert_eq!(advert_from_url(" meshcore://11ab0c\n").unwrap(), vec![0x11, 0xab, 0x0c]);
+        assert!(advert_from_url("meshcore://11a").is_err());
+        assert!(advert_from_url("meshcore://").is_err());
+    }
 }
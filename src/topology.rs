@@ -0,0 +1,309 @@
+use crate::contact_mgmt::{Contact, PublicKey};
+use crate::packet::{AdvertType, Packet, Payload};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::SystemTime;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum NodeId {
+    /// The radio this companion is attached to
+    Local,
+    Key([u8; 32]),
+    /// A hop hash that did not resolve to exactly one known node
+    Hop(u8),
+}
+
+impl NodeId {
+    fn label(&self) -> String {
+        match self {
+            NodeId::Local => "local".to_string(),
+            NodeId::Key(key) => key[..6].iter().map(|b| format!("{:02x}", b)).collect(),
+            NodeId::Hop(hash) => format!("hop {hash:02x}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeInfo {
+    pub public_key: PublicKey,
+    pub name: String,
+    pub node_type: AdvertType,
+    pub last_seen: SystemTime,
+}
+
+/// Quality of a link as measured by the receiving end.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkStats {
+    pub last_snr: Option<f32>,
+    pub avg_snr: Option<f32>,
+    pub snr_samples: u32,
+    /// How many times the link was seen in a path, trace or packet
+    pub observations: u32,
+    pub last_seen: SystemTime,
+}
+
+impl LinkStats {
+    fn observe(&mut self, snr: Option<f32>, at: SystemTime) {
+        self.observations += 1;
+        self.last_seen = self.last_seen.max(at);
+        if let Some(snr) = snr {
+            self.snr_samples += 1;
+            let n = self.snr_samples as f32;
+            self.avg_snr = Some(self.avg_snr.map_or(snr, |avg| avg + (snr - avg) / n));
+            self.last_snr = Some(snr);
+        }
+    }
+}
+
+/// A trace result: the hop hashes the trace went through and the SNR each hop heard it with.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TraceResult {
+    pub tag: u32,
+    pub path: Vec<u8>,
+    pub hop_snrs: Vec<f32>,
+    /// SNR we received the completed trace with
+    pub final_snr: f32,
+}
+
+/// Graph of nodes and the directed links between them, learned from paths heard on the mesh.
+#[derive(Debug, Clone, Default)]
+pub struct Topology {
+    nodes: HashMap<[u8; 32], NodeInfo>,
+    links: HashMap<(NodeId, NodeId), LinkStats>,
+}
+
+impl Topology {
+    pub fn add_node(&mut self, public_key: PublicKey, name: &str, node_type: AdvertType, seen: SystemTime) {
+        let node = self.nodes.entry(public_key.bytes).or_insert(NodeInfo {
+            public_key,
+            name: name.to_string(),
+            node_type,
+            last_seen: seen,
+        });
+        if !name.is_empty() {
+            node.name = name.to_string();
+        }
+        node.node_type = node_type;
+        node.last_seen = node.last_seen.max(seen);
+    }
+
+    /// Known nodes whose public key starts with `hash`, repeaters first.
+    pub fn candidates(&self, hash: u8) -> Vec<&NodeInfo> {
+        let mut found: Vec<&NodeInfo> = self.nodes.values().filter(|n| n.public_key.bytes[0] == hash).collect();
+        found.sort_by_key(|n| (n.node_type != AdvertType::Repeater, n.public_key.bytes));
+        found
+    }
+
+    /// Resolves a hop hash, only repeaters forward packets so they win over other matches.
+    pub fn resolve(&self, hash: u8) -> NodeId {
+        let candidates = self.candidates(hash);
+        let repeaters: Vec<_> = candidates.iter().filter(|n| n.node_type == AdvertType::Repeater).collect();
+        match (repeaters.as_slice(), candidates.as_slice()) {
+            ([only], _) => NodeId::Key(only.public_key.bytes),
+            ([], [only]) => NodeId::Key(only.public_key.bytes),
+            _ => NodeId::Hop(hash),
+        }
+    }
+
+    pub fn node(&self, id: &NodeId) -> Option<&NodeInfo> {
+        match id {
+            NodeId::Key(key) => self.nodes.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeInfo> {
+        self.nodes.values()
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = (&(NodeId, NodeId), &LinkStats)> {
+        self.links.iter()
+    }
+
+    pub fn link(&self, from: NodeId, to: NodeId) -> Option<&LinkStats> {
+        self.links.get(&(from, to))
+    }
+
+    fn observe(&mut self, from: NodeId, to: NodeId, snr: Option<f32>, at: SystemTime) {
+        if from == to {
+            return;
+        }
+        self.links
+            .entry((from, to))
+            .or_insert(LinkStats {
+                last_snr: None,
+                avg_snr: None,
+                snr_samples: 0,
+                observations: 0,
+                last_seen: at,
+            })
+            .observe(snr, at);
+    }
+
+    /// Records a chain of hops, with `last_snr` applying to the final link.
+    fn add_chain(&mut self, chain: &[NodeId], last_snr: Option<f32>, at: SystemTime) {
+        for (i, pair) in chain.windows(2).enumerate() {
+            let snr = if i == chain.len() - 2 { last_snr } else { None };
+            self.observe(pair[0], pair[1], snr, at);
+        }
+    }
+
+    /// The route we use to reach a contact, hop hashes in the order we send through them.
+    pub fn add_contact_path(&mut self, contact: &Contact) {
+        let seen = SystemTime::now();
+        self.add_node(contact.public_key, &contact.adv_name, contact.adv_type.into(), seen);
+        // A negative length means the firmware has no path and floods instead
+        let Ok(len) = usize::try_from(contact.out_path_len) else {
+            return;
+        };
+        let mut chain = vec![NodeId::Local];
+        chain.extend(contact.out_path[..len.min(64)].iter().map(|h| self.resolve(*h)));
+        chain.push(NodeId::Key(contact.public_key.bytes));
+        self.add_chain(&chain, None, seen);
+    }
+
+    /// The path an advert from `origin` took to reach us.
+    pub fn add_advert_path(&mut self, origin: &PublicKey, path: &[u8], last_snr: Option<f32>) {
+        let mut chain = vec![NodeId::Key(origin.bytes)];
+        chain.extend(path.iter().map(|h| self.resolve(*h)));
+        chain.push(NodeId::Local);
+        self.add_chain(&chain, last_snr, SystemTime::now());
+    }
+
+    pub fn add_trace(&mut self, trace: &TraceResult) {
+        let at = SystemTime::now();
+        let mut previous = NodeId::Local;
+        for (i, hash) in trace.path.iter().enumerate() {
+            let hop = self.resolve(*hash);
+            self.observe(previous, hop, trace.hop_snrs.get(i).copied(), at);
+            previous = hop;
+        }
+        self.observe(previous, NodeId::Local, Some(trace.final_snr), at);
+    }
+
+    /// Learns from a flood packet we heard, its path lists the repeaters it came through.
+    pub fn add_packet(&mut self, packet: &Packet, payload: Option<&Payload>, snr: f32) {
+        if let Some(Payload::Advert(advert)) = payload {
+            let name = advert.name.clone().unwrap_or_default();
+            self.add_node(advert.public_key, &name, advert.node_type, SystemTime::now());
+            if packet.route.is_flood() {
+                self.add_advert_path(&advert.public_key, &packet.path, Some(snr));
+            }
+            return;
+        }
+        // Direct packets carry the route still to travel, not where they came from
+        if !packet.route.is_flood() || packet.path.is_empty() {
+            return;
+        }
+        let mut chain: Vec<NodeId> = packet.path.iter().map(|h| self.resolve(*h)).collect();
+        chain.push(NodeId::Local);
+        self.add_chain(&chain, Some(snr), SystemTime::now());
+    }
+
+    fn label(&self, id: &NodeId) -> String {
+        match (id, self.node(id)) {
+            (_, Some(node)) if !node.name.is_empty() => node.name.clone(),
+            (NodeId::Hop(hash), _) => {
+                let names: Vec<String> = self.candidates(*hash).iter().map(|n| n.name.clone()).collect();
+                if names.is_empty() {
+                    id.label()
+                } else {
+                    format!("{} ({})", id.label(), names.join(" | "))
+                }
+            }
+            _ => id.label(),
+        }
+    }
+
+    fn sorted_ids(&self) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = self.nodes.keys().map(|k| NodeId::Key(*k)).collect();
+        for (from, to) in self.links.keys() {
+            ids.push(*from);
+            ids.push(*to);
+        }
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    fn sorted_links(&self) -> Vec<(&(NodeId, NodeId), &LinkStats)> {
+        let mut links: Vec<_> = self.links.iter().collect();
+        links.sort_by_key(|(k, _)| **k);
+        links
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph meshcore {\n");
+        for id in self.sorted_ids() {
+            let shape = match (id, self.node(&id).map(|n| n.node_type)) {
+                (NodeId::Local, _) => "doublecircle",
+                (_, Some(AdvertType::Repeater)) => "box",
+                (NodeId::Hop(_), _) => "diamond",
+                _ => "ellipse",
+            };
+            let _ = writeln!(out, "  \"{}\" [label=\"{}\", shape={shape}];", id.label(), dot_escape(&self.label(&id)));
+        }
+        for ((from, to), stats) in self.sorted_links() {
+            let label = match stats.avg_snr {
+                Some(snr) => format!("{snr:.1} dB x{}", stats.observations),
+                None => format!("x{}", stats.observations),
+            };
+            let _ = writeln!(out, "  \"{}\" -> \"{}\" [label=\"{label}\"];", from.label(), to.label());
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_graphml(&self) -> String {
+        let mut out = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+            "  <key id=\"name\" for=\"node\" attr.name=\"name\" attr.type=\"string\"/>\n",
+            "  <key id=\"type\" for=\"node\" attr.name=\"type\" attr.type=\"string\"/>\n",
+            "  <key id=\"snr\" for=\"edge\" attr.name=\"avg_snr\" attr.type=\"double\"/>\n",
+            "  <key id=\"seen\" for=\"edge\" attr.name=\"observations\" attr.type=\"int\"/>\n",
+            "  <key id=\"last\" for=\"edge\" attr.name=\"last_seen\" attr.type=\"long\"/>\n",
+            "  <graph id=\"meshcore\" edgedefault=\"directed\">\n",
+        ));
+        for id in self.sorted_ids() {
+            let node_type = match (id, self.node(&id)) {
+                (NodeId::Local, _) => "local".to_string(),
+                (NodeId::Hop(_), _) => "unresolved".to_string(),
+                (_, Some(node)) => format!("{:?}", node.node_type).to_lowercase(),
+                _ => "unknown".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "    <node id=\"{}\"><data key=\"name\">{}</data><data key=\"type\">{node_type}</data></node>",
+                id.label(),
+                xml_escape(&self.label(&id))
+            );
+        }
+        for ((from, to), stats) in self.sorted_links() {
+            let last_seen = stats.last_seen.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+            let _ = write!(out, "    <edge source=\"{}\" target=\"{}\">", from.label(), to.label());
+            if let Some(snr) = stats.avg_snr {
+                let _ = write!(out, "<data key=\"snr\">{snr:.2}</data>");
+            }
+            let _ = writeln!(
+                out,
+                "<data key=\"seen\">{}</data><data key=\"last\">{last_seen}</data></edge>",
+                stats.observations
+            );
+        }
+        out.push_str("  </graph>\n</graphml>\n");
+        out
+    }
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}